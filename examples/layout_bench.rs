@@ -0,0 +1,39 @@
+use std::time::Instant;
+
+use lay::Layer;
+use lay_simulator_gk::{GottesmanKnillSimulator, Layout};
+use rand::{Rng, SeedableRng};
+use rand_xorshift::XorShiftRng;
+
+// Times a random H/S/CX circuit with a final measurement layer on both tableau layouts.
+// Run with `cargo run --release --example layout_bench`.
+fn main() {
+    for &n_qubits in &[1000, 2000, 5000, 10000] {
+        let mut rng = XorShiftRng::seed_from_u64(0);
+        let mut gates = GottesmanKnillSimulator::from_seed(n_qubits, 0).opsvec();
+        for _ in 0..n_qubits * 4 {
+            let a = rng.gen_range(0..n_qubits);
+            let b = (a + rng.gen_range(1..n_qubits)) % n_qubits;
+            match rng.gen_range(0..3) {
+                0 => gates.h(a),
+                1 => gates.s(a),
+                _ => gates.cx(a, b),
+            }
+        }
+        let mut meas = GottesmanKnillSimulator::from_seed(n_qubits, 0).opsvec();
+        for i in 0..n_qubits.min(100) {
+            meas.measure(i, i);
+        }
+        for &layout in &[Layout::RowMajor, Layout::ColumnMajor] {
+            let mut sim = GottesmanKnillSimulator::from_seed_with_layout(n_qubits, 0, layout);
+            let start = Instant::now();
+            sim.send(gates.as_ref()).unwrap();
+            let gate_time = start.elapsed();
+            let start = Instant::now();
+            sim.send(meas.as_ref()).unwrap();
+            let meas_time = start.elapsed();
+            println!("{:>6} qubits {:>12}: {:>6} gates in {:>10.3?}, {:>3} measurements in {:>10.3?}",
+                     n_qubits, format!("{:?}", layout), gates.as_ref().len(), gate_time, meas.as_ref().len(), meas_time);
+        }
+    }
+}
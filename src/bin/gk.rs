@@ -1,22 +1,194 @@
-use lay::Layer;
-use lay_simulator_gk::{GottesmanKnillSimulator, BitArray};
+//! Runs a circuit file on the Gottesman-Knill simulator and prints the measured slots or the final state.
+//!
+//! The format is chosen by the extension of the file: `.chp`, `.stim` or `.qasm`.
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::{env, process};
+
+use lay_simulator_gk::{BitArray, ChpCircuit, GottesmanKnillSimulator, Instruction, QasmCircuit, StimCircuit};
+
+const USAGE: &str = "usage: gk [--seed N] [--shots N] [--format FORMAT] [--out FILE] FILE
+
+Runs FILE (.chp, .stim or .qasm) and prints the measured slots or the final state.
+
+options:
+    --seed N         seed of the random number generator, random if omitted
+    --shots N        number of runs, 1 by default; shot i only depends on the seed and i
+    --format FORMAT  hist:    count of every outcome, the default with --shots
+                     01:      one line of 0s and 1s per shot, the default otherwise
+                     b8:      slots packed into bytes, least significant bit first, per shot
+                     csv:     one line of comma-separated 0s and 1s per shot
+                     stab:    stabilizers after the last shot, like +XXI, one per line
+                     tableau: destabilizers and stabilizers after the last shot
+                     json:    tableau and measured slots after the last shot
+    --out FILE       writes to FILE instead of the standard output";
+
+struct Circuit {
+    n_qubits: u32,
+    n_slots: u32,
+    instructions: Vec<Instruction>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Format {
+    Histogram,
+    Bits,
+    Packed,
+    Csv,
+    Stabilizers,
+    Tableau,
+    Json,
+}
+
+struct Options {
+    seed: u64,
+    shots: usize,
+    format: Format,
+    out: Option<String>,
+    path: String,
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Options, String> {
+    let mut seed = None;
+    let mut shots = None;
+    let mut format = None;
+    let mut out = None;
+    let mut path = None;
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("{} needs a value", arg));
+        match arg.as_str() {
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                process::exit(0);
+            },
+            "--seed" => {
+                let value = value()?;
+                seed = Some(value.parse().map_err(|_| format!("invalid seed {:?}", value))?);
+            },
+            "--shots" => {
+                let value = value()?;
+                shots = Some(value.parse().map_err(|_| format!("invalid number of shots {:?}", value))?);
+            },
+            "--format" => {
+                format = Some(match value()?.as_str() {
+                    "hist" => Format::Histogram,
+                    "01" => Format::Bits,
+                    "b8" => Format::Packed,
+                    "csv" => Format::Csv,
+                    "stab" => Format::Stabilizers,
+                    "tableau" => Format::Tableau,
+                    "json" => Format::Json,
+                    f => return Err(format!("unknown format {:?}", f)),
+                });
+            },
+            "--out" => out = Some(value()?),
+            _ if arg.starts_with('-') => return Err(format!("unknown option {}", arg)),
+            _ if path.is_none() => path = Some(arg),
+            _ => return Err(format!("unexpected argument {}", arg)),
+        }
+    }
+    Ok(Options {
+        seed: seed.unwrap_or_else(rand::random),
+        format: format.unwrap_or(if shots.is_some() { Format::Histogram } else { Format::Bits }),
+        shots: shots.unwrap_or(1),
+        out,
+        path: path.ok_or("no circuit file given")?,
+    })
+}
+
+fn load(path: &str) -> Result<Circuit, String> {
+    let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+    let err = |e: &dyn std::error::Error| format!("{}: {}", path, e);
+    let extension = path.rsplit('.').next().unwrap_or("");
+    match extension {
+        "chp" => {
+            let c: ChpCircuit = text.parse().map_err(|e| err(&e))?;
+            Ok(Circuit { n_qubits: c.n_qubits, n_slots: c.n_slots, instructions: c.instructions })
+        },
+        "stim" => {
+            let c: StimCircuit = text.parse().map_err(|e| err(&e))?;
+            Ok(Circuit { n_qubits: c.n_qubits, n_slots: c.n_slots(), instructions: c.instructions })
+        },
+        "qasm" => {
+            let c: QasmCircuit = text.parse().map_err(|e| err(&e))?;
+            Ok(Circuit { n_qubits: c.n_qubits, n_slots: c.n_slots, instructions: c.instructions })
+        },
+        _ => Err(format!("{}: unknown circuit format, expected .chp, .stim or .qasm", path)),
+    }
+}
+
+/// Writes each shot as soon as it is run. The state formats only run the shots.
+fn write_shots(out: &mut dyn Write, shots: impl Iterator<Item = BitArray>, format: Format) -> io::Result<()> {
+    match format {
+        Format::Histogram => {
+            let mut counts = HashMap::new();
+            for shot in shots {
+                *counts.entry(shot.to_bit_string()).or_insert(0) += 1;
+            }
+            let mut counts: Vec<_> = counts.into_iter().collect();
+            counts.sort_by(|(b1, c1), (b2, c2)| c2.cmp(c1).then(b1.cmp(b2)));
+            for (bits, count) in counts {
+                writeln!(out, "{}: {}", bits, count)?;
+            }
+        },
+        Format::Bits => {
+            for shot in shots {
+                writeln!(out, "{}", shot.to_bit_string())?;
+            }
+        },
+        Format::Packed => {
+            for shot in shots {
+                let mut bytes = vec![0u8; (shot.len() + 7) / 8];
+                shot.true_indices().for_each(|i| bytes[i / 8] |= 1 << (i % 8));
+                out.write_all(&bytes)?;
+            }
+        },
+        Format::Csv => {
+            for shot in shots {
+                let row: Vec<_> = shot.to_bit_string().chars().map(String::from).collect();
+                writeln!(out, "{}", row.join(","))?;
+            }
+        },
+        Format::Stabilizers | Format::Tableau | Format::Json => shots.for_each(drop),
+    }
+    Ok(())
+}
+
+fn write_state<Rng>(out: &mut dyn Write, sim: &GottesmanKnillSimulator<Rng>, format: Format) -> io::Result<()> {
+    match format {
+        Format::Stabilizers => writeln!(out, "{}", sim)?,
+        Format::Tableau => writeln!(out, "{:#}", sim)?,
+        Format::Json => writeln!(out, "{}", sim.to_json())?,
+        _ => {},
+    }
+    out.flush()
+}
+
+fn run() -> Result<(), String> {
+    let options = parse_args(env::args().skip(1)).map_err(|e| format!("{}\n\n{}", e, USAGE))?;
+    let circuit = load(&options.path)?;
+    let mut sim = GottesmanKnillSimulator::from_seed_with_slots(circuit.n_qubits, circuit.n_slots, options.seed);
+    let mut write = |out: &mut dyn Write| {
+        let shots = sim.iter_shots(&circuit.instructions, options.shots, options.seed)
+                       .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("{}: {}", options.path, e)))?;
+        write_shots(out, shots, options.format)?;
+        write_state(out, &sim, options.format)
+    };
+    let result = match &options.out {
+        Some(path) => {
+            let file = File::create(path).map_err(|e| format!("{}: {}", path, e))?;
+            write(&mut BufWriter::new(file))
+        },
+        None => write(&mut BufWriter::new(io::stdout().lock())),
+    };
+    result.map_err(|e| e.to_string())
+}
 
 fn main() {
-    let mut cli = GottesmanKnillSimulator::from_seed(2, 0);
-    let mut ops = cli.opsvec();
-    ops.x(0);
-    ops.cx(0, 1);
-    cli.send(ops.as_ref());
-    cli.dump_print();
-    ops.clear();
-    ops.measure(0, 0);
-    cli.send(ops.as_ref());
-    cli.dump_print();
-    ops.clear();
-    ops.measure(1, 1);
-    cli.send(ops.as_ref());
-    cli.dump_print();
-    let mut result = BitArray::zeros(0);
-    cli.receive(&mut result);
-    println!("result: {:?}", result);
+    if let Err(e) = run() {
+        eprintln!("gk: {}", e);
+        process::exit(2);
+    }
 }
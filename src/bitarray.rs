@@ -2,9 +2,10 @@
 
 use std::fmt::{self, Debug, Formatter};
 use lay::Measured;
+use rand_core::RngCore;
 
-type Block = u32;
-const BLOCK_SIZE: usize = 32;
+pub(crate) type Block = u64;
+const BLOCK_SIZE: usize = 64;
 const BLOCK_MASK: usize = (!(0 as Block)) as usize;
 
 pub struct BitArray {
@@ -39,6 +40,30 @@ impl BitArray {
         ones
     }
 
+    /// Fills every bit with independent fair coin flips.
+    pub fn randomize<Rng: RngCore>(&mut self, rng: &mut Rng) {
+        self.inner.iter_mut().for_each(|x| *x = rng.next_u64());
+        let rem = self.len % BLOCK_SIZE;
+        if rem > 0 {
+            *self.inner.last_mut().unwrap() &= (1 << rem) - 1;
+        }
+    }
+
+    /// Appends a bit, growing the array by one.
+    pub fn push(&mut self, val: bool) {
+        if Self::_cap_from_len(self.len + 1) > self.inner.len() {
+            self.inner.push(0);
+        }
+        self.len += 1;
+        self.set_bool(self.len - 1, val);
+    }
+
+    /// Removes every bit, keeping the allocation.
+    pub fn clear(&mut self) {
+        self.inner.clear();
+        self.len = 0;
+    }
+
     pub fn copy_from(&mut self, other: &Self) {
         let cap = other.inner.len();
         if self.inner.len() < cap {
@@ -70,15 +95,17 @@ impl BitArray {
         }
     }
 
+    /// The bit masked within its 32-bit word, which is 0 or `1 << (index % 32)`.
     #[inline]
-    pub fn get_masked(&self, index: usize) -> Block {
-        let (block, mask) = Self::_access(index);
-        self.inner[block] & mask
+    pub fn get_masked(&self, index: usize) -> u32 {
+        let word = self.inner[index / BLOCK_SIZE] >> (index % BLOCK_SIZE / 32 * 32);
+        word as u32 & (1 << (index % 32))
     }
 
     #[inline]
     pub fn get_bool(&self, index: usize) -> bool {
-        self.get_masked(index) != 0
+        let (block, mask) = Self::_access(index);
+        self.inner[block] & mask != 0
     }
 
     #[inline]
@@ -89,6 +116,16 @@ impl BitArray {
         }
     }
 
+    #[inline]
+    pub(crate) fn blocks(&self) -> &[Block] {
+        &self.inner
+    }
+
+    #[inline]
+    pub(crate) fn blocks_mut(&mut self) -> &mut [Block] {
+        &mut self.inner
+    }
+
     pub fn len(&self) -> usize {
         self.len
     }
@@ -96,15 +133,41 @@ impl BitArray {
     pub fn true_indices(&self) -> TIndices {
         TIndices::new(&self)
     }
+
+    /// Writes the bits in order as "0101".
+    pub fn to_bit_string(&self) -> String {
+        (0..self.len).map(|i| if self.get_bool(i) { '1' } else { '0' }).collect()
+    }
+}
+
+impl Clone for BitArray {
+    fn clone(&self) -> Self {
+        BitArray { inner: self.inner.clone(), len: self.len }
+    }
+
+    /// Reuses the allocated blocks.
+    fn clone_from(&mut self, source: &Self) {
+        self.inner.clone_from(&source.inner);
+        self.len = source.len;
+    }
 }
 
+impl PartialEq for BitArray {
+    fn eq(&self, other: &Self) -> bool {
+        let cap = Self::_cap_from_len(self.len);
+        self.len == other.len && self.inner[..cap] == other.inner[..cap]
+    }
+}
+
+impl Eq for BitArray {}
+
 impl Debug for BitArray {
     fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
         fmt.write_str("Bitarray { inner: [")?;
         if !self.inner.is_empty() {
             fmt.write_fmt(format_args!("{:b}", self.inner[0]))?;
         }
-        for bin in self.inner[1..].iter() {
+        for bin in self.inner.iter().skip(1) {
             fmt.write_fmt(format_args!(" {:b}", *bin))?;
         }
         fmt.write_fmt(format_args!("], len: {} }}", self.len))
@@ -140,7 +203,7 @@ impl Iterator for TIndices<'_> {
     type Item = usize;
     fn next(&mut self) -> Option<Self::Item> {
         if self.buf == 0 {
-            if self.current_blk < self.barray.inner.len() - 1 {
+            if self.current_blk + 1 < self.barray.inner.len() {
                 self.current_blk += 1;
                 self.current_bit = 0;
                 self.buf = self.barray.inner[self.current_blk];
@@ -165,6 +228,23 @@ mod tests {
         assert_eq!(2 + 2, 4);
     }
 
+    #[test]
+    fn push() {
+        let mut ba = BitArray::zeros(0);
+        assert_eq!(ba.true_indices().next(), None);
+        for i in 0..130 {
+            ba.push(i % 3 == 0);
+        }
+        assert_eq!(ba.len(), 130);
+        assert_eq!(&ba.to_bit_string()[..7], "1001001");
+        assert!((0..130).all(|i| ba.get_bool(i) == (i % 3 == 0)));
+        assert!((0..130).all(|i| ba.get_masked(i) == ((i % 3 == 0) as u32) << (i % 32)));
+        ba.clear();
+        assert_eq!(ba.len(), 0);
+        ba.push(true);
+        assert_eq!(ba.true_indices().collect::<Vec<_>>(), vec![0]);
+    }
+
     #[test]
     fn set_get1() {
         let mut ba = BitArray::zeros(6);
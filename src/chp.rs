@@ -0,0 +1,94 @@
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+use std::str::FromStr;
+
+use crate::{Gate1, Gate2, Instruction};
+
+/// Error of reading a `.chp` circuit, with the line it occurred on.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChpError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl Display for ChpError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl Error for ChpError {}
+
+/// Circuit in the `.chp` format of Aaronson and Gottesman's CHP simulator.
+///
+/// A free-form description is followed by a line starting with '#', then one gate per line:
+/// "h q", "p q" (phase, S), "c control target" and "m q". The i-th measurement is written to slot i.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChpCircuit {
+    pub n_qubits: u32,
+    pub n_slots: u32,
+    pub instructions: Vec<Instruction>,
+}
+
+impl FromStr for ChpCircuit {
+    type Err = ChpError;
+
+    fn from_str(s: &str) -> Result<Self, ChpError> {
+        let lines: Vec<_> = s.lines().enumerate().map(|(i, l)| (i + 1, l.trim())).collect();
+        // Without a '#' line, there is no description.
+        let body = lines.iter().position(|(_, l)| l.starts_with('#')).map_or(0, |i| i + 1);
+        let mut c = ChpCircuit { n_qubits: 0, n_slots: 0, instructions: vec![] };
+        for &(line, text) in lines[body..].iter().filter(|(_, l)| !l.is_empty()) {
+            let err = |message: String| ChpError { line, message };
+            let mut words = text.split_whitespace();
+            let gate = words.next().unwrap().to_lowercase();
+            let qubits = words.map(|w| w.parse::<u32>().map_err(|_| err(format!("invalid qubit {:?}", w))))
+                              .collect::<Result<Vec<_>, _>>()?;
+            let arity = if gate == "c" { 2 } else { 1 };
+            if qubits.len() != arity {
+                return Err(err(format!("{} takes {} qubits", gate, arity)));
+            }
+            let inst = match gate.as_str() {
+                "h" => Instruction::Gate1(Gate1::H, qubits[0]),
+                "p" => Instruction::Gate1(Gate1::S, qubits[0]),
+                "c" if qubits[0] == qubits[1] => return Err(err(format!("c on qubit {} twice", qubits[0]))),
+                "c" => Instruction::Gate2(Gate2::CX, qubits[0], qubits[1]),
+                "m" => {
+                    c.n_slots += 1;
+                    Instruction::Measure(qubits[0], c.n_slots - 1)
+                },
+                _ => return Err(err(format!("unknown gate {:?}", gate))),
+            };
+            let max = qubits.iter().max().unwrap();
+            let n = max.checked_add(1).ok_or_else(|| err(format!("qubit {} out of range", max)))?;
+            c.n_qubits = c.n_qubits.max(n);
+            c.instructions.push(inst);
+        }
+        Ok(c)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{ChpCircuit, ChpError, Gate1, Gate2, Instruction};
+
+    #[test]
+    fn parse() {
+        let c: ChpCircuit = "Bell pair\n\n#\nh 0\nc 0 1\n\np 1\nm 0\nm 1\n".parse().unwrap();
+        assert_eq!(c.n_qubits, 2);
+        assert_eq!(c.n_slots, 2);
+        assert_eq!(c.instructions, vec![
+            Instruction::Gate1(Gate1::H, 0),
+            Instruction::Gate2(Gate2::CX, 0, 1),
+            Instruction::Gate1(Gate1::S, 1),
+            Instruction::Measure(0, 0),
+            Instruction::Measure(1, 1),
+        ]);
+        assert_eq!("h 3".parse::<ChpCircuit>().unwrap().n_qubits, 4);
+        assert_eq!("#\nh 0\nx 1".parse::<ChpCircuit>(), Err(ChpError { line: 3, message: "unknown gate \"x\"".to_owned() }));
+        assert_eq!("#\nc 0".parse::<ChpCircuit>().unwrap_err().line, 2);
+        assert_eq!("#\nm a".parse::<ChpCircuit>().unwrap_err().line, 2);
+        assert_eq!("#\nh 4294967295".parse::<ChpCircuit>(),
+                   Err(ChpError { line: 2, message: "qubit 4294967295 out of range".to_owned() }));
+    }
+}
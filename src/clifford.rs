@@ -0,0 +1,246 @@
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+
+use crate::{BitArray, PauliString};
+use crate::tableau::pauli_phase;
+
+/// Errors of building or applying a `CliffordTableau`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CliffordError {
+    /// The number or the length of the images does not match the number of qubits.
+    Shape,
+    /// An image has an imaginary phase.
+    NotHermitian,
+    /// The images do not have the commutation relations of the X_i and Z_i they replace.
+    NotSymplectic,
+    /// The number of target qubits is wrong, or a target is repeated or out of range.
+    Targets,
+}
+
+impl Display for CliffordError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            CliffordError::Shape => f.write_str("Clifford images have inconsistent sizes"),
+            CliffordError::NotHermitian => f.write_str("Clifford images must be Hermitian"),
+            CliffordError::NotSymplectic => f.write_str("Clifford images do not preserve commutation relations"),
+            CliffordError::Targets => f.write_str("invalid target qubits for the Clifford"),
+        }
+    }
+}
+
+impl Error for CliffordError {}
+
+/// k-qubit Clifford operation given by its own tableau: the images of X_i and Z_i
+/// under conjugation, as signed Pauli strings.
+///
+/// Row i (i < k) is the image of X_i and row k + i is the image of Z_i.
+/// A qubit with both x and z set is Y, as in the simulator's tableau.
+#[derive(Clone, Debug)]
+pub struct CliffordTableau {
+    k: usize,
+    xs: Vec<BitArray>,
+    zs: Vec<BitArray>,
+    sgns: BitArray,
+}
+
+/// Returns whether the Pauli strings (x1, z1) and (x2, z2) anticommute.
+pub(crate) fn anticommutes(x1: &BitArray, z1: &BitArray, x2: &BitArray, z2: &BitArray) -> bool {
+    let mut parity = 0;
+    for (((&x1, &z1), &x2), &z2) in x1.blocks().iter()
+                                      .zip(z1.blocks().iter())
+                                      .zip(x2.blocks().iter())
+                                      .zip(z2.blocks().iter()) {
+        parity ^= ((x1 & z2) ^ (z1 & x2)).count_ones();
+    }
+    parity & 1 != 0
+}
+
+impl CliffordTableau {
+    /// Builds a Clifford from the 2k rows of its tableau, images of X_0..X_{k-1} followed by
+    /// images of Z_0..Z_{k-1}. `signs` has a bit set for every negated image.
+    pub fn from_rows(xs: Vec<BitArray>, zs: Vec<BitArray>, signs: BitArray) -> Result<Self, CliffordError> {
+        let k = xs.len() / 2;
+        if xs.len() != 2 * k || zs.len() != 2 * k || signs.len() != 2 * k
+                || xs.iter().chain(zs.iter()).any(|a| a.len() != k) {
+            return Err(CliffordError::Shape);
+        }
+        let c = Self { k, xs, zs, sgns: signs };
+        for i in 0..2 * k {
+            for j in i + 1..2 * k {
+                let expected = j == i + k;
+                if c.row_anticommutes(i, j) != expected {
+                    return Err(CliffordError::NotSymplectic);
+                }
+            }
+        }
+        Ok(c)
+    }
+
+    /// Builds a Clifford from the images of X_0..X_{k-1} and of Z_0..Z_{k-1}.
+    /// Shorter images are padded with identities.
+    pub fn from_paulis(x_images: &[PauliString], z_images: &[PauliString]) -> Result<Self, CliffordError> {
+        let k = x_images.len();
+        if z_images.len() != k || x_images.iter().chain(z_images.iter()).any(|p| p.len() > k) {
+            return Err(CliffordError::Shape);
+        }
+        let mut xs = vec![];
+        let mut zs = vec![];
+        let mut signs = BitArray::zeros(2 * k);
+        for (i, p) in x_images.iter().chain(z_images.iter()).enumerate() {
+            if !p.is_hermitian() {
+                return Err(CliffordError::NotHermitian);
+            }
+            let mut p = p.clone();
+            p.resize(k);
+            xs.push(p.xs().clone());
+            zs.push(p.zs().clone());
+            signs.set_bool(i, p.is_negative());
+        }
+        Self::from_rows(xs, zs, signs)
+    }
+
+    pub fn identity(k: usize) -> Self {
+        let rows = |offset| (0..2 * k).map(|i| {
+            let mut a = BitArray::zeros(k);
+            if i >= offset && i < offset + k {
+                a.negate(i - offset);
+            }
+            a
+        }).collect();
+        Self { k, xs: rows(0), zs: rows(k), sgns: BitArray::zeros(2 * k) }
+    }
+
+    pub fn n_qubits(&self) -> usize {
+        self.k
+    }
+
+    fn row_anticommutes(&self, i: usize, j: usize) -> bool {
+        anticommutes(&self.xs[i], &self.zs[i], &self.xs[j], &self.zs[j])
+    }
+
+    /// Image of X_i as (x, z, negated).
+    pub fn x_image(&self, i: usize) -> (&BitArray, &BitArray, bool) {
+        (&self.xs[i], &self.zs[i], self.sgns.get_bool(i))
+    }
+
+    /// Image of Z_i as (x, z, negated).
+    pub fn z_image(&self, i: usize) -> (&BitArray, &BitArray, bool) {
+        (&self.xs[i + self.k], &self.zs[i + self.k], self.sgns.get_bool(i + self.k))
+    }
+
+    /// Conjugates the local Pauli string (x, z) by the Clifford.
+    /// Returns the image and whether its sign is negated.
+    pub(crate) fn conjugate(&self, x: &BitArray, z: &BitArray) -> (BitArray, BitArray, bool) {
+        let mut acc_x = BitArray::zeros(self.k);
+        let mut acc_z = BitArray::zeros(self.k);
+        let mut phase = 0;
+        for j in 0..self.k {
+            let (xj, zj) = (x.get_bool(j), z.get_bool(j));
+            // Y = iXZ
+            if xj && zj {
+                phase += 1;
+            }
+            for &row in [(xj, j), (zj, j + self.k)].iter().filter(|(b, _)| *b).map(|(_, r)| r) {
+                phase += pauli_phase(&acc_x, &acc_z, &self.xs[row], &self.zs[row]);
+                phase += 2 * self.sgns.get_bool(row) as u32;
+                acc_x.xor_all(&self.xs[row]);
+                acc_z.xor_all(&self.zs[row]);
+            }
+        }
+        debug_assert_eq!(phase & 1, 0);
+        (acc_x, acc_z, phase & 3 == 2)
+    }
+
+    /// Checks that `targets` names k distinct qubits below `n_qubits`.
+    pub(crate) fn check_targets(&self, targets: &[u32], n_qubits: u32) -> Result<(), CliffordError> {
+        if targets.len() != self.k || targets.iter().any(|&t| t >= n_qubits) {
+            return Err(CliffordError::Targets);
+        }
+        for (i, t) in targets.iter().enumerate() {
+            if targets[i + 1..].contains(t) {
+                return Err(CliffordError::Targets);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl PartialEq for CliffordTableau {
+    fn eq(&self, other: &Self) -> bool {
+        self.k == other.k && (0..2 * self.k).all(|i| {
+            self.sgns.get_bool(i) == other.sgns.get_bool(i)
+                && self.xs[i].true_indices().eq(other.xs[i].true_indices())
+                && self.zs[i].true_indices().eq(other.zs[i].true_indices())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{BitArray, CliffordError, CliffordTableau, PauliString};
+
+    /// Parses dense Pauli strings such as "-XZ".
+    pub(crate) fn rows(images: &[&str]) -> (Vec<BitArray>, Vec<BitArray>, BitArray) {
+        let k = images[0].len() - 1;
+        let mut xs = vec![];
+        let mut zs = vec![];
+        let mut sgns = BitArray::zeros(images.len());
+        for (i, s) in images.iter().enumerate() {
+            let mut x = BitArray::zeros(k);
+            let mut z = BitArray::zeros(k);
+            if s.starts_with('-') {
+                sgns.negate(i);
+            }
+            for (j, c) in s[1..].chars().enumerate() {
+                x.set_bool(j, c == 'X' || c == 'Y');
+                z.set_bool(j, c == 'Z' || c == 'Y');
+            }
+            xs.push(x);
+            zs.push(z);
+        }
+        (xs, zs, sgns)
+    }
+
+    #[test]
+    fn valid() {
+        // CX(0, 1)
+        let (xs, zs, sgns) = rows(&["+XX", "+IX", "+ZI", "+ZZ"]);
+        assert!(CliffordTableau::from_rows(xs, zs, sgns).is_ok());
+        assert!(CliffordTableau::from_rows(vec![], vec![], BitArray::zeros(0)).is_ok());
+    }
+
+    #[test]
+    fn invalid() {
+        let (xs, zs, sgns) = rows(&["+XX", "+IX", "+ZI", "+ZI"]);
+        assert_eq!(CliffordTableau::from_rows(xs, zs, sgns), Err(CliffordError::NotSymplectic));
+        let (xs, zs, sgns) = rows(&["+XI", "+IX", "+ZI", "+IZ"]);
+        assert_eq!(CliffordTableau::from_rows(xs[..3].to_vec(), zs, sgns), Err(CliffordError::Shape));
+        let (xs, zs, sgns) = rows(&["+X", "+Z"]);
+        let c = CliffordTableau::from_rows(xs, zs, sgns).unwrap();
+        assert_eq!(c.check_targets(&[0, 1], 3), Err(CliffordError::Targets));
+        assert_eq!(c.check_targets(&[3], 3), Err(CliffordError::Targets));
+        assert_eq!(c.check_targets(&[2], 3), Ok(()));
+    }
+
+    #[test]
+    fn from_paulis() {
+        let p = |s: &str| s.parse::<PauliString>().unwrap();
+        let swap = CliffordTableau::from_paulis(&[p("IX"), p("X")], &[p("-IZ"), p("Z")]).unwrap();
+        assert!(swap.x_image(0).0.get_bool(1));
+        assert!(swap.z_image(0).2);
+        assert_eq!(CliffordTableau::from_paulis(&[p("iY")], &[p("Z")]), Err(CliffordError::NotHermitian));
+        assert_eq!(CliffordTableau::from_paulis(&[p("X")], &[p("X")]), Err(CliffordError::NotSymplectic));
+        assert_eq!(CliffordTableau::from_paulis(&[p("X")], &[p("IZ")]), Err(CliffordError::Shape));
+    }
+
+    #[test]
+    fn conjugate_y() {
+        // S: X -> Y, Z -> Z, so Y -> -X.
+        let (xs, zs, sgns) = rows(&["+Y", "+Z"]);
+        let c = CliffordTableau::from_rows(xs, zs, sgns).unwrap();
+        let (x, z, neg) = c.conjugate(&BitArray::ones(1), &BitArray::ones(1));
+        assert!(x.get_bool(0));
+        assert!(!z.get_bool(0));
+        assert!(neg);
+    }
+}
@@ -0,0 +1,122 @@
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+
+use crate::{BitArray, FrameError, Instruction};
+
+/// Values of the detectors and logical observables of a circuit without noise.
+///
+/// A detection event is a detector whose value differs from the reference,
+/// and an observable flip is an observable which differs from it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DetectorReference {
+    pub detectors: BitArray,
+    pub observables: BitArray,
+}
+
+/// Detectors and observables, by index, whose value is random even without noise.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NonDeterministic {
+    pub detectors: Vec<usize>,
+    pub observables: Vec<usize>,
+}
+
+impl Display for NonDeterministic {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.write_str("non-deterministic")?;
+        if !self.detectors.is_empty() {
+            write!(f, " detectors {:?}", self.detectors)?;
+        }
+        if !self.observables.is_empty() {
+            if !self.detectors.is_empty() {
+                f.write_str(" and")?;
+            }
+            write!(f, " observables {:?}", self.observables)?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for NonDeterministic {}
+
+/// Errors of checking the detectors of a circuit or building its `DetectorErrorModel`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum DemError {
+    /// Some detectors or observables are random even without noise.
+    NonDeterministic(NonDeterministic),
+    /// A noise instruction which is not a product of independent Pauli errors.
+    Channel(Instruction),
+    /// An instruction the Pauli frame simulator cannot follow.
+    Frame(FrameError),
+}
+
+impl Display for DemError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            DemError::NonDeterministic(e) => Display::fmt(e, f),
+            DemError::Channel(inst) => write!(f, "cannot decompose {:?} into independent errors", inst),
+            DemError::Frame(e) => Display::fmt(e, f),
+        }
+    }
+}
+
+impl Error for DemError {}
+
+/// Independent error which flips a set of detectors and observables.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ErrorMechanism {
+    pub probability: f64,
+    pub detectors: Vec<usize>,
+    pub observables: Vec<usize>,
+}
+
+/// Detector error model of a noisy circuit: its independent error mechanisms, each listed
+/// once with the detectors and observables it flips.
+///
+/// `Display` writes Stim's `.dem` format.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DetectorErrorModel {
+    pub errors: Vec<ErrorMechanism>,
+    /// Coordinates of every detector, empty if none were given.
+    pub detector_coords: Vec<Vec<f64>>,
+    pub n_observables: usize,
+}
+
+impl DetectorErrorModel {
+    pub fn n_detectors(&self) -> usize {
+        self.detector_coords.len()
+    }
+}
+
+impl Display for DetectorErrorModel {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let mut mentioned_detectors = BitArray::zeros(self.n_detectors());
+        let mut mentioned_observables = BitArray::zeros(self.n_observables);
+        for e in self.errors.iter() {
+            write!(f, "error({})", e.probability)?;
+            for &d in e.detectors.iter() {
+                write!(f, " D{}", d)?;
+                mentioned_detectors.set_bool(d, true);
+            }
+            for &l in e.observables.iter() {
+                write!(f, " L{}", l)?;
+                mentioned_observables.set_bool(l, true);
+            }
+            writeln!(f)?;
+        }
+        // Detectors and observables which no error flips are still declared, so that none is lost.
+        for (d, coords) in self.detector_coords.iter().enumerate() {
+            if !coords.is_empty() {
+                let coords: Vec<_> = coords.iter().map(|c| c.to_string()).collect();
+                writeln!(f, "detector({}) D{}", coords.join(", "), d)?;
+            } else if !mentioned_detectors.get_bool(d) {
+                writeln!(f, "detector D{}", d)?;
+            }
+        }
+        for l in 0..self.n_observables {
+            if !mentioned_observables.get_bool(l) {
+                writeln!(f, "logical_observable L{}", l)?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,1017 @@
+use std::collections::{HashMap, hash_map::Entry};
+use std::error::Error;
+use std::fmt::{self, Debug, Display, Formatter};
+
+use rand::Rng as _;
+use rand_core::{RngCore, SeedableRng};
+use lay::{Layer, operations::OpArgs};
+
+use crate::{Basis, BitArray, CliffordTableau, Condition, DefaultRng, DemError, DetectorErrorModel, DetectorReference, ErrorMechanism,
+            Gate1, Gate2, GottesmanKnillSimulator, Instruction, InstructionError, NonDeterministic, Pauli, PauliString, check_error_probability,
+            tableau::pair_mut};
+
+/// Error of running a circuit on a `PauliFrameSimulator`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum FrameError {
+    /// An op which is unknown, or an instruction which cannot run on the qubits of the simulator.
+    Instruction(InstructionError),
+    /// An instruction whose effect on a Pauli frame is not linear: a condition comparing several
+    /// slots with a number, or a conditioned instruction other than a Pauli gate.
+    NonLinear(Instruction),
+}
+
+impl Display for FrameError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            FrameError::Instruction(e) => write!(f, "{}", e),
+            FrameError::NonLinear(inst) => write!(f, "{:?} is not linear in a Pauli frame", inst),
+        }
+    }
+}
+
+impl Error for FrameError {}
+
+/// Samples many shots of a Clifford circuit at once.
+///
+/// The circuit is run once on a `GottesmanKnillSimulator` to get a reference sample.
+/// Every other shot is represented by a Pauli frame, the difference from the reference,
+/// and the frames of all shots are propagated together, one bit per shot.
+#[derive(Debug)]
+pub struct PauliFrameSimulator<Rng> {
+    n_qubits: u32,
+    n_shots: usize,
+    xs: Vec<BitArray>,
+    zs: Vec<BitArray>,
+    readout_errors: Vec<f64>,
+    reset_errors: Vec<f64>,
+    rng: Rng,
+    coins: Coins,
+}
+
+/// How random frame bits and noise are drawn.
+#[derive(Debug)]
+enum Coins {
+    Random,
+    /// Every random frame bit is a fresh symbol, one per "shot", counting the symbols used so far.
+    /// Then the flip of a measurement is the set of symbols it depends on, and it is empty
+    /// exactly when the noiseless outcome is deterministic.
+    Symbolic(usize),
+    /// No random frame bits. Every independent error of the noise gets a "shot" of its own
+    /// and happens there with certainty; its probability is listed in order.
+    Errors(Vec<f64>),
+}
+
+/// Flips of everything a circuit reports, one bit per shot.
+struct Flips {
+    reference: GottesmanKnillSimulator<DefaultRng>,
+    slots: Vec<BitArray>,
+    detectors: Vec<BitArray>,
+    observables: Vec<BitArray>,
+}
+
+impl PauliFrameSimulator<DefaultRng> {
+    pub fn from_seed(n_qubits: u32, n_shots: usize, seed: u64) -> Self {
+        Self::from_rng(n_qubits, n_shots, DefaultRng::seed_from_u64(seed))
+    }
+
+    /// Returns the noiseless values of the detectors and observables of `insts`,
+    /// or the ones which are not deterministic. Noise is ignored.
+    pub fn check_detectors(n_qubits: u32, insts: &[Instruction]) -> Result<DetectorReference, DemError> {
+        let noiseless: Vec<_> = insts.iter().filter(|inst| !inst.is_noise()).cloned().collect();
+        // An upper bound of the random frame bits drawn while propagating.
+        let n_symbols = n_qubits as usize + noiseless.iter().map(|inst| match inst {
+            Instruction::Init => n_qubits as usize,
+            Instruction::Measure(..) | Instruction::MeasureIn(..) | Instruction::Reset(..) | Instruction::Mpp(..) => 1,
+            Instruction::MeasureReset(..) => 2,
+            _ => 0,
+        }).sum::<usize>();
+        let mut sim = Self::from_seed(n_qubits, n_symbols, 0);
+        sim.coins = Coins::Symbolic(0);
+        let flips = sim.propagate(&noiseless).map_err(DemError::Frame)?;
+        let random = |flips: &[BitArray]| -> Vec<usize> {
+            flips.iter().enumerate().filter(|(_, f)| f.true_indices().next().is_some()).map(|(i, _)| i).collect()
+        };
+        let detectors = random(&flips.detectors);
+        let observables = random(&flips.observables);
+        if detectors.is_empty() && observables.is_empty() {
+            Ok(DetectorReference {
+                detectors: flips.reference.detector_parities().clone(),
+                observables: flips.reference.observable_parities().clone(),
+            })
+        } else {
+            Err(DemError::NonDeterministic(NonDeterministic { detectors, observables }))
+        }
+    }
+
+    /// Finds the independent errors of the noise in `insts` and the detectors and observables
+    /// each of them flips. Errors with the same effect are merged, and ones without any are dropped.
+    pub fn detector_error_model(n_qubits: u32, insts: &[Instruction]) -> Result<DetectorErrorModel, DemError> {
+        Self::check_detectors(n_qubits, insts)?;
+        let mut n_errors = 0;
+        let mut detector_coords = vec![];
+        for inst in insts.iter() {
+            match inst {
+                Instruction::Init => detector_coords.clear(),
+                Instruction::Detector(coords, _) => detector_coords.push(coords.clone()),
+                Instruction::Noise1(ch, _) => {
+                    n_errors += ch.independent_components().ok_or_else(|| DemError::Channel(inst.clone()))?.len();
+                },
+                Instruction::Noise2(ch, _, _) => {
+                    n_errors += ch.independent_components().ok_or_else(|| DemError::Channel(inst.clone()))?.len();
+                },
+                _ => {},
+            }
+        }
+        let mut sim = Self::from_seed(n_qubits, n_errors, 0);
+        sim.coins = Coins::Errors(vec![]);
+        let flips = sim.propagate(insts).map_err(DemError::Frame)?;
+        let probs = match sim.coins {
+            Coins::Errors(probs) => probs,
+            _ => unreachable!(),
+        };
+
+        let mut effects: Vec<(Vec<usize>, Vec<usize>)> = vec![(vec![], vec![]); probs.len()];
+        for (d, flip) in flips.detectors.iter().enumerate() {
+            flip.true_indices().for_each(|e| effects[e].0.push(d));
+        }
+        for (l, flip) in flips.observables.iter().enumerate() {
+            flip.true_indices().for_each(|e| effects[e].1.push(l));
+        }
+        let mut errors: Vec<ErrorMechanism> = vec![];
+        let mut index: HashMap<_, usize> = HashMap::new();
+        for ((detectors, observables), p) in effects.into_iter().zip(probs) {
+            if detectors.is_empty() && observables.is_empty() {
+                continue;
+            }
+            match index.entry((detectors.clone(), observables.clone())) {
+                Entry::Occupied(i) => {
+                    // Two independent errors with the same effect act as one.
+                    let q = &mut errors[*i.get()].probability;
+                    *q = *q * (1.0 - p) + p * (1.0 - *q);
+                },
+                Entry::Vacant(v) => {
+                    v.insert(errors.len());
+                    errors.push(ErrorMechanism { probability: p, detectors, observables });
+                },
+            }
+        }
+        Ok(DetectorErrorModel { errors, detector_coords, n_observables: flips.observables.len() })
+    }
+}
+
+impl<Rng: RngCore> PauliFrameSimulator<Rng> {
+    pub fn from_rng(n_qubits: u32, n_shots: usize, rng: Rng) -> Self {
+        let xs = (0..n_qubits).map(|_| BitArray::zeros(n_shots)).collect();
+        let zs = (0..n_qubits).map(|_| BitArray::zeros(n_shots)).collect();
+        let readout_errors = vec![0.0; n_qubits as usize];
+        let reset_errors = vec![0.0; n_qubits as usize];
+        Self { n_qubits, n_shots, xs, zs, readout_errors, reset_errors, rng, coins: Coins::Random }
+    }
+
+    pub fn n_qubits(&self) -> u32 {
+        self.n_qubits
+    }
+
+    pub fn n_shots(&self) -> usize {
+        self.n_shots
+    }
+
+    /// Sets the probability that a measured bit is recorded flipped, for every qubit,
+    /// as `GottesmanKnillSimulator::set_readout_error` does.
+    ///
+    /// # Panics
+    /// Panics if `p` is not in [0, 1], here and in the other error setters.
+    pub fn set_readout_error(&mut self, p: f64) {
+        check_error_probability(p);
+        self.readout_errors.iter_mut().for_each(|e| *e = p);
+    }
+
+    pub fn set_qubit_readout_error(&mut self, q: u32, p: f64) {
+        check_error_probability(p);
+        self.readout_errors[q as usize] = p;
+    }
+
+    /// Sets the probability that a reset leaves a qubit in the orthogonal state, for every qubit,
+    /// as `GottesmanKnillSimulator::set_reset_error` does.
+    pub fn set_reset_error(&mut self, p: f64) {
+        check_error_probability(p);
+        self.reset_errors.iter_mut().for_each(|e| *e = p);
+    }
+
+    pub fn set_qubit_reset_error(&mut self, q: u32, p: f64) {
+        check_error_probability(p);
+        self.reset_errors[q as usize] = p;
+    }
+
+    /// Runs `ops` for every shot and returns one row of measured slots per shot.
+    pub fn sample<L>(&mut self, ops: &[OpArgs<L>]) -> Result<Vec<BitArray>, FrameError>
+            where L: Layer<Qubit = u32, Slot = u32> + Debug {
+        let insts = ops.iter().map(|op| {
+            Instruction::from_op(op).ok_or_else(|| FrameError::Instruction(InstructionError::UnknownOp(format!("{:?}", *op))))
+        }).collect::<Result<Vec<_>, _>>()?;
+        self.sample_instructions(&insts)
+    }
+
+    /// Runs `insts` for every shot and returns one row of measured slots per shot.
+    /// Rows have one slot per qubit, or more if `insts` uses higher slots.
+    /// Noise is drawn independently for each shot.
+    pub fn sample_instructions(&mut self, insts: &[Instruction]) -> Result<Vec<BitArray>, FrameError> {
+        let flips = self.propagate(insts)?;
+        Ok(self.rows(&flips.reference.measured, &flips.slots))
+    }
+
+    /// Runs `insts` for every shot and returns one row of detection events and one row of
+    /// observable flips per shot, both relative to the noiseless values of the circuit.
+    pub fn sample_detectors(&mut self, insts: &[Instruction]) -> Result<(Vec<BitArray>, Vec<BitArray>), FrameError> {
+        let flips = self.propagate(insts)?;
+        let events = self.rows(&BitArray::zeros(flips.detectors.len()), &flips.detectors);
+        let observables = self.rows(&BitArray::zeros(flips.observables.len()), &flips.observables);
+        Ok((events, observables))
+    }
+
+    /// Transposes flips, one array of shots per column, into rows of `base` with the flips applied.
+    fn rows(&self, base: &BitArray, columns: &[BitArray]) -> Vec<BitArray> {
+        let mut rows: Vec<_> = (0..self.n_shots).map(|_| base.clone()).collect();
+        for (col, flip) in columns.iter().enumerate() {
+            for shot in flip.true_indices() {
+                rows[shot].negate(col);
+            }
+        }
+        rows
+    }
+
+    fn propagate(&mut self, insts: &[Instruction]) -> Result<Flips, FrameError> {
+        let noiseless: Vec<_> = insts.iter().filter(|inst| !inst.is_noise()).cloned().collect();
+        let n_slots = insts.iter().filter_map(|inst| inst.max_slot()).map(|s| s + 1).fold(self.n_qubits, u32::max);
+        let mut reference = GottesmanKnillSimulator::from_seed_with_slots(self.n_qubits, n_slots, self.rng.next_u64());
+        // Checks the instructions for the frames as well, which have the same qubits and record.
+        reference.run(&noiseless).map_err(FrameError::Instruction)?;
+
+        let mut flips: Vec<_> = (0..n_slots).map(|_| BitArray::zeros(self.n_shots)).collect();
+        // Flips of every measurement in order, for lookbacks into the measurement record.
+        let mut record: Vec<BitArray> = vec![];
+        let mut detectors = vec![];
+        let mut observables: Vec<BitArray> = vec![];
+        self.initialize(&mut flips);
+        for inst in insts.iter() {
+            match inst {
+                Instruction::Init => {
+                    self.initialize(&mut flips);
+                    (0..self.n_qubits).for_each(|q| self.reset_error(Basis::Z, q));
+                    record.clear();
+                    detectors.clear();
+                    observables.clear();
+                },
+                Instruction::Gate1(gate, q) => self.gate1(*gate, *q),
+                Instruction::Gate2(gate, a, b) => self.gate2(*gate, *a, *b),
+                Instruction::Measure(q, s) => {
+                    self.measure(*q, &mut flips[*s as usize]);
+                    self.readout_error(*q, &mut flips[*s as usize]);
+                },
+                Instruction::MeasureIn(basis, q, s) => {
+                    self.measure_in(*basis, *q, &mut flips[*s as usize]);
+                    self.readout_error(*q, &mut flips[*s as usize]);
+                },
+                Instruction::Reset(basis, q) => {
+                    self.reset(*basis, *q);
+                    self.reset_error(*basis, *q);
+                },
+                Instruction::MeasureReset(basis, q, s) => {
+                    self.measure_in(*basis, *q, &mut flips[*s as usize]);
+                    self.readout_error(*q, &mut flips[*s as usize]);
+                    self.reset(*basis, *q);
+                    self.reset_error(*basis, *q);
+                },
+                Instruction::Mpp(p, s) => {
+                    self.measure_pauli(p, &mut flips[*s as usize]);
+                    for q in (0..p.len()).filter(|&q| p.get(q) != Pauli::I) {
+                        self.readout_error(q as u32, &mut flips[*s as usize]);
+                    }
+                },
+                Instruction::Noise1(ch, q) if matches!(self.coins, Coins::Errors(_)) => {
+                    for (p, prob) in ch.independent_components().unwrap_or_else(|| panic!("{:?} has no independent errors", ch)) {
+                        self.inject(prob, &[(p, *q)]);
+                    }
+                },
+                Instruction::Noise2(ch, a, b) if matches!(self.coins, Coins::Errors(_)) => {
+                    for ((p1, p2), prob) in ch.independent_components().unwrap_or_else(|| panic!("{:?} has no independent errors", ch)) {
+                        self.inject(prob, &[(p1, *a), (p2, *b)]);
+                    }
+                },
+                Instruction::Noise1(ch, q) => {
+                    for shot in 0..self.n_shots {
+                        if let Some(p) = ch.sample(&mut self.rng) {
+                            self.pauli(p, *q, shot);
+                        }
+                    }
+                },
+                Instruction::Noise2(ch, a, b) => {
+                    for shot in 0..self.n_shots {
+                        if let Some((p1, p2)) = ch.sample(&mut self.rng) {
+                            self.pauli(p1, *a, shot);
+                            self.pauli(p2, *b, shot);
+                        }
+                    }
+                },
+                Instruction::Clifford(c, targets) => self.clifford(c, targets),
+                Instruction::Conditional(cond, body) => {
+                    // The reference applied the instruction when its own condition held,
+                    // so the frame differs by it exactly in the shots whose condition flipped.
+                    let mask = match cond {
+                        Condition::Parity(slots) => {
+                            let mut mask = BitArray::zeros(self.n_shots);
+                            slots.iter().for_each(|&s| mask.xor_all(&flips[s as usize]));
+                            mask
+                        },
+                        Condition::Record(lookbacks) => self.record_parity(&record, lookbacks),
+                        // A single bit equal to 0 or 1 flips with the bit, and other numbers never match.
+                        Condition::Equals(slots, n) if slots.len() == 1 && *n <= 1 => flips[slots[0] as usize].clone(),
+                        Condition::Equals(slots, _) if slots.len() <= 1 => BitArray::zeros(self.n_shots),
+                        Condition::Equals(..) => return Err(FrameError::NonLinear(inst.clone())),
+                    };
+                    let (p, q) = match **body {
+                        Instruction::Gate1(gate, q) => (gate.as_pauli(), q as usize),
+                        _ => (None, 0),
+                    };
+                    let p = p.ok_or_else(|| FrameError::NonLinear(inst.clone()))?;
+                    if p.has_x() {
+                        self.xs[q].xor_all(&mask);
+                    }
+                    if p.has_z() {
+                        self.zs[q].xor_all(&mask);
+                    }
+                },
+                Instruction::XorSlot(dest, src) => {
+                    let (dest, src) = pair_mut(&mut flips, *dest as usize, *src as usize);
+                    dest.xor_all(src);
+                },
+                Instruction::Detector(_, lookbacks) => detectors.push(self.record_parity(&record, lookbacks)),
+                Instruction::ObservableInclude(k, lookbacks) => {
+                    while observables.len() <= *k as usize {
+                        observables.push(BitArray::zeros(self.n_shots));
+                    }
+                    observables[*k as usize].xor_all(&self.record_parity(&record, lookbacks));
+                },
+            }
+            if let Some(s) = inst.measured_slot() {
+                record.push(flips[s as usize].clone());
+            }
+        }
+        Ok(Flips { reference, slots: flips, detectors, observables })
+    }
+
+    fn record_parity(&self, record: &[BitArray], lookbacks: &[u32]) -> BitArray {
+        let mut parity = BitArray::zeros(self.n_shots);
+        for &k in lookbacks {
+            parity.xor_all(&record[record.len() - k as usize]);
+        }
+        parity
+    }
+
+    /// Draws a random frame bit for every shot.
+    fn coins(&mut self) -> BitArray {
+        let mut coins = BitArray::zeros(self.n_shots);
+        match &mut self.coins {
+            Coins::Random => coins.randomize(&mut self.rng),
+            Coins::Symbolic(next) => {
+                coins.negate(*next);
+                *next += 1;
+            },
+            Coins::Errors(_) => {},
+        }
+        coins
+    }
+
+    /// Returns the shots in which an error of probability `p` happens.
+    /// Only sampling has readout and reset errors, so this draws nothing in the other modes.
+    fn bernoulli(&mut self, p: f64) -> BitArray {
+        let mut hits = BitArray::zeros(self.n_shots);
+        if p > 0.0 {
+            for shot in 0..self.n_shots {
+                if self.rng.gen::<f64>() < p {
+                    hits.negate(shot);
+                }
+            }
+        }
+        hits
+    }
+
+    fn readout_error(&mut self, q: u32, flip: &mut BitArray) {
+        flip.xor_all(&self.bernoulli(self.readout_errors[q as usize]));
+    }
+
+    /// Leaves the state orthogonal to the reset one, with the error of the reference simulator:
+    /// X after a Z-basis reset, Z otherwise.
+    fn reset_error(&mut self, basis: Basis, q: u32) {
+        let hits = self.bernoulli(self.reset_errors[q as usize]);
+        match basis {
+            Basis::Z => self.xs[q as usize].xor_all(&hits),
+            Basis::X | Basis::Y => self.zs[q as usize].xor_all(&hits),
+        }
+    }
+
+    /// Applies an error of its own to the next shot, in `Coins::Errors` mode.
+    fn inject(&mut self, probability: f64, paulis: &[(Pauli, u32)]) {
+        let shot = match &mut self.coins {
+            Coins::Errors(probs) => {
+                probs.push(probability);
+                probs.len() - 1
+            },
+            _ => unreachable!(),
+        };
+        paulis.iter().for_each(|&(p, q)| self.pauli(p, q, shot));
+    }
+
+    fn pauli(&mut self, p: Pauli, q: u32, shot: usize) {
+        if p.has_x() {
+            self.xs[q as usize].negate(shot);
+        }
+        if p.has_z() {
+            self.zs[q as usize].negate(shot);
+        }
+    }
+
+    fn initialize(&mut self, flips: &mut [BitArray]) {
+        self.xs.iter_mut().for_each(|a| a.reset());
+        // Z does nothing to |0>, so a random Z frame is free and decorrelates later X-basis outcomes.
+        for q in 0..self.zs.len() {
+            self.zs[q] = self.coins();
+        }
+        flips.iter_mut().for_each(|a| a.reset());
+    }
+
+    fn measure(&mut self, q: u32, flip: &mut BitArray) {
+        flip.copy_from(&self.xs[q as usize]);
+        // After the collapse Z_q stabilizes the state, so randomizing it is free.
+        self.zs[q as usize] = self.coins();
+    }
+
+    fn measure_in(&mut self, basis: Basis, q: u32, flip: &mut BitArray) {
+        let q = q as usize;
+        match basis {
+            Basis::X => {
+                flip.copy_from(&self.zs[q]);
+                self.xs[q] = self.coins();
+            },
+            Basis::Y => {
+                flip.copy_from(&self.xs[q]);
+                flip.xor_all(&self.zs[q]);
+                let coins = self.coins();
+                self.xs[q].xor_all(&coins);
+                self.zs[q].xor_all(&coins);
+            },
+            Basis::Z => self.measure(q as u32, flip),
+        }
+    }
+
+    /// Leaves a random frame which stabilizes the reset state.
+    fn reset(&mut self, basis: Basis, q: u32) {
+        let q = q as usize;
+        match basis {
+            Basis::X => {
+                self.zs[q].reset();
+                self.xs[q] = self.coins();
+            },
+            Basis::Y => {
+                self.xs[q] = self.coins();
+                let (xs, zs) = (&self.xs[q], &mut self.zs[q]);
+                zs.copy_from(xs);
+            },
+            Basis::Z => {
+                self.xs[q].reset();
+                self.zs[q] = self.coins();
+            },
+        }
+    }
+
+    fn measure_pauli(&mut self, p: &PauliString, flip: &mut BitArray) {
+        // The outcome flips when the frame anticommutes with p.
+        flip.reset();
+        p.xs().true_indices().for_each(|q| flip.xor_all(&self.zs[q]));
+        p.zs().true_indices().for_each(|q| flip.xor_all(&self.xs[q]));
+        // p stabilizes the collapsed state, so applying it at random is free.
+        let coins = self.coins();
+        p.xs().true_indices().for_each(|q| self.xs[q].xor_all(&coins));
+        p.zs().true_indices().for_each(|q| self.zs[q].xor_all(&coins));
+    }
+
+    fn gate1(&mut self, gate: Gate1, q: u32) {
+        match gate {
+            Gate1::I | Gate1::X | Gate1::Y | Gate1::Z => {},
+            Gate1::H => self.h(q),
+            Gate1::S | Gate1::Sdg => self.s(q),
+            _ => {
+                // Frames ignore signs, so only the Pauli part of the images matters.
+                let images = gate.images();
+                let (xs, zs) = (&mut self.xs[q as usize], &mut self.zs[q as usize]);
+                for (x, z) in xs.blocks_mut().iter_mut().zip(zs.blocks_mut().iter_mut()) {
+                    let masks = [*x & !*z, *x & *z, !*x & *z];
+                    let (mut nx, mut nz) = (0, 0);
+                    for (&m, image) in masks.iter().zip(images.iter()) {
+                        if image.0 {
+                            nx |= m;
+                        }
+                        if image.1 {
+                            nz |= m;
+                        }
+                    }
+                    *x = nx;
+                    *z = nz;
+                }
+            },
+        }
+    }
+
+    fn gate2(&mut self, gate: Gate2, a: u32, b: u32) {
+        match gate {
+            Gate2::CX => self.cx(a, b),
+            Gate2::CY => {
+                self.s(b);
+                self.cx(a, b);
+                self.s(b);
+            },
+            Gate2::CZ => {
+                self.h(b);
+                self.cx(a, b);
+                self.h(b);
+            },
+            Gate2::Swap => {
+                self.xs.swap(a as usize, b as usize);
+                self.zs.swap(a as usize, b as usize);
+            },
+            Gate2::ISwap | Gate2::ISwapdg => {
+                self.h(a);
+                self.cx(a, b);
+                self.cx(b, a);
+                self.h(b);
+                self.s(b);
+                self.s(a);
+            },
+        }
+    }
+
+    /// Frames ignore signs, so the Clifford acts on them as a linear map over the x and z bits.
+    fn clifford(&mut self, c: &CliffordTableau, targets: &[u32]) {
+        let old_xs: Vec<_> = targets.iter().map(|&q| self.xs[q as usize].clone()).collect();
+        let old_zs: Vec<_> = targets.iter().map(|&q| self.zs[q as usize].clone()).collect();
+        for &q in targets {
+            self.xs[q as usize].reset();
+            self.zs[q as usize].reset();
+        }
+        for i in 0..targets.len() {
+            for ((xs, zs, _), old) in [(c.x_image(i), &old_xs[i]), (c.z_image(i), &old_zs[i])].iter() {
+                xs.true_indices().for_each(|j| self.xs[targets[j] as usize].xor_all(old));
+                zs.true_indices().for_each(|j| self.zs[targets[j] as usize].xor_all(old));
+            }
+        }
+    }
+
+    #[inline]
+    fn h(&mut self, q: u32) {
+        std::mem::swap(&mut self.xs[q as usize], &mut self.zs[q as usize]);
+    }
+
+    #[inline]
+    fn s(&mut self, q: u32) {
+        let q = q as usize;
+        self.zs[q].xor_all(&self.xs[q]);
+    }
+
+    #[inline]
+    fn cx(&mut self, c: u32, t: u32) {
+        let (xc, xt) = pair_mut(&mut self.xs, c as usize, t as usize);
+        xt.xor_all(xc);
+        let (zc, zt) = pair_mut(&mut self.zs, c as usize, t as usize);
+        zc.xor_all(zt);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Basis, BitArray, CliffordError, CliffordTableau, Condition, DemError, FrameError, Gate1, Gate2, GottesmanKnillSimulator, Instruction,
+                InstructionError, Noise1, Noise2, NonDeterministic, PauliFrameSimulator};
+    use lay::Layer;
+
+    #[test]
+    fn deterministic() {
+        let mut ops = GottesmanKnillSimulator::from_seed(3, 0).opsvec();
+        ops.x(0);
+        ops.cx(0, 1);
+        ops.h(2);
+        ops.s(2);
+        ops.s(2);
+        ops.h(2);
+        for i in 0..3 {
+            ops.measure(i, i);
+        }
+        let shots = PauliFrameSimulator::from_seed(3, 100, 0).sample(ops.as_ref()).unwrap();
+        assert_eq!(shots.len(), 100);
+        for shot in shots {
+            assert!(shot.get_bool(0));
+            assert!(shot.get_bool(1));
+            assert!(shot.get_bool(2));
+        }
+    }
+
+    #[test]
+    fn ghz() {
+        let mut ops = GottesmanKnillSimulator::from_seed(3, 0).opsvec();
+        ops.initialize();
+        ops.h(1);
+        ops.cx(1, 0);
+        ops.cx(1, 2);
+        ops.measure(0, 0);
+        ops.measure(1, 1);
+        ops.measure(2, 2);
+        let n_shots = 1000;
+        let shots = PauliFrameSimulator::from_seed(3, n_shots, 0).sample(ops.as_ref()).unwrap();
+        let mut ones = 0;
+        for shot in shots {
+            assert_eq!(shot.get_bool(0), shot.get_bool(1));
+            assert_eq!(shot.get_bool(0), shot.get_bool(2));
+            ones += shot.get_bool(0) as usize;
+        }
+        assert!(ones > n_shots * 2 / 5 && ones < n_shots * 3 / 5);
+    }
+
+    #[test]
+    fn x_basis_after_measurement() {
+        // Measuring |+> in Z and then in X gives two independent fair coins.
+        let n_shots = 2000;
+        let mut ops = GottesmanKnillSimulator::from_seed(2, 0).opsvec();
+        ops.h(0);
+        ops.measure(0, 0);
+        ops.h(0);
+        ops.measure(0, 1);
+        let shots = PauliFrameSimulator::from_seed(2, n_shots, 1).sample(ops.as_ref()).unwrap();
+        let mut counts = [0; 4];
+        for shot in shots {
+            counts[shot.get_bool(0) as usize * 2 + shot.get_bool(1) as usize] += 1;
+        }
+        for &c in counts.iter() {
+            assert!(c > n_shots / 5 && c < n_shots * 3 / 10, "{:?}", counts);
+        }
+    }
+
+    #[test]
+    fn noisy_bell() {
+        let n_shots = 4000;
+        let insts = vec![
+            Instruction::Gate1(Gate1::H, 0),
+            Instruction::Gate2(Gate2::CX, 0, 1),
+            Instruction::Noise1(Noise1::BitFlip(0.25), 1),
+            Instruction::Noise2(Noise2::Depolarize(0.0), 0, 1),
+            Instruction::Measure(0, 0),
+            Instruction::Measure(1, 1),
+        ];
+        let shots = PauliFrameSimulator::from_seed(2, n_shots, 0).sample_instructions(&insts).unwrap();
+        let differ = shots.iter().filter(|shot| shot.get_bool(0) != shot.get_bool(1)).count();
+        assert!((differ as f64 / n_shots as f64 - 0.25).abs() < 0.03, "{}", differ);
+    }
+
+    #[test]
+    fn extended_gates() {
+        let insts = vec![
+            Instruction::Gate1(Gate1::SqrtY, 0),
+            Instruction::Gate1(Gate1::HXY, 1),
+            Instruction::Gate1(Gate1::H, 1),
+            Instruction::Gate2(Gate2::CZ, 0, 1),
+            Instruction::Gate1(Gate1::H, 1),
+            Instruction::Gate1(Gate1::X, 2),
+            Instruction::Gate2(Gate2::ISwap, 2, 3),
+            Instruction::Gate2(Gate2::CY, 3, 4),
+            Instruction::Gate2(Gate2::Swap, 4, 5),
+            Instruction::Measure(0, 0),
+            Instruction::Measure(1, 1),
+            Instruction::Measure(2, 2),
+            Instruction::Measure(3, 3),
+            Instruction::Measure(4, 4),
+            Instruction::Measure(5, 5),
+        ];
+        let shots = PauliFrameSimulator::from_seed(6, 500, 0).sample_instructions(&insts).unwrap();
+        let mut ones = 0;
+        for shot in shots.iter() {
+            // HXY flips Z, so qubit 1 starts from |1>.
+            assert_ne!(shot.get_bool(0), shot.get_bool(1));
+            assert_eq!((0..6).skip(2).map(|i| shot.get_bool(i)).collect::<Vec<_>>(),
+                       vec![false, true, false, true]);
+            ones += shot.get_bool(0) as usize;
+        }
+        assert!(ones > 200 && ones < 300, "{}", ones);
+    }
+
+    #[test]
+    fn clifford() {
+        // CX(0, 1): X0 -> X0 X1, X1 -> X1, Z0 -> Z0, Z1 -> Z0 Z1.
+        let bits = |b: &[bool]| {
+            let mut a = BitArray::zeros(b.len());
+            b.iter().enumerate().for_each(|(i, &v)| a.set_bool(i, v));
+            a
+        };
+        let xs = vec![bits(&[true, true]), bits(&[false, true]), bits(&[false, false]), bits(&[false, false])];
+        let zs = vec![bits(&[false, false]), bits(&[false, false]), bits(&[true, false]), bits(&[true, true])];
+        let cx = CliffordTableau::from_rows(xs, zs, BitArray::zeros(4)).unwrap();
+        let insts = vec![
+            Instruction::Gate1(Gate1::H, 2),
+            Instruction::Clifford(cx.clone(), vec![2, 0]),
+            Instruction::Noise1(Noise1::BitFlip(0.5), 1),
+            Instruction::Measure(0, 0),
+            Instruction::Measure(2, 1),
+        ];
+        let shots = PauliFrameSimulator::from_seed(3, 500, 0).sample_instructions(&insts).unwrap();
+        let ones = shots.iter().filter(|shot| shot.get_bool(0)).count();
+        assert!(shots.iter().all(|shot| shot.get_bool(0) == shot.get_bool(1)));
+        assert!(ones > 200 && ones < 300, "{}", ones);
+        for targets in [vec![2], vec![0, 0], vec![0, 3]].iter() {
+            let bad = [Instruction::Clifford(cx.clone(), targets.clone())];
+            assert_eq!(PauliFrameSimulator::from_seed(3, 10, 0).sample_instructions(&bad),
+                       Err(FrameError::Instruction(InstructionError::Clifford(CliffordError::Targets))));
+        }
+    }
+
+    #[test]
+    fn pauli_product() {
+        let p = |s: &str| s.parse().unwrap();
+        let insts = vec![
+            Instruction::Mpp(p("XXX"), 0),
+            Instruction::Mpp(p("ZZI"), 1),
+            Instruction::Mpp(p("-IZZ"), 2),
+            Instruction::Mpp(p("YYX"), 3),
+            Instruction::Mpp(p("XXX"), 4),
+        ];
+        let shots = PauliFrameSimulator::from_seed(5, 500, 0).sample_instructions(&insts).unwrap();
+        let ones = shots.iter().filter(|shot| shot.get_bool(0)).count();
+        for shot in shots.iter() {
+            assert!(!shot.get_bool(1));
+            assert!(shot.get_bool(2));
+            // YYX = -(XXX)(ZZI)
+            assert_ne!(shot.get_bool(3), shot.get_bool(0));
+            assert_eq!(shot.get_bool(4), shot.get_bool(0));
+        }
+        assert!(ones > 200 && ones < 300, "{}", ones);
+    }
+
+    #[test]
+    fn basis_measurements_and_resets() {
+        let insts = vec![
+            Instruction::Gate1(Gate1::H, 0),
+            Instruction::Gate2(Gate2::CX, 0, 1),
+            Instruction::MeasureIn(Basis::Y, 0, 0),
+            Instruction::Reset(Basis::Y, 0),
+            Instruction::MeasureIn(Basis::Y, 0, 1),
+            Instruction::MeasureReset(Basis::X, 1, 2),
+            Instruction::MeasureIn(Basis::X, 1, 3),
+            Instruction::MeasureIn(Basis::Z, 1, 4),
+            Instruction::Gate1(Gate1::X, 2),
+            Instruction::MeasureReset(Basis::Z, 2, 5),
+            Instruction::Measure(2, 6),
+        ];
+        let shots = PauliFrameSimulator::from_seed(7, 1000, 0).sample_instructions(&insts).unwrap();
+        let mut counts = [0; 3];
+        for shot in shots.iter() {
+            let bits: Vec<_> = (0..7).map(|i| shot.get_bool(i)).collect();
+            assert_eq!((bits[1], bits[3], bits[5], bits[6]), (false, false, true, false));
+            counts[0] += bits[0] as usize;
+            counts[1] += bits[2] as usize;
+            counts[2] += bits[4] as usize;
+        }
+        for &c in counts.iter() {
+            assert!(c > 400 && c < 600, "{:?}", counts);
+        }
+    }
+
+    #[test]
+    fn feed_forward() {
+        let pauli_if = |gate, q, slots| {
+            Instruction::Conditional(Condition::Parity(slots), Box::new(Instruction::Gate1(gate, q)))
+        };
+        // Teleports |+i> from qubit 0 to qubit 2, then undoes a random X on qubit 3.
+        let insts = vec![
+            Instruction::Gate1(Gate1::H, 0),
+            Instruction::Gate1(Gate1::S, 0),
+            Instruction::Gate1(Gate1::H, 1),
+            Instruction::Gate2(Gate2::CX, 1, 2),
+            Instruction::Gate2(Gate2::CX, 0, 1),
+            Instruction::Gate1(Gate1::H, 0),
+            Instruction::Measure(0, 0),
+            Instruction::Measure(1, 1),
+            pauli_if(Gate1::X, 2, vec![1]),
+            pauli_if(Gate1::Z, 2, vec![0]),
+            Instruction::MeasureIn(Basis::Y, 2, 2),
+            Instruction::Noise1(Noise1::BitFlip(0.5), 3),
+            Instruction::Measure(3, 3),
+            Instruction::XorSlot(4, 3),
+            pauli_if(Gate1::X, 3, vec![4]),
+            Instruction::Measure(3, 5),
+        ];
+        let shots = PauliFrameSimulator::from_seed(6, 500, 0).sample_instructions(&insts).unwrap();
+        let ones = shots.iter().filter(|shot| shot.get_bool(3)).count();
+        for shot in shots.iter() {
+            assert!(!shot.get_bool(2));
+            assert_eq!(shot.get_bool(4), shot.get_bool(3));
+            assert!(!shot.get_bool(5));
+        }
+        assert!(ones > 200 && ones < 300, "{}", ones);
+    }
+
+    #[test]
+    fn slots_beyond_qubits() {
+        let mut insts = vec![Instruction::Gate1(Gate1::X, 1)];
+        for round in 0..10 {
+            insts.push(Instruction::MeasureReset(Basis::Z, 0, 2 * round));
+            insts.push(Instruction::Measure(1, 2 * round + 1));
+        }
+        let shots = PauliFrameSimulator::from_seed(2, 10, 0).sample_instructions(&insts).unwrap();
+        for shot in shots.iter() {
+            assert_eq!(shot.len(), 20);
+            assert!((0..20).all(|s| shot.get_bool(s) == (s % 2 == 1)));
+        }
+    }
+
+    #[test]
+    fn record_lookback() {
+        let x_if_rec = |q, lookbacks| {
+            Instruction::Conditional(Condition::Record(lookbacks), Box::new(Instruction::Gate1(Gate1::X, q)))
+        };
+        // Copies the parity of two random bits onto qubit 2 through the measurement record.
+        let insts = vec![
+            Instruction::Gate1(Gate1::H, 0),
+            Instruction::Gate1(Gate1::H, 1),
+            Instruction::Measure(0, 0),
+            Instruction::Measure(1, 0),
+            x_if_rec(2, vec![1, 2]),
+            Instruction::Measure(2, 1),
+            Instruction::Measure(0, 2),
+        ];
+        let shots = PauliFrameSimulator::from_seed(3, 500, 0).sample_instructions(&insts).unwrap();
+        let ones = shots.iter().filter(|shot| shot.get_bool(1)).count();
+        for shot in shots.iter() {
+            assert_eq!(shot.get_bool(1), shot.get_bool(0) ^ shot.get_bool(2));
+        }
+        assert!(ones > 200 && ones < 300, "{}", ones);
+    }
+
+    #[test]
+    fn detectors() {
+        // A Bell pair whose ZZ parity is deterministic although each outcome is random.
+        let mut insts = vec![
+            Instruction::Gate1(Gate1::H, 0),
+            Instruction::Gate2(Gate2::CX, 0, 1),
+            Instruction::Gate1(Gate1::X, 1),
+            Instruction::Noise1(Noise1::BitFlip(0.3), 0),
+            Instruction::Measure(0, 0),
+            Instruction::Measure(1, 1),
+            Instruction::Detector(vec![0.0, 1.0], vec![1, 2]),
+            Instruction::ObservableInclude(1, vec![1, 2]),
+        ];
+        let reference = PauliFrameSimulator::check_detectors(2, &insts).unwrap();
+        assert_eq!(reference.detectors.true_indices().collect::<Vec<_>>(), vec![0]);
+        assert_eq!(reference.observables.len(), 2);
+        assert_eq!(reference.observables.true_indices().collect::<Vec<_>>(), vec![1]);
+
+        let (events, observables) = PauliFrameSimulator::from_seed(2, 1000, 0).sample_detectors(&insts).unwrap();
+        let fired = events.iter().filter(|e| e.get_bool(0)).count();
+        assert!(fired > 240 && fired < 360, "{}", fired);
+        for (e, o) in events.iter().zip(observables.iter()) {
+            assert_eq!(e.len(), 1);
+            assert!(!o.get_bool(0));
+            assert_eq!(e.get_bool(0), o.get_bool(1));
+        }
+
+        insts.push(Instruction::Detector(vec![], vec![2]));
+        insts.push(Instruction::ObservableInclude(0, vec![1]));
+        let err = NonDeterministic { detectors: vec![1], observables: vec![0] };
+        assert_eq!(PauliFrameSimulator::check_detectors(2, &insts), Err(DemError::NonDeterministic(err)));
+    }
+
+    #[test]
+    fn detectors_through_feed_forward() {
+        let x_if_rec = |q, lookbacks| {
+            Instruction::Conditional(Condition::Record(lookbacks), Box::new(Instruction::Gate1(Gate1::X, q)))
+        };
+        // Copies a random bit onto qubit 1, then measures a freshly reset qubit.
+        let insts = vec![
+            Instruction::Gate1(Gate1::H, 0),
+            Instruction::Measure(0, 0),
+            x_if_rec(1, vec![1]),
+            Instruction::Mpp("Z1".parse().unwrap(), 1),
+            Instruction::Reset(Basis::X, 0),
+            Instruction::MeasureIn(Basis::X, 0, 2),
+            Instruction::Detector(vec![], vec![2, 3]),
+            Instruction::Detector(vec![], vec![1]),
+            Instruction::Detector(vec![], vec![2]),
+        ];
+        let err = NonDeterministic { detectors: vec![2], observables: vec![] };
+        assert_eq!(PauliFrameSimulator::check_detectors(2, &insts), Err(DemError::NonDeterministic(err)));
+        let reference = PauliFrameSimulator::check_detectors(2, &insts[..8]).unwrap();
+        assert_eq!(reference.detectors, BitArray::zeros(2));
+    }
+
+    #[test]
+    fn detector_error_model() {
+        // One round of a distance-3 repetition code, with ancillas 3 and 4.
+        let mut insts = vec![
+            Instruction::Noise1(Noise1::BitFlip(0.1), 0),
+            Instruction::Noise1(Noise1::BitFlip(0.2), 1),
+            Instruction::Noise1(Noise1::BitFlip(0.3), 2),
+            Instruction::Noise1(Noise1::PhaseFlip(0.3), 2),
+            Instruction::Gate2(Gate2::CX, 0, 3),
+            Instruction::Gate2(Gate2::CX, 1, 3),
+            Instruction::Gate2(Gate2::CX, 1, 4),
+            Instruction::Gate2(Gate2::CX, 2, 4),
+            Instruction::Noise1(Noise1::BitFlip(0.1), 0),
+            Instruction::Measure(3, 0),
+            Instruction::Measure(4, 1),
+            Instruction::Measure(0, 2),
+            Instruction::Measure(1, 3),
+            Instruction::Measure(2, 4),
+            Instruction::Detector(vec![1.0, 0.0], vec![5]),
+            Instruction::Detector(vec![], vec![4]),
+            Instruction::Detector(vec![], vec![3, 5]),
+            Instruction::ObservableInclude(0, vec![1]),
+        ];
+        let dem = PauliFrameSimulator::detector_error_model(5, &insts).unwrap();
+        assert_eq!(dem.n_detectors(), 3);
+        assert_eq!(dem.n_observables, 1);
+        assert_eq!(dem.errors.len(), 4);
+        assert_eq!(dem.to_string(), "error(0.1) D0\n\
+                                     error(0.2) D0 D1 D2\n\
+                                     error(0.3) D1 L0\n\
+                                     error(0.1) D2\n\
+                                     detector(1, 0) D0\n");
+
+        // Independent errors with the same effect are merged.
+        insts.insert(0, Instruction::Noise1(Noise1::BitFlip(0.1), 2));
+        let dem = PauliFrameSimulator::detector_error_model(5, &insts).unwrap();
+        assert_eq!(dem.errors[0].detectors, vec![1]);
+        assert_eq!(dem.errors[0].observables, vec![0]);
+        assert!((dem.errors[0].probability - 0.34).abs() < 1e-12);
+
+        let depolarize = Instruction::Noise1(Noise1::Depolarize(0.1), 0);
+        insts.push(depolarize.clone());
+        let dem = PauliFrameSimulator::detector_error_model(5, &insts).unwrap();
+        assert_eq!(dem.errors.len(), 4);
+        let bad = Instruction::Noise1(Noise1::PauliChannel(0.5, 0.5, 0.0), 0);
+        insts.push(bad.clone());
+        assert_eq!(PauliFrameSimulator::detector_error_model(5, &insts), Err(DemError::Channel(bad)));
+
+        let insts = vec![
+            Instruction::Gate1(Gate1::H, 0),
+            Instruction::Measure(0, 0),
+            Instruction::Detector(vec![], vec![1]),
+        ];
+        assert!(matches!(PauliFrameSimulator::detector_error_model(1, &insts), Err(DemError::NonDeterministic(_))));
+        let dem = PauliFrameSimulator::detector_error_model(1, &insts[1..]).unwrap();
+        assert_eq!(dem.to_string(), "detector D0\n");
+    }
+
+    #[test]
+    fn non_linear_conditions() {
+        let if_equals = |slots, n, inst| Instruction::Conditional(Condition::Equals(slots, n), Box::new(inst));
+        let mut insts = vec![
+            Instruction::Gate1(Gate1::H, 0),
+            Instruction::Measure(0, 0),
+            if_equals(vec![0], 0, Instruction::Gate1(Gate1::X, 1)),
+            if_equals(vec![0], 2, Instruction::Gate1(Gate1::X, 2)),
+            Instruction::Measure(1, 1),
+            Instruction::Measure(2, 2),
+        ];
+        let shots = PauliFrameSimulator::from_seed(3, 200, 0).sample_instructions(&insts).unwrap();
+        assert!(shots.iter().all(|shot| shot.get_bool(1) != shot.get_bool(0) && !shot.get_bool(2)));
+
+        let equals = if_equals(vec![0, 1], 3, Instruction::Gate1(Gate1::X, 2));
+        insts.push(equals.clone());
+        let err = FrameError::NonLinear(equals);
+        assert_eq!(PauliFrameSimulator::from_seed(3, 10, 0).sample_instructions(&insts), Err(err.clone()));
+        assert_eq!(PauliFrameSimulator::check_detectors(3, &insts), Err(DemError::Frame(err)));
+        let h_if = Instruction::Conditional(Condition::Parity(vec![0]), Box::new(Instruction::Gate1(Gate1::H, 2)));
+        insts.pop();
+        insts.push(h_if.clone());
+        assert_eq!(PauliFrameSimulator::detector_error_model(3, &insts), Err(DemError::Frame(FrameError::NonLinear(h_if))));
+    }
+
+    #[test]
+    fn readout_and_reset_errors() {
+        let insts = vec![
+            Instruction::Init,
+            Instruction::Measure(0, 0),
+            Instruction::Mpp("ZZI".parse().unwrap(), 1),
+            Instruction::Reset(Basis::X, 2),
+            Instruction::MeasureIn(Basis::X, 2, 2),
+            Instruction::Measure(1, 3),
+        ];
+        let mut sim = PauliFrameSimulator::from_seed(3, 1000, 0);
+        sim.set_qubit_readout_error(0, 1.0);
+        sim.set_qubit_reset_error(2, 1.0);
+        sim.set_qubit_reset_error(1, 0.2);
+        let shots = sim.sample_instructions(&insts).unwrap();
+        let mut ones = 0;
+        for shot in shots.iter() {
+            assert!(shot.get_bool(0));
+            assert_eq!(shot.get_bool(1), !shot.get_bool(3));
+            assert!(shot.get_bool(2));
+            ones += shot.get_bool(3) as u32;
+        }
+        assert!(ones > 150 && ones < 250, "{}", ones);
+    }
+}
@@ -0,0 +1,473 @@
+use lay::{Layer, OpsVec, operations::OpArgs};
+
+use crate::opid;
+
+/// Identity gate, kept as an operation so it can carry noise or timing.
+pub trait IGate: Layer<Qubit = u32> + Sized {
+    fn i(q: u32) -> OpArgs<Self> {
+        OpArgs::Q(opid::I, q)
+    }
+}
+
+pub trait SqrtXGate: Layer<Qubit = u32> + Sized {
+    fn sx(q: u32) -> OpArgs<Self> {
+        OpArgs::Q(opid::SX, q)
+    }
+
+    fn sxdg(q: u32) -> OpArgs<Self> {
+        OpArgs::Q(opid::SXDG, q)
+    }
+}
+
+pub trait SqrtYGate: Layer<Qubit = u32> + Sized {
+    fn sqrt_y(q: u32) -> OpArgs<Self> {
+        OpArgs::Q(opid::SQRT_Y, q)
+    }
+
+    fn sqrt_ydg(q: u32) -> OpArgs<Self> {
+        OpArgs::Q(opid::SQRT_YDG, q)
+    }
+}
+
+/// Hadamard-like gates exchanging X with Y and Y with Z.
+pub trait HVariantGate: Layer<Qubit = u32> + Sized {
+    fn h_xy(q: u32) -> OpArgs<Self> {
+        OpArgs::Q(opid::H_XY, q)
+    }
+
+    fn h_yz(q: u32) -> OpArgs<Self> {
+        OpArgs::Q(opid::H_YZ, q)
+    }
+}
+
+/// Gates cycling X -> Y -> Z and back.
+pub trait CycleGate: Layer<Qubit = u32> + Sized {
+    fn c_xyz(q: u32) -> OpArgs<Self> {
+        OpArgs::Q(opid::C_XYZ, q)
+    }
+
+    fn c_zyx(q: u32) -> OpArgs<Self> {
+        OpArgs::Q(opid::C_ZYX, q)
+    }
+}
+
+pub trait CYGate: Layer<Qubit = u32> + Sized {
+    fn cy(c: u32, t: u32) -> OpArgs<Self> {
+        OpArgs::QQ(opid::CY, c, t)
+    }
+}
+
+pub trait CZGate: Layer<Qubit = u32> + Sized {
+    fn cz(a: u32, b: u32) -> OpArgs<Self> {
+        OpArgs::QQ(opid::CZ, a, b)
+    }
+}
+
+pub trait SwapGate: Layer<Qubit = u32> + Sized {
+    fn swap(a: u32, b: u32) -> OpArgs<Self> {
+        OpArgs::QQ(opid::SWAP, a, b)
+    }
+}
+
+pub trait ISwapGate: Layer<Qubit = u32> + Sized {
+    fn iswap(a: u32, b: u32) -> OpArgs<Self> {
+        OpArgs::QQ(opid::ISWAP, a, b)
+    }
+
+    fn iswapdg(a: u32, b: u32) -> OpArgs<Self> {
+        OpArgs::QQ(opid::ISWAPDG, a, b)
+    }
+}
+
+/// Layers which accept every extended Clifford gate of `crate::opid`.
+/// Implemented for any layer implementing the gate traits above.
+pub trait CliffordGate: IGate + SqrtXGate + SqrtYGate + HVariantGate + CycleGate
+                        + CYGate + CZGate + SwapGate + ISwapGate {}
+
+impl<L> CliffordGate for L
+    where L: IGate + SqrtXGate + SqrtYGate + HVariantGate + CycleGate + CYGate + CZGate + SwapGate + ISwapGate {}
+
+/// X- and Y-basis measurements and measure-and-reset.
+pub trait MeasureBasisGate: Layer<Qubit = u32> + Sized {
+    fn mx(q: u32, s: Self::Slot) -> OpArgs<Self> {
+        OpArgs::QS(opid::MX, q, s)
+    }
+
+    fn my(q: u32, s: Self::Slot) -> OpArgs<Self> {
+        OpArgs::QS(opid::MY, q, s)
+    }
+
+    fn mr(q: u32, s: Self::Slot) -> OpArgs<Self> {
+        OpArgs::QS(opid::MR, q, s)
+    }
+
+    fn mrx(q: u32, s: Self::Slot) -> OpArgs<Self> {
+        OpArgs::QS(opid::MRX, q, s)
+    }
+
+    fn mry(q: u32, s: Self::Slot) -> OpArgs<Self> {
+        OpArgs::QS(opid::MRY, q, s)
+    }
+}
+
+pub trait ResetGate: Layer<Qubit = u32> + Sized {
+    fn reset(q: u32) -> OpArgs<Self> {
+        OpArgs::Q(opid::RESET, q)
+    }
+
+    fn rx(q: u32) -> OpArgs<Self> {
+        OpArgs::Q(opid::RX, q)
+    }
+
+    fn ry(q: u32) -> OpArgs<Self> {
+        OpArgs::Q(opid::RY, q)
+    }
+}
+
+/// Paulis conditioned on a slot or on the measurement record.
+pub trait FeedForwardGate: Layer<Qubit = u32, Slot = u32> + Sized {
+    fn x_if(q: u32, s: u32) -> OpArgs<Self> {
+        OpArgs::QS(opid::X_IF, q, s)
+    }
+
+    fn y_if(q: u32, s: u32) -> OpArgs<Self> {
+        OpArgs::QS(opid::Y_IF, q, s)
+    }
+
+    fn z_if(q: u32, s: u32) -> OpArgs<Self> {
+        OpArgs::QS(opid::Z_IF, q, s)
+    }
+
+    /// XORs slot `src` into slot `dest`. See `opid::XOR_SLOT` for the encoding.
+    fn xor_slot(dest: Self::Slot, src: Self::Slot) -> OpArgs<Self> {
+        OpArgs::QQ(opid::XOR_SLOT, dest, src)
+    }
+
+    fn x_if_rec(q: u32, rec: Lookback) -> OpArgs<Self> {
+        OpArgs::QS(opid::X_IF_REC, q, rec.get())
+    }
+
+    fn y_if_rec(q: u32, rec: Lookback) -> OpArgs<Self> {
+        OpArgs::QS(opid::Y_IF_REC, q, rec.get())
+    }
+
+    fn z_if_rec(q: u32, rec: Lookback) -> OpArgs<Self> {
+        OpArgs::QS(opid::Z_IF_REC, q, rec.get())
+    }
+}
+
+/// Pushes the X- and Y-basis measurements and the measure-and-reset operations
+/// onto a list of operations.
+pub trait MeasureOps<Slot> {
+    fn mx(&mut self, q: u32, s: Slot);
+    fn my(&mut self, q: u32, s: Slot);
+    fn mr(&mut self, q: u32, s: Slot);
+    fn mrx(&mut self, q: u32, s: Slot);
+    fn mry(&mut self, q: u32, s: Slot);
+}
+
+/// Pushes single-qubit resets onto a list of operations. Unlike `initialize`,
+/// they leave the other qubits and the measured slots alone.
+pub trait ResetOps {
+    fn reset(&mut self, q: u32);
+    fn rx(&mut self, q: u32);
+    fn ry(&mut self, q: u32);
+}
+
+/// A lookback into the measurement record: `Lookback::new(k)` is rec[-k], so
+/// `Lookback::new(1)` is the latest outcome. Not a slot.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Lookback(u32);
+
+impl Lookback {
+    /// # Panics
+    /// Panics if `k` is 0.
+    pub fn new(k: u32) -> Self {
+        assert!(k > 0, "rec[-0] does not exist");
+        Lookback(k)
+    }
+
+    pub fn get(self) -> u32 {
+        self.0
+    }
+}
+
+/// Pushes classically controlled Paulis onto a list of operations.
+/// A parity of several slots is built with `xor_slot` before conditioning on it.
+pub trait FeedForwardOps<Slot> {
+    fn x_if(&mut self, q: u32, s: Slot);
+    fn y_if(&mut self, q: u32, s: Slot);
+    fn z_if(&mut self, q: u32, s: Slot);
+    fn xor_slot(&mut self, dest: Slot, src: Slot);
+    /// Applies X if rec[-k] of the measurement record is 1.
+    fn x_if_rec(&mut self, q: u32, rec: Lookback);
+    fn y_if_rec(&mut self, q: u32, rec: Lookback);
+    fn z_if_rec(&mut self, q: u32, rec: Lookback);
+}
+
+/// Pushes the extended Clifford gates onto a list of operations, next to the lay gates of `OpsVec`.
+/// The `*Ops` traits are implemented for both `Vec<OpArgs<L>>` and `OpsVec<L>`.
+pub trait CliffordOps {
+    fn i(&mut self, q: u32);
+    fn sx(&mut self, q: u32);
+    fn sxdg(&mut self, q: u32);
+    fn sqrt_y(&mut self, q: u32);
+    fn sqrt_ydg(&mut self, q: u32);
+    fn h_xy(&mut self, q: u32);
+    fn h_yz(&mut self, q: u32);
+    fn c_xyz(&mut self, q: u32);
+    fn c_zyx(&mut self, q: u32);
+    fn cy(&mut self, c: u32, t: u32);
+    fn cz(&mut self, a: u32, b: u32);
+    fn swap(&mut self, a: u32, b: u32);
+    fn iswap(&mut self, a: u32, b: u32);
+    fn iswapdg(&mut self, a: u32, b: u32);
+}
+
+impl<L: CliffordGate> CliffordOps for Vec<OpArgs<L>> {
+    fn i(&mut self, q: u32) {
+        self.push(L::i(q));
+    }
+
+    fn sx(&mut self, q: u32) {
+        self.push(L::sx(q));
+    }
+
+    fn sxdg(&mut self, q: u32) {
+        self.push(L::sxdg(q));
+    }
+
+    fn sqrt_y(&mut self, q: u32) {
+        self.push(L::sqrt_y(q));
+    }
+
+    fn sqrt_ydg(&mut self, q: u32) {
+        self.push(L::sqrt_ydg(q));
+    }
+
+    fn h_xy(&mut self, q: u32) {
+        self.push(L::h_xy(q));
+    }
+
+    fn h_yz(&mut self, q: u32) {
+        self.push(L::h_yz(q));
+    }
+
+    fn c_xyz(&mut self, q: u32) {
+        self.push(L::c_xyz(q));
+    }
+
+    fn c_zyx(&mut self, q: u32) {
+        self.push(L::c_zyx(q));
+    }
+
+    fn cy(&mut self, c: u32, t: u32) {
+        self.push(L::cy(c, t));
+    }
+
+    fn cz(&mut self, a: u32, b: u32) {
+        self.push(L::cz(a, b));
+    }
+
+    fn swap(&mut self, a: u32, b: u32) {
+        self.push(L::swap(a, b));
+    }
+
+    fn iswap(&mut self, a: u32, b: u32) {
+        self.push(L::iswap(a, b));
+    }
+
+    fn iswapdg(&mut self, a: u32, b: u32) {
+        self.push(L::iswapdg(a, b));
+    }
+}
+
+impl<L: MeasureBasisGate> MeasureOps<L::Slot> for Vec<OpArgs<L>> {
+    fn mx(&mut self, q: u32, s: L::Slot) {
+        self.push(L::mx(q, s));
+    }
+
+    fn my(&mut self, q: u32, s: L::Slot) {
+        self.push(L::my(q, s));
+    }
+
+    fn mr(&mut self, q: u32, s: L::Slot) {
+        self.push(L::mr(q, s));
+    }
+
+    fn mrx(&mut self, q: u32, s: L::Slot) {
+        self.push(L::mrx(q, s));
+    }
+
+    fn mry(&mut self, q: u32, s: L::Slot) {
+        self.push(L::mry(q, s));
+    }
+}
+
+impl<L: ResetGate> ResetOps for Vec<OpArgs<L>> {
+    fn reset(&mut self, q: u32) {
+        self.push(L::reset(q));
+    }
+
+    fn rx(&mut self, q: u32) {
+        self.push(L::rx(q));
+    }
+
+    fn ry(&mut self, q: u32) {
+        self.push(L::ry(q));
+    }
+}
+
+impl<L: FeedForwardGate> FeedForwardOps<u32> for Vec<OpArgs<L>> {
+    fn x_if(&mut self, q: u32, s: u32) {
+        self.push(L::x_if(q, s));
+    }
+
+    fn y_if(&mut self, q: u32, s: u32) {
+        self.push(L::y_if(q, s));
+    }
+
+    fn z_if(&mut self, q: u32, s: u32) {
+        self.push(L::z_if(q, s));
+    }
+
+    fn xor_slot(&mut self, dest: u32, src: u32) {
+        self.push(L::xor_slot(dest, src));
+    }
+
+    fn x_if_rec(&mut self, q: u32, rec: Lookback) {
+        self.push(L::x_if_rec(q, rec));
+    }
+
+    fn y_if_rec(&mut self, q: u32, rec: Lookback) {
+        self.push(L::y_if_rec(q, rec));
+    }
+
+    fn z_if_rec(&mut self, q: u32, rec: Lookback) {
+        self.push(L::z_if_rec(q, rec));
+    }
+}
+
+impl<L: CliffordGate> CliffordOps for OpsVec<L> {
+    fn i(&mut self, q: u32) {
+        self.as_mut_vec().i(q);
+    }
+
+    fn sx(&mut self, q: u32) {
+        self.as_mut_vec().sx(q);
+    }
+
+    fn sxdg(&mut self, q: u32) {
+        self.as_mut_vec().sxdg(q);
+    }
+
+    fn sqrt_y(&mut self, q: u32) {
+        self.as_mut_vec().sqrt_y(q);
+    }
+
+    fn sqrt_ydg(&mut self, q: u32) {
+        self.as_mut_vec().sqrt_ydg(q);
+    }
+
+    fn h_xy(&mut self, q: u32) {
+        self.as_mut_vec().h_xy(q);
+    }
+
+    fn h_yz(&mut self, q: u32) {
+        self.as_mut_vec().h_yz(q);
+    }
+
+    fn c_xyz(&mut self, q: u32) {
+        self.as_mut_vec().c_xyz(q);
+    }
+
+    fn c_zyx(&mut self, q: u32) {
+        self.as_mut_vec().c_zyx(q);
+    }
+
+    fn cy(&mut self, c: u32, t: u32) {
+        self.as_mut_vec().cy(c, t);
+    }
+
+    fn cz(&mut self, a: u32, b: u32) {
+        self.as_mut_vec().cz(a, b);
+    }
+
+    fn swap(&mut self, a: u32, b: u32) {
+        self.as_mut_vec().swap(a, b);
+    }
+
+    fn iswap(&mut self, a: u32, b: u32) {
+        self.as_mut_vec().iswap(a, b);
+    }
+
+    fn iswapdg(&mut self, a: u32, b: u32) {
+        self.as_mut_vec().iswapdg(a, b);
+    }
+}
+
+impl<L: MeasureBasisGate> MeasureOps<L::Slot> for OpsVec<L> {
+    fn mx(&mut self, q: u32, s: L::Slot) {
+        self.as_mut_vec().mx(q, s);
+    }
+
+    fn my(&mut self, q: u32, s: L::Slot) {
+        self.as_mut_vec().my(q, s);
+    }
+
+    fn mr(&mut self, q: u32, s: L::Slot) {
+        self.as_mut_vec().mr(q, s);
+    }
+
+    fn mrx(&mut self, q: u32, s: L::Slot) {
+        self.as_mut_vec().mrx(q, s);
+    }
+
+    fn mry(&mut self, q: u32, s: L::Slot) {
+        self.as_mut_vec().mry(q, s);
+    }
+}
+
+impl<L: ResetGate> ResetOps for OpsVec<L> {
+    fn reset(&mut self, q: u32) {
+        self.as_mut_vec().reset(q);
+    }
+
+    fn rx(&mut self, q: u32) {
+        self.as_mut_vec().rx(q);
+    }
+
+    fn ry(&mut self, q: u32) {
+        self.as_mut_vec().ry(q);
+    }
+}
+
+impl<L: FeedForwardGate> FeedForwardOps<u32> for OpsVec<L> {
+    fn x_if(&mut self, q: u32, s: u32) {
+        self.as_mut_vec().x_if(q, s);
+    }
+
+    fn y_if(&mut self, q: u32, s: u32) {
+        self.as_mut_vec().y_if(q, s);
+    }
+
+    fn z_if(&mut self, q: u32, s: u32) {
+        self.as_mut_vec().z_if(q, s);
+    }
+
+    fn xor_slot(&mut self, dest: u32, src: u32) {
+        self.as_mut_vec().xor_slot(dest, src);
+    }
+
+    fn x_if_rec(&mut self, q: u32, rec: Lookback) {
+        self.as_mut_vec().x_if_rec(q, rec);
+    }
+
+    fn y_if_rec(&mut self, q: u32, rec: Lookback) {
+        self.as_mut_vec().y_if_rec(q, rec);
+    }
+
+    fn z_if_rec(&mut self, q: u32, rec: Lookback) {
+        self.as_mut_vec().z_if_rec(q, rec);
+    }
+}
@@ -0,0 +1,525 @@
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+
+use rand::Rng;
+use rand_core::RngCore;
+use lay::{Layer, operations::{opid, OpArgs}};
+
+use crate::{CliffordError, CliffordTableau, Pauli, PauliString, opid as gkopid};
+
+/// Single-qubit Clifford gates.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Gate1 {
+    I,
+    X,
+    Y,
+    Z,
+    H,
+    S,
+    Sdg,
+    /// Square root of X: X -> X, Y -> Z, Z -> -Y.
+    SqrtX,
+    SqrtXdg,
+    /// Square root of Y: X -> -Z, Y -> Y, Z -> X.
+    SqrtY,
+    SqrtYdg,
+    /// Hadamard-like gate swapping X and Y: X -> Y, Y -> X, Z -> -Z.
+    HXY,
+    /// Hadamard-like gate swapping Y and Z: X -> -X, Y -> Z, Z -> Y.
+    HYZ,
+    /// Cycles X -> Y -> Z -> X.
+    CXYZ,
+    /// Cycles Z -> Y -> X -> Z.
+    CZYX,
+}
+
+/// Basis of single-qubit measurements and resets.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Basis {
+    X,
+    Y,
+    Z,
+}
+
+/// Classical condition on measured slots.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Condition {
+    /// Holds when the XOR of the slots is 1.
+    Parity(Vec<u32>),
+    /// Holds when the XOR of the measurement record bits rec[-k] is 1, for each k listed.
+    /// rec[-1] is the latest measurement.
+    Record(Vec<u32>),
+    /// Holds when the slots, read as a binary number whose first slot is the least significant bit,
+    /// equal the value, as `if(creg==n)` of OpenQASM.
+    Equals(Vec<u32>, u64),
+}
+
+/// Two-qubit Clifford gates. The first qubit is the control of controlled gates.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Gate2 {
+    CX,
+    CY,
+    CZ,
+    Swap,
+    ISwap,
+    ISwapdg,
+}
+
+/// Stochastic single-qubit Pauli channels.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Noise1 {
+    /// X with probability p.
+    BitFlip(f64),
+    /// Z with probability p.
+    PhaseFlip(f64),
+    /// X, Y or Z, each with probability p / 3.
+    Depolarize(f64),
+    /// X, Y and Z with probabilities px, py and pz.
+    PauliChannel(f64, f64, f64),
+}
+
+/// Stochastic two-qubit Pauli channels.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Noise2 {
+    /// Each of the 15 non-identity two-qubit Paulis with probability p / 15.
+    Depolarize(f64),
+}
+
+/// Error of instructions which cannot run on the simulator they are given to.
+/// Nothing is run when it is returned.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum InstructionError {
+    /// An op which has no `Instruction` counterpart, in its Debug form.
+    UnknownOp(String),
+    /// rec[-k] with k = 0, or further back than the measurements made so far.
+    Lookback(u32),
+    /// The targets of `Instruction::Clifford` do not fit the tableau or the qubits.
+    Clifford(CliffordError),
+}
+
+impl Display for InstructionError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            InstructionError::UnknownOp(op) => write!(f, "unexpected op {}", op),
+            InstructionError::Lookback(k) => write!(f, "rec[-{}] is out of the measurement record", k),
+            InstructionError::Clifford(e) => write!(f, "Clifford instruction: {}", e),
+        }
+    }
+}
+
+impl Error for InstructionError {}
+
+/// Checks the instructions which would otherwise fail in the middle of a run on `n_qubits` qubits,
+/// after `n_measurements` measurements. Conditioned measurements are not counted, as they may not happen.
+pub(crate) fn check_instructions(insts: &[Instruction], n_qubits: u32, mut n_measurements: usize)
+        -> Result<(), InstructionError> {
+    for inst in insts {
+        inst.check(n_qubits, n_measurements)?;
+        if matches!(inst, Instruction::Init) {
+            n_measurements = 0;
+        } else if inst.measured_slot().is_some() {
+            n_measurements += 1;
+        }
+    }
+    Ok(())
+}
+
+/// Operation understood by the simulators of this crate.
+///
+/// This is a superset of what can be sent through `Layer::send`. Every `OpArgs`
+/// the simulator accepts is converted to an `Instruction` by `Instruction::from_op`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Instruction {
+    /// Resets every qubit to |0> and clears the measured slots.
+    Init,
+    Gate1(Gate1, u32),
+    Gate2(Gate2, u32, u32),
+    /// Measures a qubit in the Z basis into a slot.
+    Measure(u32, u32),
+    /// Measures a qubit in the given basis into a slot.
+    MeasureIn(Basis, u32, u32),
+    /// Resets a qubit to the +1 eigenstate of the basis, keeping the rest of the state.
+    Reset(Basis, u32),
+    /// Measures a qubit into a slot, then resets it in the same basis.
+    MeasureReset(Basis, u32, u32),
+    Noise1(Noise1, u32),
+    Noise2(Noise2, u32, u32),
+    /// Measures a Pauli product, such as X0 X1 X2 X3, into a slot.
+    Mpp(PauliString, u32),
+    /// Applies a k-qubit Clifford to the k listed qubits, in order.
+    Clifford(CliffordTableau, Vec<u32>),
+    /// Applies the instruction only if the condition holds.
+    Conditional(Condition, Box<Instruction>),
+    /// XORs the second slot into the first, to build parities for conditions.
+    XorSlot(u32, u32),
+    /// Declares a detector, the parity of the measurement record bits rec[-k] for each k listed,
+    /// which is deterministic without noise. The coordinates are only an annotation.
+    Detector(Vec<f64>, Vec<u32>),
+    /// XORs the measurement record bits rec[-k] into the logical observable of the given index.
+    ObservableInclude(u32, Vec<u32>),
+}
+
+impl Gate1 {
+    pub(crate) fn as_pauli(self) -> Option<Pauli> {
+        match self {
+            Gate1::I => Some(Pauli::I),
+            Gate1::X => Some(Pauli::X),
+            Gate1::Y => Some(Pauli::Y),
+            Gate1::Z => Some(Pauli::Z),
+            _ => None,
+        }
+    }
+
+    /// Images of X, Y and Z under conjugation by the gate, each as (x, z, negated).
+    pub(crate) fn images(self) -> [(bool, bool, bool); 3] {
+        const XP: (bool, bool, bool) = (true, false, false);
+        const XM: (bool, bool, bool) = (true, false, true);
+        const YP: (bool, bool, bool) = (true, true, false);
+        const YM: (bool, bool, bool) = (true, true, true);
+        const ZP: (bool, bool, bool) = (false, true, false);
+        const ZM: (bool, bool, bool) = (false, true, true);
+        match self {
+            Gate1::I => [XP, YP, ZP],
+            Gate1::X => [XP, YM, ZM],
+            Gate1::Y => [XM, YP, ZM],
+            Gate1::Z => [XM, YM, ZP],
+            Gate1::H => [ZP, YM, XP],
+            Gate1::S => [YP, XM, ZP],
+            Gate1::Sdg => [YM, XP, ZP],
+            Gate1::SqrtX => [XP, ZP, YM],
+            Gate1::SqrtXdg => [XP, ZM, YP],
+            Gate1::SqrtY => [ZM, YP, XP],
+            Gate1::SqrtYdg => [ZP, YP, XM],
+            Gate1::HXY => [YP, XP, ZM],
+            Gate1::HYZ => [XM, ZP, YP],
+            Gate1::CXYZ => [YP, ZP, XP],
+            Gate1::CZYX => [ZP, XP, YP],
+        }
+    }
+}
+
+impl Noise1 {
+    /// Probabilities of X, Y and Z.
+    fn probabilities(&self) -> (f64, f64, f64) {
+        match *self {
+            Noise1::BitFlip(p) => (p, 0.0, 0.0),
+            Noise1::PhaseFlip(p) => (0.0, 0.0, p),
+            Noise1::Depolarize(p) => (p / 3.0, p / 3.0, p / 3.0),
+            Noise1::PauliChannel(px, py, pz) => (px, py, pz),
+        }
+    }
+
+    /// Whether every probability is in [0, 1] and they add up to at most 1.
+    pub fn is_valid(&self) -> bool {
+        let (px, py, pz) = self.probabilities();
+        [px, py, pz, px + py + pz].iter().all(|p| (0.0..=1.0).contains(p))
+    }
+
+    /// Draws the Pauli error to apply, if any.
+    ///
+    /// # Panics
+    /// Panics if the channel is not valid.
+    pub fn sample<Rng: RngCore>(&self, rng: &mut Rng) -> Option<Pauli> {
+        assert!(self.is_valid(), "invalid Pauli channel {:?}", self);
+        let (px, py, pz) = self.probabilities();
+        let u: f64 = rng.gen();
+        if u < px {
+            Some(Pauli::X)
+        } else if u < px + py {
+            Some(Pauli::Y)
+        } else if u < px + py + pz {
+            Some(Pauli::Z)
+        } else {
+            None
+        }
+    }
+
+    /// Decomposes the channel into independent X, Y and Z errors with their probabilities,
+    /// leaving out the ones which never happen. Returns `None` if no such decomposition exists
+    /// or the channel is not valid.
+    pub fn independent_components(&self) -> Option<Vec<(Pauli, f64)>> {
+        if !self.is_valid() {
+            return None;
+        }
+        let (px, py, pz) = match *self {
+            Noise1::BitFlip(p) => return Some(vec![(Pauli::X, p)]),
+            Noise1::PhaseFlip(p) => return Some(vec![(Pauli::Z, p)]),
+            Noise1::Depolarize(p) => (p / 3.0, p / 3.0, p / 3.0),
+            Noise1::PauliChannel(px, py, pz) => (px, py, pz),
+        };
+        // Independent errors multiply the Pauli eigenvalues of the channel: an X error
+        // with probability a scales those of Y and Z by 1 - 2a.
+        let (lx, ly, lz) = (1.0 - 2.0 * (py + pz), 1.0 - 2.0 * (px + pz), 1.0 - 2.0 * (px + py));
+        if lx <= 0.0 || ly <= 0.0 || lz <= 0.0 {
+            return None;
+        }
+        let prob = |l1: f64, l2: f64, l3: f64| 0.5 - 0.5 * (l1 * l2 / l3).sqrt();
+        let components = [(Pauli::X, prob(ly, lz, lx)), (Pauli::Y, prob(lx, lz, ly)), (Pauli::Z, prob(lx, ly, lz))];
+        Some(components.iter().cloned().filter(|&(_, p)| p > 0.0).collect())
+    }
+}
+
+impl Noise2 {
+    /// Whether the probability is in [0, 1].
+    pub fn is_valid(&self) -> bool {
+        match *self {
+            Noise2::Depolarize(p) => (0.0..=1.0).contains(&p),
+        }
+    }
+
+    /// Draws the Pauli errors to apply to both qubits, if any.
+    ///
+    /// # Panics
+    /// Panics if the channel is not valid.
+    pub fn sample<Rng: RngCore>(&self, rng: &mut Rng) -> Option<(Pauli, Pauli)> {
+        assert!(self.is_valid(), "invalid Pauli channel {:?}", self);
+        match *self {
+            Noise2::Depolarize(p) => {
+                if rng.gen::<f64>() >= p {
+                    return None;
+                }
+                let paulis = [Pauli::I, Pauli::X, Pauli::Y, Pauli::Z];
+                let k = rng.gen_range(1..16);
+                Some((paulis[k / 4], paulis[k % 4]))
+            },
+        }
+    }
+
+    /// Decomposes the channel into independent two-qubit Pauli errors with their probabilities,
+    /// leaving out the ones which never happen. Returns `None` if no such decomposition exists
+    /// or the channel is not valid.
+    pub fn independent_components(&self) -> Option<Vec<((Pauli, Pauli), f64)>> {
+        if !self.is_valid() {
+            return None;
+        }
+        match *self {
+            Noise2::Depolarize(p) => {
+                // Each of the 15 errors scales the eigenvalues of 8 of the 15 Paulis by 1 - 2q.
+                let l = 1.0 - 16.0 * p / 15.0;
+                if l <= 0.0 {
+                    return None;
+                }
+                let q = 0.5 - 0.5 * l.powf(1.0 / 8.0);
+                let paulis = [Pauli::I, Pauli::X, Pauli::Y, Pauli::Z];
+                Some((1..16).filter(|_| q > 0.0).map(|k| ((paulis[k / 4], paulis[k % 4]), q)).collect())
+            },
+        }
+    }
+}
+
+impl Instruction {
+    /// Converts an operation of `Layer::send`. Returns `None` for unsupported operations.
+    /// Lookbacks are not checked here, so rec[-0] is an error when the instruction is run.
+    pub fn from_op<L>(op: &OpArgs<L>) -> Option<Self> where L: Layer<Qubit = u32, Slot = u32> {
+        let inst = match op {
+            OpArgs::Empty(id) if *id == opid::INIT => Instruction::Init,
+            OpArgs::Q(id, q) if *id == gkopid::RESET => Instruction::Reset(Basis::Z, *q),
+            OpArgs::Q(id, q) if *id == gkopid::RX => Instruction::Reset(Basis::X, *q),
+            OpArgs::Q(id, q) if *id == gkopid::RY => Instruction::Reset(Basis::Y, *q),
+            OpArgs::Q(id, q) => {
+                let gate = match *id {
+                    opid::X => Gate1::X,
+                    opid::Y => Gate1::Y,
+                    opid::Z => Gate1::Z,
+                    opid::H => Gate1::H,
+                    opid::S => Gate1::S,
+                    opid::SDG => Gate1::Sdg,
+                    gkopid::I => Gate1::I,
+                    gkopid::SX => Gate1::SqrtX,
+                    gkopid::SXDG => Gate1::SqrtXdg,
+                    gkopid::SQRT_Y => Gate1::SqrtY,
+                    gkopid::SQRT_YDG => Gate1::SqrtYdg,
+                    gkopid::H_XY => Gate1::HXY,
+                    gkopid::H_YZ => Gate1::HYZ,
+                    gkopid::C_XYZ => Gate1::CXYZ,
+                    gkopid::C_ZYX => Gate1::CZYX,
+                    _ => return None,
+                };
+                Instruction::Gate1(gate, *q)
+            },
+            OpArgs::QS(id, q, s) => match *id {
+                opid::MEAS => Instruction::Measure(*q, *s),
+                gkopid::MX => Instruction::MeasureIn(Basis::X, *q, *s),
+                gkopid::MY => Instruction::MeasureIn(Basis::Y, *q, *s),
+                gkopid::MR => Instruction::MeasureReset(Basis::Z, *q, *s),
+                gkopid::MRX => Instruction::MeasureReset(Basis::X, *q, *s),
+                gkopid::MRY => Instruction::MeasureReset(Basis::Y, *q, *s),
+                gkopid::X_IF => Instruction::conditional_pauli(Pauli::X, *q, Condition::Parity(vec![*s])),
+                gkopid::Y_IF => Instruction::conditional_pauli(Pauli::Y, *q, Condition::Parity(vec![*s])),
+                gkopid::Z_IF => Instruction::conditional_pauli(Pauli::Z, *q, Condition::Parity(vec![*s])),
+                gkopid::X_IF_REC => Instruction::conditional_pauli(Pauli::X, *q, Condition::Record(vec![*s])),
+                gkopid::Y_IF_REC => Instruction::conditional_pauli(Pauli::Y, *q, Condition::Record(vec![*s])),
+                gkopid::Z_IF_REC => Instruction::conditional_pauli(Pauli::Z, *q, Condition::Record(vec![*s])),
+                _ => return None,
+            },
+            // The only QQ operation on slots rather than qubits.
+            OpArgs::QQ(id, dest, src) if *id == gkopid::XOR_SLOT => Instruction::XorSlot(*dest, *src),
+            OpArgs::QQ(id, a, b) => {
+                let gate = match *id {
+                    opid::CX => Gate2::CX,
+                    gkopid::CY => Gate2::CY,
+                    gkopid::CZ => Gate2::CZ,
+                    gkopid::SWAP => Gate2::Swap,
+                    gkopid::ISWAP => Gate2::ISwap,
+                    gkopid::ISWAPDG => Gate2::ISwapdg,
+                    _ => return None,
+                };
+                Instruction::Gate2(gate, *a, *b)
+            },
+            _ => return None,
+        };
+        Some(inst)
+    }
+
+    fn conditional_pauli(p: Pauli, q: u32, cond: Condition) -> Self {
+        let gate = match p {
+            Pauli::I => Gate1::I,
+            Pauli::X => Gate1::X,
+            Pauli::Y => Gate1::Y,
+            Pauli::Z => Gate1::Z,
+        };
+        Instruction::Conditional(cond, Box::new(Instruction::Gate1(gate, q)))
+    }
+
+    /// Returns the slot of a measurement, which also appends to the measurement record.
+    pub(crate) fn measured_slot(&self) -> Option<u32> {
+        match self {
+            Instruction::Measure(_, s) | Instruction::MeasureIn(_, _, s) | Instruction::MeasureReset(_, _, s)
+            | Instruction::Mpp(_, s) => Some(*s),
+            _ => None,
+        }
+    }
+
+    /// Returns the largest slot the instruction reads or writes.
+    pub(crate) fn max_slot(&self) -> Option<u32> {
+        match self {
+            Instruction::XorSlot(a, b) => Some(*a.max(b)),
+            Instruction::Conditional(Condition::Parity(slots), inst)
+            | Instruction::Conditional(Condition::Equals(slots, _), inst) => {
+                slots.iter().cloned().chain(inst.max_slot()).max()
+            },
+            Instruction::Conditional(Condition::Record(_), inst) => inst.max_slot(),
+            _ => self.measured_slot(),
+        }
+    }
+
+    fn check(&self, n_qubits: u32, n_measurements: usize) -> Result<(), InstructionError> {
+        let lookbacks = |lookbacks: &[u32]| match lookbacks.iter().find(|&&k| k == 0 || k as usize > n_measurements) {
+            Some(&k) => Err(InstructionError::Lookback(k)),
+            None => Ok(()),
+        };
+        match self {
+            Instruction::Clifford(c, targets) => c.check_targets(targets, n_qubits).map_err(InstructionError::Clifford),
+            Instruction::Conditional(cond, inst) => {
+                if let Condition::Record(ks) = cond {
+                    lookbacks(ks)?;
+                }
+                inst.check(n_qubits, n_measurements)
+            },
+            Instruction::Detector(_, ks) | Instruction::ObservableInclude(_, ks) => lookbacks(ks),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn is_noise(&self) -> bool {
+        matches!(self, Instruction::Noise1(..) | Instruction::Noise2(..))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{DefaultRng, Gate1, Noise1, Noise2, Pauli};
+    use rand_core::SeedableRng;
+
+    #[test]
+    fn gate1_images_consistent() {
+        // Y = iXZ, so the image of Y is i times the image of X times the image of Z.
+        use crate::{BitArray, tableau::pauli_phase};
+        let one = |b: bool| if b { BitArray::ones(1) } else { BitArray::zeros(1) };
+        let gates = [Gate1::I, Gate1::X, Gate1::Y, Gate1::Z, Gate1::H, Gate1::S, Gate1::Sdg,
+                     Gate1::SqrtX, Gate1::SqrtXdg, Gate1::SqrtY, Gate1::SqrtYdg,
+                     Gate1::HXY, Gate1::HYZ, Gate1::CXYZ, Gate1::CZYX];
+        for &gate in gates.iter() {
+            let [(x1, z1, n1), (yx, yz, yn), (x2, z2, n2)] = gate.images();
+            assert!(x1 != x2 || z1 != z2, "{:?}", gate);
+            let phase = 1 + pauli_phase(&one(x1), &one(z1), &one(x2), &one(z2)) + 2 * (n1 as u32 + n2 as u32);
+            assert_eq!((yx, yz), (x1 ^ x2, z1 ^ z2), "{:?}", gate);
+            assert_eq!(phase & 3, 2 * yn as u32, "{:?}", gate);
+        }
+    }
+
+    #[test]
+    fn noise1_distribution() {
+        let mut rng = DefaultRng::seed_from_u64(0);
+        let ch = Noise1::PauliChannel(0.1, 0.2, 0.3);
+        let n = 20000;
+        let mut counts = [0; 4];
+        for _ in 0..n {
+            match ch.sample(&mut rng) {
+                None => counts[0] += 1,
+                Some(Pauli::X) => counts[1] += 1,
+                Some(Pauli::Y) => counts[2] += 1,
+                Some(Pauli::Z) => counts[3] += 1,
+                Some(Pauli::I) => unreachable!(),
+            }
+        }
+        for (&c, &p) in counts.iter().zip([0.4, 0.1, 0.2, 0.3].iter()) {
+            assert!((c as f64 / n as f64 - p).abs() < 0.02, "{:?}", counts);
+        }
+        assert_eq!(Noise1::BitFlip(1.0).sample(&mut rng), Some(Pauli::X));
+        assert_eq!(Noise1::PhaseFlip(0.0).sample(&mut rng), None);
+    }
+
+    #[test]
+    fn noise2_never_identity() {
+        let mut rng = DefaultRng::seed_from_u64(0);
+        let mut seen = std::collections::HashSet::new();
+        for _ in 0..1000 {
+            let (a, b) = Noise2::Depolarize(1.0).sample(&mut rng).unwrap();
+            assert!(a != Pauli::I || b != Pauli::I);
+            seen.insert((a, b));
+        }
+        assert_eq!(seen.len(), 15);
+    }
+
+    #[test]
+    fn independent_components() {
+        use crate::Noise1;
+        let ch = Noise1::PauliChannel(0.1, 0.2, 0.05);
+        let c = ch.independent_components().unwrap();
+        let (a, b, c) = (c[0].1, c[1].1, c[2].1);
+        // Composing the independent errors gives back the disjoint probabilities.
+        let px = a * (1.0 - b) * (1.0 - c) + (1.0 - a) * b * c;
+        let py = (1.0 - a) * b * (1.0 - c) + a * (1.0 - b) * c;
+        let pz = (1.0 - a) * (1.0 - b) * c + a * b * (1.0 - c);
+        assert!((px - 0.1).abs() < 1e-12 && (py - 0.2).abs() < 1e-12 && (pz - 0.05).abs() < 1e-12, "{} {} {}", px, py, pz);
+        assert_eq!(Noise1::BitFlip(0.2).independent_components(), Some(vec![(Pauli::X, 0.2)]));
+        let c = Noise1::PauliChannel(0.0, 0.1, 0.0).independent_components().unwrap();
+        assert_eq!(c.len(), 1);
+        assert_eq!(c[0].0, Pauli::Y);
+        assert!((c[0].1 - 0.1).abs() < 1e-12);
+        assert_eq!(Noise1::PauliChannel(0.5, 0.5, 0.0).independent_components(), None);
+        let c = Noise2::Depolarize(0.15).independent_components().unwrap();
+        assert_eq!(c.len(), 15);
+        assert!(c.iter().all(|&((p1, p2), _)| (p1, p2) != (Pauli::I, Pauli::I)));
+        assert_eq!(Noise2::Depolarize(1.0).independent_components(), None);
+    }
+
+    #[test]
+    fn validity() {
+        assert!(Noise1::Depolarize(1.0).is_valid());
+        assert!(Noise1::PauliChannel(0.5, 0.25, 0.25).is_valid());
+        assert!(!Noise1::PauliChannel(0.5, 0.5, 0.25).is_valid());
+        assert!(!Noise1::BitFlip(-0.1).is_valid());
+        assert!(!Noise1::PhaseFlip(f64::NAN).is_valid());
+        assert!(!Noise2::Depolarize(1.5).is_valid());
+        assert_eq!(Noise1::BitFlip(1.5).independent_components(), None);
+        assert_eq!(Noise2::Depolarize(-0.5).independent_components(), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid Pauli channel")]
+    fn sample_invalid() {
+        Noise1::BitFlip(2.0).sample(&mut DefaultRng::seed_from_u64(0));
+    }
+}
@@ -1,20 +1,50 @@
-use std::fmt::Debug;
+use std::fmt::{self, Debug, Display, Formatter};
 
+use rand::Rng as _;
 use rand_core::{RngCore, SeedableRng};
 use rand_xorshift::XorShiftRng;
-use lay::{Layer, gates::{PauliGate, HGate, SGate, CXGate}, operations::{opid, OpArgs}};
+use lay::{Layer, gates::{PauliGate, HGate, SGate, CXGate}, operations::OpArgs};
 
 mod bitarray;
+mod chp;
+mod clifford;
+mod detector;
+mod frame;
+mod gates;
+mod instruction;
+pub mod opid;
+mod pauli;
+mod qasm;
+mod state;
+mod stim;
+mod tableau;
 pub use bitarray::BitArray;
+pub use chp::{ChpCircuit, ChpError};
+pub use clifford::{CliffordError, CliffordTableau};
+pub use detector::{DemError, DetectorErrorModel, DetectorReference, ErrorMechanism, NonDeterministic};
+pub use frame::{FrameError, PauliFrameSimulator};
+pub use gates::{CliffordGate, CliffordOps, CYGate, CZGate, CycleGate, FeedForwardGate, FeedForwardOps, HVariantGate,
+                IGate, ISwapGate, Lookback, MeasureBasisGate, MeasureOps, ResetGate, ResetOps, SqrtXGate, SqrtYGate,
+                SwapGate};
+pub use instruction::{Basis, Condition, Gate1, Gate2, Instruction, InstructionError, Noise1, Noise2};
+pub use pauli::{Pauli, PauliString, ParsePauliError};
+pub use qasm::{QasmCircuit, QasmError};
+pub use state::{ImpossibleOutcome, RngState, Snapshot, StateError};
+pub use stim::{RepeatBlock, StimCircuit, StimError};
+pub use tableau::Layout;
+use tableau::Tableau;
 
 pub type DefaultRng = XorShiftRng;
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct GottesmanKnillSimulator<Rng> {
-    xs: Vec<BitArray>,
-    zs: Vec<BitArray>,
-    sgns: BitArray,
+    tableau: Tableau,
     measured: BitArray,
+    record: BitArray,
+    detectors: BitArray,
+    observables: BitArray,
+    readout_errors: Vec<f64>,
+    reset_errors: Vec<f64>,
     rng: Rng,
 }
 
@@ -22,36 +52,239 @@ impl<Rng: RngCore + Debug> PauliGate for GottesmanKnillSimulator<Rng> {}
 impl<Rng: RngCore + Debug> HGate for GottesmanKnillSimulator<Rng> {}
 impl<Rng: RngCore + Debug> SGate for GottesmanKnillSimulator<Rng> {}
 impl<Rng: RngCore + Debug> CXGate for GottesmanKnillSimulator<Rng> {}
+impl<Rng: RngCore + Debug> IGate for GottesmanKnillSimulator<Rng> {}
+impl<Rng: RngCore + Debug> SqrtXGate for GottesmanKnillSimulator<Rng> {}
+impl<Rng: RngCore + Debug> SqrtYGate for GottesmanKnillSimulator<Rng> {}
+impl<Rng: RngCore + Debug> HVariantGate for GottesmanKnillSimulator<Rng> {}
+impl<Rng: RngCore + Debug> CycleGate for GottesmanKnillSimulator<Rng> {}
+impl<Rng: RngCore + Debug> CYGate for GottesmanKnillSimulator<Rng> {}
+impl<Rng: RngCore + Debug> CZGate for GottesmanKnillSimulator<Rng> {}
+impl<Rng: RngCore + Debug> SwapGate for GottesmanKnillSimulator<Rng> {}
+impl<Rng: RngCore + Debug> ISwapGate for GottesmanKnillSimulator<Rng> {}
+impl<Rng: RngCore + Debug> MeasureBasisGate for GottesmanKnillSimulator<Rng> {}
+impl<Rng: RngCore + Debug> ResetGate for GottesmanKnillSimulator<Rng> {}
+impl<Rng: RngCore + Debug> FeedForwardGate for GottesmanKnillSimulator<Rng> {}
 
 impl GottesmanKnillSimulator<DefaultRng> {
     pub fn from_seed(n: u32, seed: u64) -> Self {
         Self::from_rng(n, DefaultRng::seed_from_u64(seed))
     }
+
+    pub fn from_seed_with_layout(n: u32, seed: u64, layout: Layout) -> Self {
+        Self::from_rng_with_layout(n, DefaultRng::seed_from_u64(seed), layout)
+    }
+
+    pub fn from_seed_with_slots(n: u32, n_slots: u32, seed: u64) -> Self {
+        Self::from_rng_with_slots(n, n_slots, DefaultRng::seed_from_u64(seed))
+    }
+
+    /// Runs `insts` from the initial state once per shot and returns the measured slots of every shot.
+    ///
+    /// Shot i draws from its own RNG derived from `seed` and i, so a shot does not depend on
+    /// how many shots are taken. The RNG of the simulator is replaced.
+    pub fn sample_shots(&mut self, insts: &[Instruction], shots: usize, seed: u64)
+            -> Result<Vec<BitArray>, InstructionError> {
+        Ok(self.iter_shots(insts, shots, seed)?.collect())
+    }
+
+    /// Like `sample_shots`, but runs each shot only when the iterator is advanced,
+    /// so the shots need not be kept in memory.
+    pub fn iter_shots<'a>(&'a mut self, insts: &'a [Instruction], shots: usize, seed: u64)
+            -> Result<Shots<'a>, InstructionError> {
+        instruction::check_instructions(insts, self.n_qubits(), 0)?;
+        Ok(Shots { sim: self, insts, seed, shot: 0, shots })
+    }
+}
+
+/// Iterator over the measured slots of each shot, created by `iter_shots`.
+pub struct Shots<'a> {
+    sim: &'a mut GottesmanKnillSimulator<DefaultRng>,
+    insts: &'a [Instruction],
+    seed: u64,
+    shot: usize,
+    shots: usize,
+}
+
+impl Iterator for Shots<'_> {
+    type Item = BitArray;
+
+    fn next(&mut self) -> Option<BitArray> {
+        if self.shot == self.shots {
+            return None;
+        }
+        self.sim.rng = DefaultRng::seed_from_u64(shot_seed(self.seed, self.shot as u64));
+        self.shot += 1;
+        self.sim.initialize();
+        self.insts.iter().for_each(|inst| self.sim.execute(inst));
+        Some(self.sim.measured.clone())
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let n = self.shots - self.shot;
+        (n, Some(n))
+    }
+}
+
+/// Mixes the seed and the shot index with SplitMix64.
+fn shot_seed(seed: u64, shot: u64) -> u64 {
+    let mut z = seed.wrapping_add(shot.wrapping_add(1).wrapping_mul(0x9e37_79b9_7f4a_7c15));
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
 }
 
 impl<Rng: RngCore> GottesmanKnillSimulator<Rng> {
     pub fn from_rng(n: u32, rng: Rng) -> Self {
-        let xs = (0..n).map(|_| BitArray::zeros(n as usize)).collect();
-        let zs = (0..n).map(|i| {
-            let mut arr = BitArray::zeros(n as usize);
-            arr.negate(i as usize);
-            arr
-        }).collect();
-        let sgns = BitArray::zeros(n as usize);
-        let measured = BitArray::zeros(n as usize);
-        Self { xs, zs, sgns, measured, rng }
+        Self::from_rng_with_layout(n, rng, Layout::default())
+    }
+
+    pub fn from_rng_with_layout(n: u32, rng: Rng, layout: Layout) -> Self {
+        Self::new(n, n, rng, layout)
+    }
+
+    /// Creates a simulator with `n_slots` measured slots instead of one per qubit.
+    pub fn from_rng_with_slots(n: u32, n_slots: u32, rng: Rng) -> Self {
+        Self::new(n, n_slots, rng, Layout::default())
+    }
+
+    fn new(n: u32, n_slots: u32, rng: Rng, layout: Layout) -> Self {
+        let tableau = Tableau::new(n as usize, layout);
+        let measured = BitArray::zeros(n_slots as usize);
+        let readout_errors = vec![0.0; n as usize];
+        let reset_errors = vec![0.0; n as usize];
+        let record = BitArray::zeros(0);
+        let detectors = BitArray::zeros(0);
+        let observables = BitArray::zeros(0);
+        Self { tableau, measured, record, detectors, observables, readout_errors, reset_errors, rng }
     }
 }
 
 impl<Rng> GottesmanKnillSimulator<Rng> {
+    /// Prints the destabilizers, the stabilizers and the measured slots.
     pub fn dump_print(&self) {
-        println!("xs:   {:?}", self.xs);
-        println!("zs:   {:?}", self.zs);
-        println!("sgns: {:?}", self.sgns);
-        println!("measured: {:?}", self.measured);
+        println!("{:#}", self);
+        println!("measured: {}", self.measured.to_bit_string());
+    }
+    /// Returns the tableau and the measurement register as a JSON object.
+    ///
+    /// Rows are written as Pauli strings like "+XZI", and measured bits as 0 and 1 by slot.
+    pub fn to_json(&self) -> String {
+        fn strings(rows: &[PauliString]) -> String {
+            rows.iter().map(|p| format!("\"{}\"", p)).collect::<Vec<_>>().join(", ")
+        }
+        fn bits(buf: &BitArray) -> String {
+            buf.to_bit_string().chars().map(String::from).collect::<Vec<_>>().join(", ")
+        }
+        format!("{{\n  \"n_qubits\": {},\n  \"stabilizers\": [{}],\n  \"destabilizers\": [{}],\n  \
+                 \"measured\": [{}],\n  \"record\": [{}]\n}}",
+                self.n_qubits(), strings(&self.stabilizers()), strings(&self.destabilizers()),
+                bits(&self.measured), bits(&self.record))
     }
     pub fn n_qubits(&self) -> u32 {
-        self.xs.len() as _
+        self.tableau.n_qubits() as _
+    }
+    pub fn n_slots(&self) -> u32 {
+        self.measured.len() as _
+    }
+    /// Every recorded measurement outcome since the last `initialize`, in order.
+    pub fn measurement_record(&self) -> &BitArray {
+        &self.record
+    }
+    /// Parities of the detectors since the last `initialize`, in order.
+    pub fn detector_parities(&self) -> &BitArray {
+        &self.detectors
+    }
+    /// Parities of the logical observables since the last `initialize`, by index.
+    pub fn observable_parities(&self) -> &BitArray {
+        &self.observables
+    }
+    /// Returns which detectors differ from their noiseless values.
+    pub fn detection_events(&self, reference: &DetectorReference) -> BitArray {
+        let mut events = self.detectors.clone();
+        events.xor_all(&reference.detectors);
+        events
+    }
+    /// Returns which logical observables differ from their noiseless values.
+    pub fn observable_flips(&self, reference: &DetectorReference) -> BitArray {
+        let mut flips = self.observables.clone();
+        flips.xor_all(&reference.observables);
+        flips
+    }
+    pub fn layout(&self) -> Layout {
+        self.tableau.layout()
+    }
+    /// Returns the stabilizer generators of the current state, one per qubit.
+    pub fn stabilizers(&self) -> Vec<PauliString> {
+        let n = self.tableau.n_qubits();
+        (n..2 * n).map(|i| self.tableau.pauli_row(i)).collect()
+    }
+    /// Returns the destabilizers. The i-th one anticommutes only with the i-th stabilizer.
+    pub fn destabilizers(&self) -> Vec<PauliString> {
+        (0..self.tableau.n_qubits()).map(|i| self.tableau.pauli_row(i)).collect()
+    }
+    /// Returns the outcome of measuring the observable `p` if it is deterministic, true meaning
+    /// the -1 eigenvalue, or None if it would be random. Neither the state nor the RNG is touched.
+    ///
+    /// # Panics
+    /// Panics if `p` is not Hermitian or is not the identity on qubits beyond the simulated ones.
+    pub fn peek_observable(&self, p: &PauliString) -> Option<bool> {
+        self.tableau.peek(p)
+    }
+    /// Returns the expectation value of the observable `p`, which is 1, -1 or 0.
+    ///
+    /// # Panics
+    /// Panics as `peek_observable` does.
+    pub fn expectation(&self, p: &PauliString) -> f64 {
+        match self.peek_observable(p) {
+            Some(false) => 1.0,
+            Some(true) => -1.0,
+            None => 0.0,
+        }
+    }
+    /// Transposes the tableau into the given layout. The simulated state is unchanged.
+    pub fn set_layout(&mut self, layout: Layout) {
+        self.tableau.set_layout(layout);
+    }
+    /// Sets the probability that a measured bit is recorded flipped, for every qubit.
+    /// The post-measurement state is not affected.
+    ///
+    /// # Panics
+    /// Panics if `p` is not in [0, 1], here and in the other error setters.
+    pub fn set_readout_error(&mut self, p: f64) {
+        check_error_probability(p);
+        self.readout_errors.iter_mut().for_each(|e| *e = p);
+    }
+    pub fn set_qubit_readout_error(&mut self, q: u32, p: f64) {
+        check_error_probability(p);
+        self.readout_errors[q as usize] = p;
+    }
+    /// Sets the probability that a reset leaves a qubit in |1> instead of |0>, for every qubit.
+    pub fn set_reset_error(&mut self, p: f64) {
+        check_error_probability(p);
+        self.reset_errors.iter_mut().for_each(|e| *e = p);
+    }
+    pub fn set_qubit_reset_error(&mut self, q: u32, p: f64) {
+        check_error_probability(p);
+        self.reset_errors[q as usize] = p;
+    }
+}
+
+/// Checks a readout or reset error probability, like `Noise1::sample` checks its channel.
+pub(crate) fn check_error_probability(p: f64) {
+    assert!((0.0..=1.0).contains(&p), "invalid error probability {}", p);
+}
+
+impl<Rng> Display for GottesmanKnillSimulator<Rng> {
+    /// Writes the stabilizers one per line, like "+XXI".
+    /// The alternate form `{:#}` writes the destabilizers first, separated by a line of '-'.
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let mut rows = vec![];
+        if f.alternate() {
+            rows.extend(self.destabilizers().iter().map(|p| p.to_string()));
+            rows.push("-".repeat(self.n_qubits() as usize + 1));
+        }
+        rows.extend(self.stabilizers().iter().map(|p| p.to_string()));
+        f.write_str(&rows.join("\n"))
     }
 }
 
@@ -60,41 +293,25 @@ impl<Rng: RngCore + Debug> Layer for GottesmanKnillSimulator<Rng> {
     type Qubit = u32;
     type Slot = u32;
     type Buffer = BitArray;
-    type Requested = ();
-    type Response = ();
+    type Requested = Result<(), InstructionError>;
+    type Response = Result<(), InstructionError>;
 
-    fn send(&mut self, ops: &[OpArgs<Self>]) {
-        for op in ops.iter() {
-            match op {
-                OpArgs::Empty(id) if *id == opid::INIT =>
-                    self.initialize(),
-                OpArgs::Q(id, q) => {
-                    match *id {
-                        opid::X => self.x(*q),
-                        opid::Y => self.y(*q),
-                        opid::Z => self.z(*q),
-                        opid::H => self.h(*q),
-                        opid::S => self.s(*q),
-                        opid::SDG => self.sdg(*q),
-                        _ => unimplemented!("Unexpected opid {:?}", *op)
-                    }
-                },
-                OpArgs::QS(id, q, s) if *id == opid::MEAS =>
-                    self.measure(*q, *s),
-                OpArgs::QQ(id, c, t) if *id == opid::CX =>
-                    self.cx(*c, *t),
-                _ => unimplemented!("Unexpected op {:?}", *op)
-            }
-        }
+    /// Runs `ops`, or none of them if one is unknown or cannot run.
+    fn send(&mut self, ops: &[OpArgs<Self>]) -> Result<(), InstructionError> {
+        let insts = ops.iter().map(|op| {
+            Instruction::from_op(op).ok_or_else(|| InstructionError::UnknownOp(format!("{:?}", *op)))
+        }).collect::<Result<Vec<_>, _>>()?;
+        self.run(&insts)
     }
 
-    fn receive(&mut self, buf: &mut BitArray) {
+    fn receive(&mut self, buf: &mut BitArray) -> Result<(), InstructionError> {
         buf.copy_from(&self.measured);
+        Ok(())
     }
 
-    fn send_receive(&mut self, ops: &[OpArgs<Self>], buf: &mut BitArray) {
-        self.send(ops);
-        self.receive(buf);
+    fn send_receive(&mut self, ops: &[OpArgs<Self>], buf: &mut BitArray) -> Result<(), InstructionError> {
+        self.send(ops)?;
+        self.receive(buf)
     }
 
     fn make_buffer(&self) -> Self::Buffer {
@@ -103,180 +320,288 @@ impl<Rng: RngCore + Debug> Layer for GottesmanKnillSimulator<Rng> {
 }
 
 impl<Rng: RngCore> GottesmanKnillSimulator<Rng> {
+    /// Runs instructions, including the ones which have no `OpArgs` counterpart such as noise.
+    /// They are checked first, so nothing is run if one of them cannot be.
+    pub fn run(&mut self, insts: &[Instruction]) -> Result<(), InstructionError> {
+        instruction::check_instructions(insts, self.n_qubits(), self.record.len())?;
+        insts.iter().for_each(|inst| self.execute(inst));
+        Ok(())
+    }
+
+    pub fn apply(&mut self, inst: &Instruction) -> Result<(), InstructionError> {
+        self.run(std::slice::from_ref(inst))
+    }
+
+    /// Applies a checked instruction.
+    fn execute(&mut self, inst: &Instruction) {
+        match inst {
+            Instruction::Init => self.initialize(),
+            Instruction::Gate1(gate, q) => self.gate1(*gate, *q),
+            Instruction::Gate2(gate, a, b) => self.gate2(*gate, *a, *b),
+            Instruction::Measure(q, s) => {
+                self.measure(*q, *s);
+            },
+            Instruction::MeasureIn(basis, q, s) => {
+                self.rotate_basis_to_z(*basis, *q);
+                self.measure(*q, *s);
+                self.rotate_z_to_basis(*basis, *q);
+            },
+            Instruction::Reset(basis, q) => {
+                let bit = self.tableau.measure(*q as usize, &mut self.rng);
+                self.reset_z(*q, bit);
+                self.rotate_z_to_basis(*basis, *q);
+            },
+            Instruction::MeasureReset(basis, q, s) => {
+                self.rotate_basis_to_z(*basis, *q);
+                let bit = self.measure(*q, *s);
+                self.reset_z(*q, bit);
+                self.rotate_z_to_basis(*basis, *q);
+            },
+            Instruction::Mpp(p, s) => self.measure_pauli(p, *s),
+            Instruction::Noise1(ch, q) => {
+                if let Some(p) = ch.sample(&mut self.rng) {
+                    self.pauli(p, *q);
+                }
+            },
+            Instruction::Noise2(ch, a, b) => {
+                if let Some((p1, p2)) = ch.sample(&mut self.rng) {
+                    self.pauli(p1, *a);
+                    self.pauli(p2, *b);
+                }
+            },
+            Instruction::Clifford(c, targets) => {
+                let targets: Vec<_> = targets.iter().map(|&q| q as usize).collect();
+                self.tableau.clifford(c, &targets);
+            },
+            Instruction::Conditional(cond, inst) => {
+                if self.holds(cond) {
+                    self.execute(inst);
+                }
+            },
+            Instruction::XorSlot(dest, src) => {
+                if self.measured.get_bool(*src as usize) {
+                    self.measured.negate(*dest as usize);
+                }
+            },
+            Instruction::Detector(_, lookbacks) => {
+                let parity = self.record_parity(lookbacks);
+                self.detectors.push(parity);
+            },
+            Instruction::ObservableInclude(k, lookbacks) => {
+                while self.observables.len() <= *k as usize {
+                    self.observables.push(false);
+                }
+                if self.record_parity(lookbacks) {
+                    self.observables.negate(*k as usize);
+                }
+            },
+        }
+    }
+
+    /// Returns the index of rec[-k] in the measurement record. Lookbacks are checked by `run`.
+    fn lookback(&self, k: u32) -> usize {
+        self.record.len() - k as usize
+    }
+
+    fn holds(&self, cond: &Condition) -> bool {
+        match cond {
+            Condition::Parity(slots) => slots.iter().fold(false, |b, &s| b ^ self.measured.get_bool(s as usize)),
+            Condition::Record(lookbacks) => self.record_parity(lookbacks),
+            Condition::Equals(slots, value) => {
+                value.checked_shr(slots.len() as u32).unwrap_or(0) == 0 && slots.iter().enumerate().all(|(i, &s)| {
+                    self.measured.get_bool(s as usize) == (value.checked_shr(i as u32).unwrap_or(0) & 1 == 1)
+                })
+            },
+        }
+    }
+
+    fn record_parity(&self, lookbacks: &[u32]) -> bool {
+        lookbacks.iter().fold(false, |b, &k| b ^ self.record.get_bool(self.lookback(k)))
+    }
+
+    /// Applies a k-qubit Clifford to `targets`; qubit i of the Clifford is `targets[i]`.
+    pub fn apply_clifford(&mut self, c: &CliffordTableau, targets: &[u32]) -> Result<(), CliffordError> {
+        c.check_targets(targets, self.n_qubits())?;
+        let targets: Vec<_> = targets.iter().map(|&q| q as usize).collect();
+        self.tableau.clifford(c, &targets);
+        Ok(())
+    }
+
+    fn gate1(&mut self, gate: Gate1, q: u32) {
+        match gate {
+            Gate1::I => {},
+            Gate1::X => self.x(q),
+            Gate1::Y => self.y(q),
+            Gate1::Z => self.z(q),
+            Gate1::H => self.h(q),
+            Gate1::S => self.s(q),
+            Gate1::Sdg => self.sdg(q),
+            _ => self.tableau.clifford1(&gate.images(), q as usize),
+        }
+    }
+
+    fn gate2(&mut self, gate: Gate2, a: u32, b: u32) {
+        match gate {
+            Gate2::CX => self.cx(a, b),
+            Gate2::CY => {
+                self.sdg(b);
+                self.cx(a, b);
+                self.s(b);
+            },
+            Gate2::CZ => self.tableau.cz(a as usize, b as usize),
+            Gate2::Swap => self.tableau.swap(a as usize, b as usize),
+            Gate2::ISwap => {
+                self.h(a);
+                self.cx(a, b);
+                self.cx(b, a);
+                self.h(b);
+                self.s(b);
+                self.s(a);
+            },
+            Gate2::ISwapdg => {
+                self.sdg(a);
+                self.sdg(b);
+                self.h(b);
+                self.cx(b, a);
+                self.cx(a, b);
+                self.h(a);
+            },
+        }
+    }
+
+    fn pauli(&mut self, p: Pauli, q: u32) {
+        match p {
+            Pauli::I => {},
+            Pauli::X => self.x(q),
+            Pauli::Y => self.y(q),
+            Pauli::Z => self.z(q),
+        }
+    }
+
     fn initialize(&mut self) {
-        self.xs.iter_mut().for_each(|a| a.reset());
-        self.zs.iter_mut().for_each(|a| a.reset());
-        self.zs.iter_mut().enumerate().for_each(|(i, a)| a.negate(i as usize));
-        self.sgns.reset();
+        self.tableau.reset();
         self.measured.reset();
+        self.record.clear();
+        self.detectors.clear();
+        self.observables.clear();
+        for q in 0..self.n_qubits() {
+            if self.happens(self.reset_errors[q as usize]) {
+                self.x(q);
+            }
+        }
     }
 
-    fn measure(&mut self, q: u32, ch: u32) {
-        let bit = measure(self, q);
-        self.measured.set_bool(ch as usize, bit);
+    /// Returns the outcome before the readout error.
+    fn measure(&mut self, q: u32, ch: u32) -> bool {
+        let bit = self.tableau.measure(q as usize, &mut self.rng);
+        let flip = self.happens(self.readout_errors[q as usize]);
+        self.measured.set_bool(ch as usize, bit ^ flip);
+        self.record.push(bit ^ flip);
+        bit
+    }
+
+    /// Brings a qubit measured in the Z basis with outcome `bit` back to |0>.
+    fn reset_z(&mut self, q: u32, bit: bool) {
+        if bit ^ self.happens(self.reset_errors[q as usize]) {
+            self.x(q);
+        }
+    }
+
+    /// Maps the +1 eigenstate of the basis to |0>.
+    fn rotate_basis_to_z(&mut self, basis: Basis, q: u32) {
+        match basis {
+            Basis::X => self.h(q),
+            Basis::Y => {
+                self.sdg(q);
+                self.h(q);
+            },
+            Basis::Z => {},
+        }
+    }
+
+    fn rotate_z_to_basis(&mut self, basis: Basis, q: u32) {
+        match basis {
+            Basis::X => self.h(q),
+            Basis::Y => {
+                self.h(q);
+                self.s(q);
+            },
+            Basis::Z => {},
+        }
+    }
+
+    /// Measures a Pauli product without ancillas. The parity is read out from every qubit
+    /// of `p`, so each of them adds its readout error.
+    pub fn measure_pauli(&mut self, p: &PauliString, ch: u32) {
+        let bit = self.tableau.measure_pauli(p, &mut self.rng);
+        let mut flip = false;
+        for q in (0..p.len()).filter(|&q| p.get(q) != Pauli::I) {
+            flip ^= self.happens(self.readout_errors[q]);
+        }
+        self.measured.set_bool(ch as usize, bit ^ flip);
+        self.record.push(bit ^ flip);
+    }
+
+    /// Measures qubit `q` in the Z basis with the outcome `bit`, appending it to the measurement
+    /// record with the readout error of `q`, and returns the probability of `bit`, 0.5 or 1.
+    ///
+    /// Nothing changes if the outcome has probability zero.
+    pub fn measure_forced(&mut self, q: u32, bit: bool) -> Result<f64, ImpossibleOutcome> {
+        let p = self.tableau.measure_forced(q as usize, bit).ok_or(ImpossibleOutcome { qubit: q, bit })?;
+        let flip = self.happens(self.readout_errors[q as usize]);
+        self.record.push(bit ^ flip);
+        Ok(p)
+    }
+
+    #[inline]
+    fn happens(&mut self, p: f64) -> bool {
+        p > 0.0 && self.rng.gen::<f64>() < p
     }
 
     #[inline]
     fn x(&mut self, q: u32) {
-        for (i, _) in self.zs.iter().enumerate()
-                                    .filter(|(_, zs)| zs.get_bool(q as usize)) {
-            self.sgns.negate(i as usize);
-        }
+        self.tableau.x(q as usize);
     }
 
     #[inline]
     fn y(&mut self, q: u32) {
-        for (i, _) in  self.xs.iter().zip(self.zs.iter())
-                           .enumerate()
-                           .filter(|(_, (xs, zs))| (xs.get_masked(q as usize) ^ zs.get_masked(q as usize)) != 0) {
-            self.sgns.negate(i as usize);
-         }
+        self.tableau.y(q as usize);
     }
 
     #[inline]
     fn z(&mut self, q: u32) {
-        for (i, _) in self.xs.iter().enumerate()
-                                    .filter(|(_, xs)| xs.get_bool(q as usize)) {
-            self.sgns.negate(i as usize);
-        }
+        self.tableau.z(q as usize);
     }
 
     #[inline]
     fn h(&mut self, q: u32) {
-        for (i, (xs, zs)) in self.xs.iter_mut().zip(self.zs.iter_mut()).enumerate() {
-            let x = xs.get_bool(q as usize);
-            let z = zs.get_bool(q as usize);
-            if x && z {
-                self.sgns.negate(i);
-            } else if x || z {
-                xs.negate(q as usize);
-                zs.negate(q as usize);
-            }
-         }
+        self.tableau.h(q as usize);
     }
 
     #[inline]
     fn s(&mut self, q: u32) {
-        for (i, (xs, zs)) in self.xs.iter().zip(self.zs.iter_mut())
-                                           .enumerate() {
-            if xs.get_bool(q as usize) {
-                if zs.get_bool(q as usize) {
-                    self.sgns.negate(i as usize);
-                }
-                zs.negate(q as usize);
-            }
-         }
+        self.tableau.s(q as usize);
     }
 
     #[inline]
     fn sdg(&mut self, q: u32) {
-        for (i, (xs, zs)) in self.xs.iter().zip(self.zs.iter_mut())
-                                           .enumerate() {
-            if xs.get_bool(q as usize) {
-                if !zs.get_bool(q as usize) {
-                    self.sgns.negate(i as usize);
-                }
-                zs.negate(q as usize);
-            }
-         }
+        self.tableau.sdg(q as usize);
     }
 
     #[inline]
     fn cx(&mut self, c: u32, t: u32) {
-        for (i, (xs, zs)) in self.xs.iter_mut()
-                                 .zip(self.zs.iter_mut())
-                                 .enumerate() {
-            if xs.get_bool(c as usize) {
-                xs.negate(t as usize);
-                if zs.get_bool(c as usize) {
-                    self.sgns.negate(i as usize);
-                }
-            }
-            if zs.get_bool(t as usize) {
-                zs.negate(c as usize);
-            }
-        }
-    }
-}
-
-fn mult_to<Rng>(gk: &mut GottesmanKnillSimulator<Rng>, dest: usize, src: usize) {
-    assert_ne!(dest, src);
-    let from = unsafe { &*(&gk.xs[src] as *const _) };
-    let into = &mut gk.xs[dest];
-    into.xor_all(&*from);
-    let from = unsafe { &*(&gk.zs[src] as *const _) };
-    let into = &mut gk.zs[dest];
-    into.xor_all(&*from);
-    gk.sgns.set_bool(dest, gk.sgns.get_bool(src));
-}
-
-fn measure<Rng: RngCore>(gk: &mut GottesmanKnillSimulator<Rng>, q: u32) -> bool {
-    let noncommutatives: Vec<_> = gk.xs.iter().map(|a| a.get_bool(q as usize))
-                                              .enumerate()
-                                              .filter(|(_, b)| *b)
-                                              .map(|(i, _)| i)
-                                              .collect();
-    if noncommutatives.is_empty() {
-        //eprintln!("stabilized pattern");
-        let n_qubits = gk.n_qubits() as usize;
-        let mut indices: Vec<_> = (0..n_qubits).collect();
-        for i in 0..n_qubits as usize {
-            let x_inds: Vec<_> = indices.iter().enumerate().filter(|(_, &k)| gk.xs[k].get_bool(i)).map(|(i, _)| i).collect();
-            if !x_inds.is_empty() {
-                let xs0 = unsafe { &*(&gk.xs[indices[x_inds[0]]] as *const _) };
-                let zs0 = unsafe { &*(&gk.zs[indices[x_inds[0]]] as *const _) };
-                let sg0 = gk.sgns.get_bool(indices[x_inds[0]]);
-                for j in x_inds[1..].iter() {
-                    gk.xs[indices[*j]].xor_all(&xs0);
-                    gk.zs[indices[*j]].xor_all(&zs0);
-                    if sg0 {
-                        gk.sgns.negate(indices[*j]);
-                    }
-                }
-                indices.swap_remove(x_inds[0]);
-            }
-        }
-        for i in 0..n_qubits as usize {
-            if i == q as usize { continue }
-            let z_inds: Vec<_> = indices.iter().enumerate().filter(|(_, &k)| gk.zs[k].get_bool(i)).map(|(i, _)| i).collect();
-            if !z_inds.is_empty() {
-                let xs0 = unsafe { &*(&gk.xs[indices[z_inds[0]]] as *const _) };
-                let zs0 = unsafe { &*(&gk.zs[indices[z_inds[0]]] as *const _) };
-                let sg0 = gk.sgns.get_bool(indices[z_inds[0]]);
-                for j in z_inds[1..].iter() {
-                    gk.xs[indices[*j]].xor_all(&xs0);
-                    gk.zs[indices[*j]].xor_all(&zs0);
-                    if sg0 {
-                        gk.sgns.negate(indices[*j]);
-                    }
-                }
-                indices.swap_remove(z_inds[0]);
-            }
-        }
-        assert_eq!(indices.len(), 1);
-        // println!("measured xs: {:?}", gk.xs[indices[0]]);
-        // println!("measured zs: {:?}", gk.zs[indices[0]]);
-        // println!("measured sg: {:?}", gk.sgns.get_bool(indices[0]));
-        gk.sgns.get_bool(indices[0])
-    } else {
-        //eprintln!("non-stabilized pattern");
-        let i = noncommutatives[0];
-        for &j in noncommutatives[1..].iter() {
-            mult_to(gk, j, i);
-        }
-        let is_one = (gk.rng.next_u32() & 1) != 0;
-        gk.xs[noncommutatives[0]].reset();
-        gk.zs[noncommutatives[0]].reset();
-        gk.zs[noncommutatives[0]].negate(q as usize);
-        gk.sgns.set_bool(noncommutatives[0], is_one);
-        is_one
+        self.tableau.cx(c as usize, t as usize);
     }
 }
 
 #[cfg(test)]
 mod tests {
     #![allow(unused_imports)]
-    use crate::{GottesmanKnillSimulator, BitArray, DefaultRng};
+    use crate::{GottesmanKnillSimulator, BitArray, DefaultRng, Layout, Gate1, Gate2, Instruction, Noise1, Noise2, CliffordOps,
+                MeasureOps, ResetOps, FeedForwardOps, Basis, Condition, InstructionError,
+                CliffordError, CliffordTableau, Pauli, PauliString, PauliFrameSimulator, ImpossibleOutcome, Lookback,
+                SqrtXGate, SwapGate, MeasureBasisGate, FeedForwardGate};
+    use lay::operations::{opid, OpArgs};
     use fakerng::RepeatSeqFakeRng;
     use rand_core::{RngCore, SeedableRng};
     use rand_xorshift::XorShiftRng;
@@ -294,7 +619,7 @@ mod tests {
         let mut ops = OpsVec::new();
         let mut result = BitArray::zeros(0);
         f(&mut ops, expect.len() as u32);
-        GottesmanKnillSimulator::from_seed(expect.len() as u32, 0).send_receive(ops.as_ref(), &mut result);
+        GottesmanKnillSimulator::from_seed(expect.len() as u32, 0).send_receive(ops.as_ref(), &mut result).unwrap();
         let actual: Vec<_> = (0..expect.len()).map(|i| result.get_bool(i) as u32).collect();
         assert_eq!(actual.as_slice(), expect);
     }
@@ -306,7 +631,7 @@ mod tests {
         let mut result = BitArray::zeros(0);
         f(&mut ops, expect.len() as u32);
         GottesmanKnillSimulator::from_rng(expect.len() as u32,
-                                          RepeatSeqFakeRng::new(seq)).send_receive(ops.as_ref(), &mut result);
+                                          RepeatSeqFakeRng::new(seq)).send_receive(ops.as_ref(), &mut result).unwrap();
         let actual: Vec<_> = (0..expect.len()).map(|i| result.get_bool(i) as u32).collect();
         assert_eq!(actual.as_slice(), expect);
     }
@@ -471,7 +796,7 @@ mod tests {
             ops.x(i);
             ops.measure(i, i);
         }
-        sim.send(ops.as_ref());
+        sim.send(ops.as_ref()).unwrap();
     }
 
     #[test]
@@ -484,7 +809,7 @@ mod tests {
             ops.x(i);
             ops.measure(i, i);
         }
-        sim.send(ops.as_ref());
+        sim.send(ops.as_ref()).unwrap();
     }
 
     #[test]
@@ -498,7 +823,7 @@ mod tests {
             ops.measure(i, i);
         }
         let mut buf = sim.make_buffer();
-        sim.send_receive(ops.as_ref(), &mut buf);
+        sim.send_receive(ops.as_ref(), &mut buf).unwrap();
         assert!(buf.get(0));
         assert!(buf.get(1));
         assert!(buf.get(2));
@@ -519,7 +844,7 @@ mod tests {
             ops.measure(i, i);
         }
         let mut buf = sim.make_buffer();
-        sim.send_receive(ops.as_ref(), &mut buf);
+        sim.send_receive(ops.as_ref(), &mut buf).unwrap();
         assert!(buf.get(0));
         assert!(buf.get(1));
         assert!(buf.get(2));
@@ -539,7 +864,7 @@ mod tests {
             ops.measure(i, i);
         }
         let mut buf = sim.make_buffer();
-        sim.send_receive(ops.as_ref(), &mut buf);
+        sim.send_receive(ops.as_ref(), &mut buf).unwrap();
         assert!(!buf.get(0));
         assert!(!buf.get(1));
         assert!(!buf.get(2));
@@ -567,7 +892,7 @@ mod tests {
         ops.measure(0, 0);
         ops.measure(0, 1);
         for i in 0..10 {
-            sim.send_receive(ops.as_ref(), &mut buf);
+            sim.send_receive(ops.as_ref(), &mut buf).unwrap();
             let s0 = buf.get(0);
             let s1 = buf.get(1);
             eprintln!("try: {}, |{}{}>", i, s0 as u8, s1 as u8);
@@ -588,7 +913,7 @@ mod tests {
         ops.measure(1, 1);
         ops.measure(2, 2);
         for i in 0..10 {
-            sim.send_receive(ops.as_ref(), &mut buf);
+            sim.send_receive(ops.as_ref(), &mut buf).unwrap();
             let m0 = buf.get(0);
             let m1 = buf.get(1);
             let m2 = buf.get(2);
@@ -597,4 +922,989 @@ mod tests {
             assert_eq!(m0, m2);
         }
     }
+
+    #[test]
+    fn test_ghz_manyqubit() {
+        let n_qubits = 300;
+        let mut sim = GottesmanKnillSimulator::from_seed(n_qubits, 0);
+        let mut ops = sim.opsvec();
+        let mut buf = sim.make_buffer();
+        ops.initialize();
+        ops.h(0);
+        for i in 1..n_qubits {
+            ops.cx(i - 1, i);
+        }
+        for i in (0..n_qubits).rev() {
+            ops.measure(i, i);
+        }
+        for _ in 0..5 {
+            sim.send_receive(ops.as_ref(), &mut buf).unwrap();
+            let m0 = buf.get(0);
+            for i in 1..n_qubits {
+                assert_eq!(buf.get(i), m0);
+            }
+        }
+    }
+
+    #[test]
+    fn test_ghz_column_major() {
+        let n_qubits = 130;
+        let mut sim = GottesmanKnillSimulator::from_seed_with_layout(n_qubits, 0, Layout::ColumnMajor);
+        let mut ops = sim.opsvec();
+        let mut buf = sim.make_buffer();
+        ops.initialize();
+        ops.h(0);
+        for i in 1..n_qubits {
+            ops.cx(i - 1, i);
+        }
+        ops.measure(n_qubits / 2, 0);
+        sim.send(ops.as_ref()).unwrap();
+        // Switching layouts mid-run keeps the collapsed state.
+        sim.set_layout(Layout::RowMajor);
+        ops.clear();
+        for i in 1..n_qubits {
+            ops.measure(i, i);
+        }
+        sim.send_receive(ops.as_ref(), &mut buf).unwrap();
+        for i in 1..n_qubits {
+            assert_eq!(buf.get(i), buf.get(0));
+        }
+    }
+
+    #[test]
+    fn test_noise() {
+        let mut sim = GottesmanKnillSimulator::from_seed(2, 0);
+        let mut buf = sim.make_buffer();
+        sim.run(&[
+            Instruction::Noise1(Noise1::BitFlip(1.0), 0),
+            Instruction::Noise1(Noise1::PhaseFlip(1.0), 1),
+            Instruction::Measure(0, 0),
+            Instruction::Measure(1, 1),
+        ]).unwrap();
+        sim.receive(&mut buf).unwrap();
+        assert!(buf.get(0));
+        assert!(!buf.get(1));
+
+        // Depolarizing noise flips a Z measurement with probability 2p/3.
+        let n_shots = 3000;
+        let mut flipped = 0;
+        for _ in 0..n_shots {
+            sim.run(&[
+                Instruction::Init,
+                Instruction::Gate1(Gate1::H, 0),
+                Instruction::Gate1(Gate1::H, 0),
+                Instruction::Noise1(Noise1::Depolarize(0.3), 0),
+                Instruction::Noise2(Noise2::Depolarize(0.3), 0, 1),
+                Instruction::Measure(0, 0),
+            ]).unwrap();
+            sim.receive(&mut buf).unwrap();
+            flipped += buf.get(0) as u32;
+        }
+        let p1 = 0.2;
+        let p2 = 0.3 * 8.0 / 15.0;
+        let expected = p1 * (1.0 - p2) + p2 * (1.0 - p1);
+        assert!((flipped as f64 / n_shots as f64 - expected).abs() < 0.03, "{}", flipped);
+    }
+
+    #[test]
+    fn test_readout_error() {
+        let mut sim = GottesmanKnillSimulator::from_seed(3, 0);
+        sim.set_readout_error(1.0);
+        sim.set_qubit_readout_error(2, 0.0);
+        let mut ops = sim.opsvec();
+        let mut buf = sim.make_buffer();
+        ops.x(0);
+        ops.measure(0, 0);
+        ops.measure(1, 1);
+        ops.measure(2, 2);
+        // The state is not corrupted: measuring again gives the same flipped record.
+        ops.measure(0, 0);
+        sim.send_receive(ops.as_ref(), &mut buf).unwrap();
+        assert!(!buf.get(0));
+        assert!(buf.get(1));
+        assert!(!buf.get(2));
+
+        sim.set_readout_error(0.0);
+        ops.clear();
+        ops.measure(0, 0);
+        ops.measure(1, 1);
+        sim.send_receive(ops.as_ref(), &mut buf).unwrap();
+        assert!(buf.get(0));
+        assert!(!buf.get(1));
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid error probability")]
+    fn test_invalid_readout_error() {
+        GottesmanKnillSimulator::from_seed(1, 0).set_qubit_readout_error(0, f64::NAN);
+    }
+
+    #[test]
+    fn test_reset_error() {
+        let mut sim = GottesmanKnillSimulator::from_seed(2, 0);
+        sim.set_qubit_reset_error(1, 1.0);
+        let mut ops = sim.opsvec();
+        let mut buf = sim.make_buffer();
+        ops.initialize();
+        ops.measure(0, 0);
+        ops.measure(1, 1);
+        sim.send_receive(ops.as_ref(), &mut buf).unwrap();
+        assert!(!buf.get(0));
+        assert!(buf.get(1));
+
+        sim.set_reset_error(0.5);
+        let mut ones = 0;
+        for _ in 0..1000 {
+            sim.send_receive(ops.as_ref(), &mut buf).unwrap();
+            ones += buf.get(0) as u32;
+        }
+        assert!(ones > 400 && ones < 600, "{}", ones);
+    }
+
+    #[test]
+    fn test_error_seeded() {
+        let run = |seed| {
+            let mut sim = GottesmanKnillSimulator::from_seed(8, seed);
+            sim.set_readout_error(0.3);
+            sim.set_reset_error(0.3);
+            let mut ops = sim.opsvec();
+            let mut buf = sim.make_buffer();
+            ops.initialize();
+            for i in 0..8 {
+                ops.measure(i, i);
+            }
+            sim.send_receive(ops.as_ref(), &mut buf).unwrap();
+            (0..8).map(|i| buf.get(i)).collect::<Vec<_>>()
+        };
+        assert_eq!(run(42), run(42));
+    }
+
+    #[test]
+    fn test_clifford_tableau() {
+        use rand::Rng;
+        let mut rng = XorShiftRng::seed_from_u64(99);
+        for layout in [Layout::RowMajor, Layout::ColumnMajor].iter().cloned() {
+            for _ in 0..20 {
+                // The tableau of a circuit run from |000> lists the images of X_i and Z_i under it.
+                let circuit: Vec<_> = random_circuit(&mut rng, 3, 20).into_iter()
+                                          .filter(|op| !matches!(op, RandOp::Meas(_)))
+                                          .collect();
+                let mut ops: Vec<OpArgs<GottesmanKnillSimulator<DefaultRng>>> = vec![];
+                push_ops(&mut ops, &circuit, &[0, 1, 2]);
+                let mut sim = GottesmanKnillSimulator::from_seed(3, 0);
+                sim.send(&ops).unwrap();
+                let c = CliffordTableau::from_paulis(&sim.destabilizers(), &sim.stabilizers()).unwrap();
+
+                let targets = [4, 1, 3];
+                let mut prep: Vec<OpArgs<GottesmanKnillSimulator<DefaultRng>>> = vec![];
+                push_ops(&mut prep, &random_circuit(&mut rng, 6, 30).into_iter()
+                                        .filter(|op| !matches!(op, RandOp::Meas(_)))
+                                        .collect::<Vec<_>>(), &[0, 1, 2, 3, 4, 5]);
+                let mut expected = GottesmanKnillSimulator::from_seed_with_layout(6, 0, layout);
+                expected.send(&prep).unwrap();
+                let mut actual = GottesmanKnillSimulator::from_seed_with_layout(6, 0, layout);
+                actual.send(&prep).unwrap();
+                let mut ops = vec![];
+                push_ops(&mut ops, &circuit, &targets);
+                expected.send(&ops).unwrap();
+                actual.apply_clifford(&c, &targets).unwrap();
+                assert_eq!(format!("{:?}", actual.tableau), format!("{:?}", expected.tableau));
+                assert_eq!(actual.apply_clifford(&c, &[0, 1]), Err(CliffordError::Targets));
+                assert_eq!(actual.apply_clifford(&c, &[0, 1, 0]), Err(CliffordError::Targets));
+                // Nothing is run when a Clifford has bad targets.
+                let before = format!("{:?}", actual.tableau);
+                let bad = [Instruction::Gate1(Gate1::H, 0), Instruction::Clifford(c.clone(), vec![0, 1, 6])];
+                assert_eq!(actual.run(&bad), Err(InstructionError::Clifford(CliffordError::Targets)));
+                assert_eq!(format!("{:?}", actual.tableau), before);
+                let q = rng.gen_range(0..6);
+                assert_eq!(actual.tableau.measure(q, &mut XorShiftRng::seed_from_u64(1)),
+                           expected.tableau.measure(q, &mut XorShiftRng::seed_from_u64(1)));
+            }
+        }
+    }
+
+    #[test]
+    fn test_expectation() {
+        let p = |s: &str| s.parse::<PauliString>().unwrap();
+        let mut sim = GottesmanKnillSimulator::from_seed(3, 0);
+        let mut ops = sim.opsvec();
+        ops.h(0);
+        ops.cx(0, 1);
+        ops.x(2);
+        sim.send(ops.as_ref()).unwrap();
+        let before = format!("{:?}", sim);
+        assert_eq!(sim.expectation(&p("XXI")), 1.0);
+        assert_eq!(sim.expectation(&p("ZZI")), 1.0);
+        assert_eq!(sim.expectation(&p("YYI")), -1.0);
+        assert_eq!(sim.expectation(&p("-YYZ")), -1.0);
+        assert_eq!(sim.expectation(&p("ZII")), 0.0);
+        assert_eq!(sim.peek_observable(&p("Z2")), Some(true));
+        assert_eq!(sim.peek_observable(&p("I")), Some(false));
+        assert_eq!(format!("{:?}", sim), before);
+    }
+
+    #[test]
+    #[should_panic(expected = "acts on missing qubits")]
+    fn test_expectation_missing_qubit() {
+        GottesmanKnillSimulator::from_seed(1, 0).expectation(&"Z3".parse().unwrap());
+    }
+
+    #[test]
+    fn test_expectation_statevector() {
+        use rand::Rng;
+        let mut rng = XorShiftRng::seed_from_u64(4321);
+        let n_qubits = 4;
+        for &layout in [Layout::RowMajor, Layout::ColumnMajor].iter() {
+            for _ in 0..30 {
+                let circuit: Vec<_> = random_circuit(&mut rng, n_qubits, 30).into_iter()
+                                          .filter(|op| !matches!(op, RandOp::Meas(_)))
+                                          .collect();
+                let mut ops = vec![];
+                push_ops(&mut ops, &circuit, &[0, 1, 2, 3]);
+                let mut sim = GottesmanKnillSimulator::from_seed_with_layout(n_qubits, 0, layout);
+                sim.send(&ops).unwrap();
+                let mut sv = StateVector::new(n_qubits);
+                circuit.iter().for_each(|&op| sv.apply(op));
+                for _ in 0..20 {
+                    let p = random_pauli(&mut rng, n_qubits);
+                    let expected = sv.expectation(&p);
+                    assert!((sim.expectation(&p) - expected).abs() < 1e-9, "<{}> = {}", p, expected);
+                }
+            }
+        }
+    }
+
+    fn random_pauli(rng: &mut XorShiftRng, n_qubits: u32) -> PauliString {
+        use rand::Rng;
+        let paulis: Vec<_> = (0..n_qubits).map(|_| [Pauli::I, Pauli::X, Pauli::Y, Pauli::Z][rng.gen_range(0..4)])
+                                          .collect();
+        let mut p = PauliString::from_paulis(&paulis);
+        if rng.gen() {
+            p.negate();
+        }
+        p
+    }
+
+    #[test]
+    fn test_basis_measurements() {
+        let mut sim = GottesmanKnillSimulator::from_seed(6, 0);
+        let mut ops: Vec<OpArgs<GottesmanKnillSimulator<DefaultRng>>> = vec![
+            OpArgs::Empty(opid::INIT),
+            OpArgs::Q(opid::H, 0),
+            OpArgs::Q(opid::X, 1),
+            OpArgs::Q(opid::H, 1),
+            OpArgs::Q(opid::H, 2),
+            OpArgs::Q(opid::S, 2),
+            OpArgs::Q(opid::X, 3),
+        ];
+        let mut buf = sim.make_buffer();
+        ops.mx(0, 0);
+        ops.mx(1, 1);
+        ops.my(2, 2);
+        ops.mr(3, 3);
+        ops.push(OpArgs::QS(opid::MEAS, 3, 4));
+        ops.mx(0, 5);
+        for _ in 0..10 {
+            sim.send_receive(ops.as_ref(), &mut buf).unwrap();
+            let bits: Vec<_> = (0..6).map(|i| buf.get(i)).collect();
+            assert_eq!(bits, vec![false, true, false, true, false, false]);
+        }
+    }
+
+    #[test]
+    fn test_reset_single_qubit() {
+        let mut sim = GottesmanKnillSimulator::from_seed(4, 0);
+        let mut ops: Vec<OpArgs<GottesmanKnillSimulator<DefaultRng>>> = vec![
+            OpArgs::Empty(opid::INIT),
+            OpArgs::Q(opid::H, 0),
+            OpArgs::QQ(opid::CX, 0, 1),
+            OpArgs::QQ(opid::CX, 0, 2),
+            OpArgs::Q(opid::X, 3),
+            OpArgs::QS(opid::MEAS, 3, 3),
+        ];
+        ops.reset(0);
+        ops.reset(3);
+        ops.push(OpArgs::QS(opid::MEAS, 0, 0));
+        ops.push(OpArgs::QS(opid::MEAS, 1, 1));
+        ops.push(OpArgs::QS(opid::MEAS, 2, 2));
+        let mut buf = sim.make_buffer();
+        let mut ones = 0;
+        for _ in 0..200 {
+            sim.send_receive(&ops, &mut buf).unwrap();
+            assert!(!buf.get(0));
+            // Qubits 1 and 2 stay correlated, and the earlier slot is kept.
+            assert_eq!(buf.get(1), buf.get(2));
+            assert!(buf.get(3));
+            ones += buf.get(1) as u32;
+        }
+        assert!(ones > 70 && ones < 130, "{}", ones);
+
+        // An ancilla reused for repeated parity checks of a Bell pair.
+        let mut ops: Vec<OpArgs<GottesmanKnillSimulator<DefaultRng>>> = vec![
+            OpArgs::Empty(opid::INIT),
+            OpArgs::Q(opid::H, 0),
+            OpArgs::QQ(opid::CX, 0, 1),
+        ];
+        for round in 0..3 {
+            ops.push(OpArgs::QQ(opid::CX, 0, 2));
+            ops.push(OpArgs::QQ(opid::CX, 1, 2));
+            ops.push(OpArgs::QS(opid::MEAS, 2, round));
+            ops.reset(2);
+            ops.rx(3);
+            ops.mx(3, 3);
+        }
+        sim.send_receive(&ops, &mut buf).unwrap();
+        assert!((0..4).all(|i| !buf.get(i)));
+    }
+
+    #[test]
+    fn test_slots() {
+        // 9 data and 8 ancilla qubits, each ancilla checking the parity of two neighbours.
+        let (n_data, n_anc, rounds) = (9, 8, 100);
+        let mut sim = GottesmanKnillSimulator::from_seed_with_slots(n_data + n_anc, n_anc * rounds, 0);
+        assert_eq!(sim.n_qubits(), 17);
+        assert_eq!(sim.n_slots(), 800);
+        let mut ops: Vec<OpArgs<GottesmanKnillSimulator<DefaultRng>>> = vec![
+            OpArgs::Empty(opid::INIT),
+            OpArgs::Q(opid::X, 4),
+        ];
+        for round in 0..rounds {
+            for a in 0..n_anc {
+                ops.push(OpArgs::QQ(opid::CX, a, n_data + a));
+                ops.push(OpArgs::QQ(opid::CX, a + 1, n_data + a));
+                ops.mr(n_data + a, round * n_anc + a);
+            }
+        }
+        let mut buf = sim.make_buffer();
+        sim.send_receive(&ops, &mut buf).unwrap();
+        assert_eq!(buf.len(), 800);
+        for round in 0..rounds {
+            for a in 0..n_anc {
+                assert_eq!(buf.get(round * n_anc + a), a == 3 || a == 4);
+            }
+        }
+    }
+
+    #[test]
+    fn test_measurement_record() {
+        let mut sim = GottesmanKnillSimulator::from_seed(3, 0);
+        let mut ops: Vec<OpArgs<GottesmanKnillSimulator<DefaultRng>>> = vec![
+            OpArgs::Empty(opid::INIT),
+            OpArgs::Q(opid::X, 0),
+            OpArgs::QS(opid::MEAS, 0, 0),
+            OpArgs::QS(opid::MEAS, 1, 0),
+            OpArgs::QS(opid::MEAS, 0, 0),
+        ];
+        ops.mr(0, 1);
+        // rec[-2] and rec[-4] are the outcomes of qubit 0 before the reset.
+        ops.x_if_rec(2, Lookback::new(2));
+        ops.x_if_rec(2, Lookback::new(4));
+        ops.x_if_rec(1, Lookback::new(2));
+        ops.push(OpArgs::QS(opid::MEAS, 1, 2));
+        ops.push(OpArgs::QS(opid::MEAS, 2, 2));
+        sim.send(&ops).unwrap();
+        let record = sim.measurement_record();
+        assert_eq!(record.len(), 6);
+        assert_eq!((0..6).map(|i| record.get_bool(i)).collect::<Vec<_>>(),
+                   vec![true, false, true, true, true, false]);
+        sim.run(&[Instruction::Init]).unwrap();
+        assert_eq!(sim.measurement_record().len(), 0);
+    }
+
+    #[test]
+    fn test_detectors() {
+        let insts = vec![
+            Instruction::Init,
+            Instruction::Gate1(Gate1::H, 0),
+            Instruction::Gate2(Gate2::CX, 0, 1),
+            Instruction::Measure(0, 0),
+            Instruction::Measure(1, 1),
+            Instruction::Gate1(Gate1::X, 0),
+            Instruction::Measure(0, 0),
+            Instruction::Detector(vec![], vec![2, 3]),
+            Instruction::Detector(vec![], vec![1, 3]),
+            Instruction::ObservableInclude(2, vec![2, 3]),
+        ];
+        let reference = PauliFrameSimulator::check_detectors(2, &insts).unwrap();
+        assert_eq!(reference.detectors.true_indices().collect::<Vec<_>>(), vec![1]);
+        let mut sim = GottesmanKnillSimulator::from_seed(2, 0);
+        for _ in 0..10 {
+            sim.run(&insts).unwrap();
+            assert_eq!(sim.detector_parities(), &reference.detectors);
+            assert_eq!(sim.detection_events(&reference), BitArray::zeros(2));
+            assert_eq!(sim.observable_flips(&reference), BitArray::zeros(3));
+        }
+        sim.set_qubit_readout_error(1, 1.0);
+        sim.run(&insts).unwrap();
+        assert_eq!(sim.detection_events(&reference).true_indices().collect::<Vec<_>>(), vec![0]);
+        assert_eq!(sim.observable_flips(&reference).true_indices().collect::<Vec<_>>(), vec![2]);
+        sim.run(&[Instruction::Init]).unwrap();
+        assert_eq!(sim.detector_parities().len(), 0);
+        assert_eq!(sim.observable_parities().len(), 0);
+    }
+
+    #[test]
+    fn test_measurement_record_out_of_range() {
+        let mut sim = GottesmanKnillSimulator::from_seed(2, 0);
+        let x_if_rec = |k| Instruction::Conditional(Condition::Record(vec![k]), Box::new(Instruction::Gate1(Gate1::X, 1)));
+        assert_eq!(sim.run(&[Instruction::Measure(0, 0), x_if_rec(2)]), Err(InstructionError::Lookback(2)));
+        assert_eq!(sim.measurement_record().len(), 0);
+        sim.run(&[Instruction::Measure(0, 0)]).unwrap();
+        sim.run(&[Instruction::Measure(0, 0), x_if_rec(2)]).unwrap();
+        assert_eq!(sim.run(&[Instruction::Init, x_if_rec(1)]), Err(InstructionError::Lookback(1)));
+        assert_eq!(sim.run(&[Instruction::Detector(vec![], vec![0])]), Err(InstructionError::Lookback(0)));
+
+        let ops: Vec<OpArgs<GottesmanKnillSimulator<DefaultRng>>> = vec![OpArgs::QS(crate::opid::X_IF_REC, 1, 0)];
+        assert_eq!(sim.send(&ops), Err(InstructionError::Lookback(0)));
+        let ops: Vec<OpArgs<GottesmanKnillSimulator<DefaultRng>>> = vec![OpArgs::Q(opid::X, 0), OpArgs::Q(0xffff, 0)];
+        assert_eq!(sim.send(&ops), Err(InstructionError::UnknownOp("Q(65535, 0)".to_owned())));
+        assert_eq!(sim.measurement_record().len(), 2);
+    }
+
+    #[test]
+    fn test_feed_forward() {
+        // Teleports |+i> from qubit 0 to qubit 2.
+        let mut sim = GottesmanKnillSimulator::from_seed(5, 0);
+        let mut ops: Vec<OpArgs<GottesmanKnillSimulator<DefaultRng>>> = vec![
+            OpArgs::Empty(opid::INIT),
+            OpArgs::Q(opid::H, 0),
+            OpArgs::Q(opid::S, 0),
+            OpArgs::Q(opid::H, 1),
+            OpArgs::QQ(opid::CX, 1, 2),
+            OpArgs::QQ(opid::CX, 0, 1),
+            OpArgs::Q(opid::H, 0),
+            OpArgs::QS(opid::MEAS, 0, 0),
+            OpArgs::QS(opid::MEAS, 1, 1),
+        ];
+        ops.x_if(2, 1);
+        ops.z_if(2, 0);
+        ops.my(2, 2);
+        // Slot 3 collects the parity of the two Bell measurements and drives an X on qubit 3.
+        ops.xor_slot(3, 0);
+        ops.xor_slot(3, 1);
+        ops.x_if(3, 3);
+        ops.push(OpArgs::QS(opid::MEAS, 3, 4));
+        let mut buf = sim.make_buffer();
+        let mut seen = [false; 4];
+        for _ in 0..100 {
+            sim.send_receive(&ops, &mut buf).unwrap();
+            assert!(!buf.get(2));
+            assert_eq!(buf.get(3), buf.get(0) ^ buf.get(1));
+            assert_eq!(buf.get(4), buf.get(3));
+            seen[buf.get(0) as usize * 2 + buf.get(1) as usize] = true;
+        }
+        assert!(seen.iter().all(|&b| b));
+
+        sim.run(&[
+            Instruction::Init,
+            Instruction::Gate1(Gate1::X, 0),
+            Instruction::Gate1(Gate1::X, 1),
+            Instruction::Measure(0, 0),
+            Instruction::Measure(1, 1),
+            Instruction::Conditional(Condition::Parity(vec![0, 1]), Box::new(Instruction::Gate1(Gate1::X, 2))),
+            Instruction::Conditional(Condition::Parity(vec![0]), Box::new(Instruction::Gate2(Gate2::CX, 0, 3))),
+            Instruction::Measure(2, 2),
+            Instruction::Measure(3, 3),
+        ]).unwrap();
+        sim.receive(&mut buf).unwrap();
+        assert!(!buf.get(2));
+        assert!(buf.get(3));
+    }
+
+    #[test]
+    fn test_resets() {
+        let mut sim = GottesmanKnillSimulator::from_seed(3, 0);
+        let mut buf = sim.make_buffer();
+        for basis in [Basis::X, Basis::Y, Basis::Z].iter().cloned() {
+            for _ in 0..10 {
+                sim.run(&[
+                    Instruction::Init,
+                    Instruction::Gate1(Gate1::H, 0),
+                    Instruction::Gate2(Gate2::CX, 0, 1),
+                    Instruction::Gate1(Gate1::SqrtY, 2),
+                    Instruction::Reset(basis, 0),
+                    Instruction::MeasureIn(basis, 0, 0),
+                    Instruction::MeasureReset(basis, 2, 1),
+                    Instruction::MeasureIn(basis, 2, 2),
+                ]).unwrap();
+                sim.receive(&mut buf).unwrap();
+                assert!(!buf.get(0));
+                assert!(!buf.get(2));
+            }
+        }
+        // Resetting one half of a Bell pair leaves the other half maximally mixed.
+        let mut ones = 0;
+        for _ in 0..400 {
+            sim.run(&[
+                Instruction::Init,
+                Instruction::Gate1(Gate1::H, 0),
+                Instruction::Gate2(Gate2::CX, 0, 1),
+                Instruction::Reset(Basis::Z, 0),
+                Instruction::MeasureIn(Basis::X, 1, 0),
+            ]).unwrap();
+            sim.receive(&mut buf).unwrap();
+            ones += buf.get(0) as u32;
+        }
+        assert!(ones > 150 && ones < 250, "{}", ones);
+    }
+
+    #[test]
+    fn test_mpp() {
+        let p = |s: &str| s.parse::<PauliString>().unwrap();
+        let mut sim = GottesmanKnillSimulator::from_seed(4, 0);
+        let mut buf = sim.make_buffer();
+        for _ in 0..20 {
+            sim.run(&[
+                Instruction::Init,
+                Instruction::Mpp(p("XXXX"), 0),
+                Instruction::Mpp(p("ZZII"), 1),
+                Instruction::Mpp(p("IIZZ"), 2),
+                Instruction::Mpp(p("-XXXX"), 3),
+            ]).unwrap();
+            sim.receive(&mut buf).unwrap();
+            assert!(!buf.get(1));
+            assert!(!buf.get(2));
+            assert_ne!(buf.get(0), buf.get(3));
+            assert_eq!(sim.peek_observable(&p("XXXX")), Some(buf.get(0)));
+        }
+    }
+
+    #[test]
+    fn test_mpp_statevector() {
+        let mut rng = XorShiftRng::seed_from_u64(8765);
+        let n_qubits = 4;
+        for (seed, &layout) in [Layout::RowMajor, Layout::ColumnMajor].iter().cycle().take(40).enumerate() {
+            let circuit: Vec<_> = random_circuit(&mut rng, n_qubits, 20).into_iter()
+                                      .filter(|op| !matches!(op, RandOp::Meas(_)))
+                                      .collect();
+            let mut ops = vec![];
+            push_ops(&mut ops, &circuit, &[0, 1, 2, 3]);
+            let mut sim = GottesmanKnillSimulator::from_seed_with_layout(n_qubits, seed as u64, layout);
+            sim.send(&ops).unwrap();
+            let mut buf = sim.make_buffer();
+            let mut sv = StateVector::new(n_qubits);
+            circuit.iter().for_each(|&op| sv.apply(op));
+            for _ in 0..5 {
+                let p = random_pauli(&mut rng, n_qubits);
+                let peeked = sim.peek_observable(&p);
+                sim.run(&[Instruction::Mpp(p.clone(), 0)]).unwrap();
+                sim.receive(&mut buf).unwrap();
+                let outcome = buf.get(0);
+                if let Some(b) = peeked {
+                    assert_eq!(b, outcome);
+                }
+                let prob = sv.project_pauli(&p, outcome);
+                assert!((prob - 1.0).abs() < 1e-9 || (prob - 0.5).abs() < 1e-9, "{} -> {}: {}", p, outcome, prob);
+            }
+            for _ in 0..10 {
+                let p = random_pauli(&mut rng, n_qubits);
+                assert!((sim.expectation(&p) - sv.expectation(&p)).abs() < 1e-9);
+            }
+        }
+    }
+
+    #[derive(Clone, Copy, Debug)]
+    enum RandOp {
+        X(u32),
+        Y(u32),
+        Z(u32),
+        H(u32),
+        S(u32),
+        Sdg(u32),
+        CX(u32, u32),
+        G1(Gate1, u32),
+        G2(Gate2, u32, u32),
+        Meas(u32),
+    }
+
+    const GATE1S: [Gate1; 9] = [Gate1::I, Gate1::SqrtX, Gate1::SqrtXdg, Gate1::SqrtY, Gate1::SqrtYdg,
+                                Gate1::HXY, Gate1::HYZ, Gate1::CXYZ, Gate1::CZYX];
+    const GATE2S: [Gate2; 5] = [Gate2::CY, Gate2::CZ, Gate2::Swap, Gate2::ISwap, Gate2::ISwapdg];
+
+    fn random_circuit(rng: &mut XorShiftRng, n_qubits: u32, len: usize) -> Vec<RandOp> {
+        use rand::Rng;
+        (0..len).map(|_| {
+            let a = rng.gen_range(0..n_qubits);
+            let b = (a + rng.gen_range(1..n_qubits)) % n_qubits;
+            match rng.gen_range(0..20) {
+                16 | 17 => RandOp::G1(GATE1S[rng.gen_range(0..GATE1S.len())], a),
+                18 | 19 => RandOp::G2(GATE2S[rng.gen_range(0..GATE2S.len())], a, b),
+                0 => RandOp::X(a),
+                1 => RandOp::Y(a),
+                2 => RandOp::Z(a),
+                3..=5 => RandOp::H(a),
+                6..=7 => RandOp::S(a),
+                8 => RandOp::Sdg(a),
+                9..=14 => RandOp::CX(a, b),
+                _ => RandOp::Meas(a),
+            }
+        }).collect()
+    }
+
+    /// Brute-force state vector. Qubit i is the i-th bit of the index.
+    struct StateVector(Vec<(f64, f64)>);
+
+    impl StateVector {
+        fn new(n_qubits: u32) -> Self {
+            let mut amps = vec![(0.0, 0.0); 1 << n_qubits];
+            amps[0] = (1.0, 0.0);
+            StateVector(amps)
+        }
+
+        fn pairs(&self, q: u32) -> impl Iterator<Item=(usize, usize)> {
+            let bit = 1 << q;
+            (0..self.0.len()).filter(move |i| i & bit == 0).map(move |i| (i, i | bit))
+        }
+
+        fn apply(&mut self, op: RandOp) {
+            let r = std::f64::consts::FRAC_1_SQRT_2;
+            match op {
+                RandOp::X(q) => for (a, b) in self.pairs(q).collect::<Vec<_>>() {
+                    self.0.swap(a, b);
+                },
+                RandOp::Y(q) => for (a, b) in self.pairs(q).collect::<Vec<_>>() {
+                    let (ar, ai) = self.0[a];
+                    let (br, bi) = self.0[b];
+                    self.0[a] = (bi, -br);
+                    self.0[b] = (-ai, ar);
+                },
+                RandOp::Z(q) => for (_, b) in self.pairs(q).collect::<Vec<_>>() {
+                    self.0[b] = (-self.0[b].0, -self.0[b].1);
+                },
+                RandOp::H(q) => for (a, b) in self.pairs(q).collect::<Vec<_>>() {
+                    let (ar, ai) = self.0[a];
+                    let (br, bi) = self.0[b];
+                    self.0[a] = ((ar + br) * r, (ai + bi) * r);
+                    self.0[b] = ((ar - br) * r, (ai - bi) * r);
+                },
+                RandOp::S(q) => for (_, b) in self.pairs(q).collect::<Vec<_>>() {
+                    self.0[b] = (-self.0[b].1, self.0[b].0);
+                },
+                RandOp::Sdg(q) => for (_, b) in self.pairs(q).collect::<Vec<_>>() {
+                    self.0[b] = (self.0[b].1, -self.0[b].0);
+                },
+                RandOp::CX(c, t) => for (a, b) in self.pairs(t).collect::<Vec<_>>() {
+                    if a & (1 << c) != 0 {
+                        self.0.swap(a, b);
+                    }
+                },
+                // Up to global phase, in terms of the gates above.
+                RandOp::G1(gate, q) => {
+                    let seq: &[RandOp] = match gate {
+                        Gate1::SqrtX => &[RandOp::H(q), RandOp::S(q), RandOp::H(q)],
+                        Gate1::SqrtXdg => &[RandOp::H(q), RandOp::Sdg(q), RandOp::H(q)],
+                        Gate1::SqrtY => &[RandOp::Z(q), RandOp::H(q)],
+                        Gate1::SqrtYdg => &[RandOp::H(q), RandOp::Z(q)],
+                        Gate1::HXY => &[RandOp::X(q), RandOp::S(q)],
+                        Gate1::HYZ => &[RandOp::H(q), RandOp::S(q), RandOp::H(q), RandOp::Z(q)],
+                        Gate1::CXYZ => &[RandOp::Sdg(q), RandOp::H(q)],
+                        Gate1::CZYX => &[RandOp::H(q), RandOp::S(q)],
+                        _ => &[],
+                    };
+                    seq.iter().for_each(|&op| self.apply(op));
+                },
+                RandOp::G2(Gate2::CY, c, t) => for (a, b) in self.pairs(t).collect::<Vec<_>>() {
+                    if a & (1 << c) != 0 {
+                        let (ar, ai) = self.0[a];
+                        let (br, bi) = self.0[b];
+                        self.0[a] = (bi, -br);
+                        self.0[b] = (-ai, ar);
+                    }
+                },
+                RandOp::G2(Gate2::CZ, c, t) => for (_, b) in self.pairs(t).collect::<Vec<_>>() {
+                    if b & (1 << c) != 0 {
+                        self.0[b] = (-self.0[b].0, -self.0[b].1);
+                    }
+                },
+                RandOp::G2(gate, c, t) => for (a, b) in self.pairs(t).collect::<Vec<_>>() {
+                    // |01> <-> |10> on (c, t), with a factor of i or -i for iSWAP.
+                    if a & (1 << c) != 0 {
+                        let a10 = a;
+                        let a01 = b & !(1 << c);
+                        let (p, q) = (self.0[a10], self.0[a01]);
+                        let (p, q) = match gate {
+                            Gate2::ISwap => ((-q.1, q.0), (-p.1, p.0)),
+                            Gate2::ISwapdg => ((q.1, -q.0), (p.1, -p.0)),
+                            _ => (q, p),
+                        };
+                        self.0[a10] = p;
+                        self.0[a01] = q;
+                    }
+                },
+                RandOp::Meas(_) => panic!("use project() for measurements"),
+            }
+        }
+
+        fn apply_pauli(&mut self, p: &PauliString) {
+            for q in 0..p.len() {
+                match p.get(q) {
+                    Pauli::X => self.apply(RandOp::X(q as u32)),
+                    Pauli::Y => self.apply(RandOp::Y(q as u32)),
+                    Pauli::Z => self.apply(RandOp::Z(q as u32)),
+                    Pauli::I => {},
+                }
+            }
+            if p.is_negative() {
+                self.0.iter_mut().for_each(|a| *a = (-a.0, -a.1));
+            }
+        }
+
+        fn expectation(&self, p: &PauliString) -> f64 {
+            let mut applied = StateVector(self.0.clone());
+            applied.apply_pauli(p);
+            self.0.iter().zip(applied.0.iter()).map(|(a, b)| a.0 * b.0 + a.1 * b.1).sum()
+        }
+
+        /// Projects onto the eigenspace of p with eigenvalue (-1)^outcome and returns its probability.
+        fn project_pauli(&mut self, p: &PauliString, outcome: bool) -> f64 {
+            let mut applied = StateVector(self.0.clone());
+            applied.apply_pauli(p);
+            let s = if outcome { -1.0 } else { 1.0 };
+            for (a, b) in self.0.iter_mut().zip(applied.0.iter()) {
+                *a = ((a.0 + s * b.0) / 2.0, (a.1 + s * b.1) / 2.0);
+            }
+            let prob: f64 = self.0.iter().map(|(re, im)| re * re + im * im).sum();
+            let norm = prob.sqrt();
+            self.0.iter_mut().for_each(|a| *a = (a.0 / norm, a.1 / norm));
+            prob
+        }
+
+        /// Projects qubit q onto the outcome and returns its probability.
+        fn project(&mut self, q: u32, outcome: bool) -> f64 {
+            let bit = 1 << q;
+            let p: f64 = self.0.iter().enumerate()
+                                      .filter(|(i, _)| (i & bit != 0) == outcome)
+                                      .map(|(_, (re, im))| re * re + im * im)
+                                      .sum();
+            let norm = p.sqrt();
+            for (i, amp) in self.0.iter_mut().enumerate() {
+                if (i & bit != 0) == outcome {
+                    *amp = (amp.0 / norm, amp.1 / norm);
+                } else {
+                    *amp = (0.0, 0.0);
+                }
+            }
+            p
+        }
+    }
+
+    /// Pushes the gates of `circuit` with qubit i renamed to `qubits[i]`.
+    fn push_ops(ops: &mut Vec<OpArgs<GottesmanKnillSimulator<DefaultRng>>>, circuit: &[RandOp], qubits: &[u32]) {
+        let m = |q: u32| qubits[q as usize];
+        for &op in circuit {
+            match op {
+                RandOp::X(q) => ops.push(OpArgs::Q(opid::X, m(q))),
+                RandOp::Y(q) => ops.push(OpArgs::Q(opid::Y, m(q))),
+                RandOp::Z(q) => ops.push(OpArgs::Q(opid::Z, m(q))),
+                RandOp::H(q) => ops.push(OpArgs::Q(opid::H, m(q))),
+                RandOp::S(q) => ops.push(OpArgs::Q(opid::S, m(q))),
+                RandOp::Sdg(q) => ops.push(OpArgs::Q(opid::SDG, m(q))),
+                RandOp::CX(c, t) => ops.push(OpArgs::QQ(opid::CX, m(c), m(t))),
+                RandOp::G1(gate, q) => match gate {
+                    Gate1::I => ops.i(m(q)),
+                    Gate1::SqrtX => ops.sx(m(q)),
+                    Gate1::SqrtXdg => ops.sxdg(m(q)),
+                    Gate1::SqrtY => ops.sqrt_y(m(q)),
+                    Gate1::SqrtYdg => ops.sqrt_ydg(m(q)),
+                    Gate1::HXY => ops.h_xy(m(q)),
+                    Gate1::HYZ => ops.h_yz(m(q)),
+                    Gate1::CXYZ => ops.c_xyz(m(q)),
+                    Gate1::CZYX => ops.c_zyx(m(q)),
+                    _ => unreachable!(),
+                },
+                RandOp::G2(gate, a, b) => match gate {
+                    Gate2::CY => ops.cy(m(a), m(b)),
+                    Gate2::CZ => ops.cz(m(a), m(b)),
+                    Gate2::Swap => ops.swap(m(a), m(b)),
+                    Gate2::ISwap => ops.iswap(m(a), m(b)),
+                    Gate2::ISwapdg => ops.iswapdg(m(a), m(b)),
+                    _ => unreachable!(),
+                },
+                RandOp::Meas(_) => unreachable!(),
+            }
+        }
+    }
+
+    fn check_against_statevector(n_qubits: u32, circuit: &[RandOp], seed: u64, layout: Layout) -> Vec<(bool, f64)> {
+        let n_meas = circuit.iter().filter(|op| matches!(op, RandOp::Meas(_))).count() as u32;
+        let n_slots = n_meas + n_qubits;
+        let mut ops: Vec<OpArgs<GottesmanKnillSimulator<DefaultRng>>> = vec![];
+        let mut slot = 0;
+        let mut meas = vec![];
+        let qubits: Vec<_> = (0..n_qubits).collect();
+        for &op in circuit {
+            if let RandOp::Meas(q) = op {
+                meas.push(q);
+                ops.push(OpArgs::QS(opid::MEAS, q, slot));
+                slot += 1;
+            } else {
+                push_ops(&mut ops, &[op], &qubits);
+            }
+        }
+        for q in 0..n_qubits {
+            meas.push(q);
+            ops.push(OpArgs::QS(opid::MEAS, q, slot));
+            slot += 1;
+        }
+        let mut sim = GottesmanKnillSimulator::from_seed_with_slots(n_qubits, n_slots, seed);
+        sim.set_layout(layout);
+        let mut buf = sim.make_buffer();
+        sim.send_receive(&ops, &mut buf).unwrap();
+
+        let mut sv = StateVector::new(n_qubits);
+        let mut results = vec![];
+        let mut slot = 0;
+        for &op in circuit.iter().chain((0..n_qubits).map(RandOp::Meas).collect::<Vec<_>>().iter()) {
+            if let RandOp::Meas(q) = op {
+                assert_eq!(meas[slot as usize], q);
+                let outcome = buf.get(slot);
+                let p = sv.project(q, outcome);
+                results.push((outcome, p));
+                slot += 1;
+            } else {
+                sv.apply(op);
+            }
+        }
+        results
+    }
+
+    #[test]
+    fn test_random_circuits_statevector() {
+        let mut rng = XorShiftRng::seed_from_u64(1234);
+        for n_qubits in 2..=5 {
+            for _ in 0..40 {
+                let circuit = random_circuit(&mut rng, n_qubits, 30);
+                for (seed, &layout) in [Layout::RowMajor, Layout::ColumnMajor].iter().cycle().take(4).enumerate() {
+                    for (outcome, p) in check_against_statevector(n_qubits, &circuit, seed as u64, layout) {
+                        // Stabilizer measurements are either deterministic or uniformly random.
+                        assert!((p - 1.0).abs() < 1e-9 || (p - 0.5).abs() < 1e-9,
+                                "impossible or biased outcome {} (p = {}) in {:?}", outcome, p, circuit);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_random_circuits_statistics() {
+        let mut rng = XorShiftRng::seed_from_u64(5678);
+        let n_qubits = 4;
+        let n_shots = 400;
+        for _ in 0..10 {
+            let circuit: Vec<_> = random_circuit(&mut rng, n_qubits, 25).into_iter()
+                                      .filter(|op| !matches!(op, RandOp::Meas(_)))
+                                      .collect();
+            let mut sv = StateVector::new(n_qubits);
+            circuit.iter().for_each(|&op| sv.apply(op));
+            let mut ones = vec![0; n_qubits as usize];
+            for seed in 0..n_shots {
+                let results = check_against_statevector(n_qubits, &circuit, seed, Layout::RowMajor);
+                for (i, &(outcome, _)) in results.iter().enumerate() {
+                    ones[i] += outcome as u32;
+                }
+            }
+            for q in 0..n_qubits {
+                let p1 = StateVector(sv.0.clone()).project(q, true);
+                let freq = ones[q as usize] as f64 / n_shots as f64;
+                assert!((freq - p1).abs() < 0.1, "qubit {}: P(1) = {}, observed {}", q, p1, freq);
+            }
+        }
+    }
+
+    #[test]
+    fn test_readout_error_on_every_measurement() {
+        let mut sim = GottesmanKnillSimulator::from_seed_with_slots(3, 4, 0);
+        sim.set_qubit_readout_error(0, 1.0);
+        sim.run(&[
+            Instruction::Mpp("ZZI".parse().unwrap(), 0),
+            Instruction::Mpp("IZZ".parse().unwrap(), 1),
+            Instruction::MeasureIn(Basis::Z, 0, 2),
+            Instruction::MeasureReset(Basis::Z, 0, 3),
+        ]).unwrap();
+        assert_eq!((0..4).map(|s| sim.measurement_record().get_bool(s)).collect::<Vec<_>>(), vec![true, false, true, true]);
+        assert_eq!(sim.measure_forced(0, false), Ok(1.0));
+        assert!(sim.measurement_record().get_bool(4));
+    }
+
+    #[test]
+    fn test_sample_shots() {
+        let insts = vec![
+            Instruction::Gate1(Gate1::H, 1),
+            Instruction::Gate2(Gate2::CX, 1, 0),
+            Instruction::Measure(0, 0),
+            Instruction::Measure(1, 1),
+        ];
+        let mut sim = GottesmanKnillSimulator::from_seed(2, 0);
+        let shots = sim.sample_shots(&insts, 200, 7).unwrap();
+        assert!(shots.iter().all(|s| s.get_bool(0) == s.get_bool(1)));
+        let ones = shots.iter().filter(|s| s.get_bool(0)).count();
+        assert!(ones > 70 && ones < 130, "{}", ones);
+        // Every shot is reproducible on its own, whatever the number of shots.
+        let mut other = GottesmanKnillSimulator::from_seed(2, 123);
+        assert_eq!(other.sample_shots(&insts, 50, 7).unwrap(), shots[..50].to_vec());
+        assert_ne!(other.sample_shots(&insts, 50, 8).unwrap(), shots[..50].to_vec());
+        let mut iter = other.iter_shots(&insts, 3, 7).unwrap();
+        assert_eq!(iter.size_hint(), (3, Some(3)));
+        assert_eq!(iter.by_ref().collect::<Vec<_>>(), shots[..3].to_vec());
+        assert_eq!(iter.next(), None);
+    }
+
+    #[test]
+    fn test_display() {
+        let mut sim = GottesmanKnillSimulator::from_seed(2, 0);
+        sim.run(&[Instruction::Gate1(Gate1::H, 0), Instruction::Gate2(Gate2::CX, 0, 1), Instruction::Gate1(Gate1::Z, 0)]).unwrap();
+        assert_eq!(sim.to_string(), "-XX\n+ZZ");
+        assert_eq!(format!("{:#}", sim), "+ZI\n+IX\n---\n-XX\n+ZZ");
+        assert_eq!(sim.to_json(), "{\n  \"n_qubits\": 2,\n  \"stabilizers\": [\"-XX\", \"+ZZ\"],\n  \
+                                   \"destabilizers\": [\"+ZI\", \"+IX\"],\n  \"measured\": [0, 0],\n  \"record\": []\n}");
+    }
+
+    #[test]
+    fn test_measure_forced() {
+        let mut sim = GottesmanKnillSimulator::from_seed(3, 0);
+        sim.run(&[Instruction::Gate1(Gate1::H, 0), Instruction::Gate2(Gate2::CX, 0, 1), Instruction::Gate2(Gate2::CX, 0, 2)]).unwrap();
+        let ghz = sim.snapshot();
+        let before = sim.to_bytes();
+        for &bit in &[false, true] {
+            assert_eq!(sim.measure_forced(1, bit), Ok(0.5));
+            assert_eq!(sim.measure_forced(0, !bit), Err(ImpossibleOutcome { qubit: 0, bit: !bit }));
+            assert_eq!(sim.measure_forced(0, bit), Ok(1.0));
+            assert_eq!(sim.measure_forced(2, bit), Ok(1.0));
+            let record = sim.measurement_record();
+            assert_eq!((0..record.len()).map(|i| record.get_bool(i)).collect::<Vec<_>>(), vec![bit; 3]);
+            sim.restore(&ghz);
+        }
+        assert_eq!(sim.to_bytes(), before);
+    }
+
+    #[test]
+    fn test_gate_traits() {
+        type GK = GottesmanKnillSimulator<DefaultRng>;
+        let ops = vec![
+            GK::sx(0),
+            GK::sx(0),
+            GK::swap(0, 1),
+            GK::mr(1, 0),
+            GK::x_if_rec(0, Lookback::new(1)),
+            OpArgs::QS(opid::MEAS, 0, 1),
+            OpArgs::QS(opid::MEAS, 1, 2),
+        ];
+        let mut sim = GK::from_seed_with_slots(2, 3, 0);
+        let mut buf = sim.make_buffer();
+        sim.send_receive(&ops, &mut buf).unwrap();
+        assert_eq!((0..3).map(|s| buf.get(s)).collect::<Vec<_>>(), vec![true, true, false]);
+
+        // The same through the operations of `sim.opsvec()`.
+        let mut ops = sim.opsvec();
+        ops.initialize();
+        ops.sx(0);
+        ops.sx(0);
+        ops.swap(0, 1);
+        ops.mr(1, 0);
+        ops.x_if_rec(0, Lookback::new(1));
+        ops.measure(0, 1);
+        ops.measure(1, 2);
+        sim.send_receive(ops.as_ref(), &mut buf).unwrap();
+        assert_eq!((0..3).map(|s| buf.get(s)).collect::<Vec<_>>(), vec![true, true, false]);
+    }
 }
@@ -0,0 +1,51 @@
+//! Operation codes of the gates this crate adds on top of `lay::operations::opid`.
+//! They are placed well above the codes used by lay.
+
+use lay::operations::opid::OpCode;
+
+const BASE: OpCode = 0x4000;
+
+pub const I: OpCode = BASE;
+pub const SX: OpCode = BASE + 1;
+pub const SXDG: OpCode = BASE + 2;
+pub const SQRT_Y: OpCode = BASE + 3;
+pub const SQRT_YDG: OpCode = BASE + 4;
+pub const H_XY: OpCode = BASE + 5;
+pub const H_YZ: OpCode = BASE + 6;
+pub const C_XYZ: OpCode = BASE + 7;
+pub const C_ZYX: OpCode = BASE + 8;
+
+pub const CY: OpCode = BASE + 0x100;
+pub const CZ: OpCode = BASE + 0x101;
+pub const SWAP: OpCode = BASE + 0x102;
+pub const ISWAP: OpCode = BASE + 0x103;
+pub const ISWAPDG: OpCode = BASE + 0x104;
+
+pub const MX: OpCode = BASE + 0x200;
+pub const MY: OpCode = BASE + 0x201;
+pub const MR: OpCode = BASE + 0x202;
+pub const MRX: OpCode = BASE + 0x203;
+pub const MRY: OpCode = BASE + 0x204;
+
+pub const RESET: OpCode = BASE + 0x210;
+pub const RX: OpCode = BASE + 0x211;
+pub const RY: OpCode = BASE + 0x212;
+
+pub const X_IF: OpCode = BASE + 0x300;
+pub const Y_IF: OpCode = BASE + 0x301;
+pub const Z_IF: OpCode = BASE + 0x302;
+
+/// XORs one slot into another, encoded as `OpArgs::QQ(XOR_SLOT, dest, src)` as lay has no
+/// operation on two slots. Both arguments are slots, not qubits, so layers and passes which
+/// map qubits must leave them alone.
+/// Build it with `FeedForwardGate::xor_slot`.
+pub const XOR_SLOT: OpCode = BASE + 0x310;
+
+/// Paulis conditioned on the measurement record, encoded as `OpArgs::QS(code, q, k)`.
+/// Unlike the other `QS` operations the last argument is not a slot: it is the
+/// lookback k >= 1 of rec[-k], where rec[-1] is the latest outcome. Layers which
+/// don't keep a record must not read it as a slot index.
+/// Build them with `FeedForwardGate::x_if_rec` and `Lookback`.
+pub const X_IF_REC: OpCode = BASE + 0x320;
+pub const Y_IF_REC: OpCode = BASE + 0x321;
+pub const Z_IF_REC: OpCode = BASE + 0x322;
@@ -0,0 +1,315 @@
+use std::borrow::Cow;
+use std::error::Error;
+use std::fmt::{self, Debug, Display, Formatter};
+use std::ops::{Mul, MulAssign};
+use std::str::FromStr;
+
+use crate::BitArray;
+use crate::clifford::anticommutes;
+use crate::tableau::pauli_phase;
+
+/// Single-qubit Pauli operator.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Pauli {
+    I,
+    X,
+    Y,
+    Z,
+}
+
+impl Pauli {
+    pub fn from_xz(x: bool, z: bool) -> Self {
+        match (x, z) {
+            (false, false) => Pauli::I,
+            (true, false) => Pauli::X,
+            (true, true) => Pauli::Y,
+            (false, true) => Pauli::Z,
+        }
+    }
+
+    pub fn has_x(self) -> bool {
+        self == Pauli::X || self == Pauli::Y
+    }
+
+    pub fn has_z(self) -> bool {
+        self == Pauli::Z || self == Pauli::Y
+    }
+}
+
+/// Error of parsing a `PauliString`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ParsePauliError {
+    /// A character which is neither a sign nor a Pauli.
+    InvalidChar(char),
+    /// A factor of a sparse string such as "X3" without a valid qubit index.
+    InvalidIndex(String),
+    /// A qubit named twice in a sparse string.
+    DuplicateQubit(usize),
+}
+
+impl Display for ParsePauliError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            ParsePauliError::InvalidChar(c) => write!(f, "unexpected character {:?} in Pauli string", c),
+            ParsePauliError::InvalidIndex(s) => write!(f, "invalid Pauli factor {:?}", s),
+            ParsePauliError::DuplicateQubit(q) => write!(f, "qubit {} appears twice in Pauli string", q),
+        }
+    }
+}
+
+impl Error for ParsePauliError {}
+
+/// Pauli string i^phase * P_0 P_1 ... P_{n-1}.
+///
+/// A qubit with both x and z set is Y, as in the simulator's tableau, so "+Y" has phase 0.
+/// Strings of different lengths are padded with identities when combined.
+#[derive(Clone, PartialEq, Eq)]
+pub struct PauliString {
+    xs: BitArray,
+    zs: BitArray,
+    phase: u8,
+}
+
+impl PauliString {
+    pub fn identity(n: usize) -> Self {
+        Self { xs: BitArray::zeros(n), zs: BitArray::zeros(n), phase: 0 }
+    }
+
+    pub fn from_paulis(paulis: &[Pauli]) -> Self {
+        let mut p = Self::identity(paulis.len());
+        paulis.iter().enumerate().for_each(|(q, &pauli)| p.set(q, pauli));
+        p
+    }
+
+    /// Builds a Hermitian string from the bits of a tableau row.
+    pub fn from_xz(xs: BitArray, zs: BitArray, negated: bool) -> Self {
+        assert_eq!(xs.len(), zs.len());
+        Self { xs, zs, phase: 2 * negated as u8 }
+    }
+
+    pub fn len(&self) -> usize {
+        self.xs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn xs(&self) -> &BitArray {
+        &self.xs
+    }
+
+    pub fn zs(&self) -> &BitArray {
+        &self.zs
+    }
+
+    /// The power of i in front of the string, 0 to 3.
+    pub fn phase(&self) -> u8 {
+        self.phase
+    }
+
+    pub fn is_hermitian(&self) -> bool {
+        self.phase & 1 == 0
+    }
+
+    /// Whether the phase is -1 or -i.
+    pub fn is_negative(&self) -> bool {
+        self.phase & 2 != 0
+    }
+
+    pub fn negate(&mut self) {
+        self.phase ^= 2;
+    }
+
+    pub fn get(&self, q: usize) -> Pauli {
+        Pauli::from_xz(self.xs.get_bool(q), self.zs.get_bool(q))
+    }
+
+    pub fn set(&mut self, q: usize, pauli: Pauli) {
+        self.xs.set_bool(q, pauli.has_x());
+        self.zs.set_bool(q, pauli.has_z());
+    }
+
+    /// Number of qubits with a non-identity Pauli.
+    pub fn weight(&self) -> usize {
+        self.xs.blocks().iter()
+                        .zip(self.zs.blocks().iter())
+                        .map(|(x, z)| (x | z).count_ones() as usize)
+                        .sum()
+    }
+
+    /// Pads with identities or truncates to `n` qubits.
+    pub fn resize(&mut self, n: usize) {
+        let mut xs = BitArray::zeros(n);
+        let mut zs = BitArray::zeros(n);
+        self.xs.true_indices().filter(|&q| q < n).for_each(|q| xs.negate(q));
+        self.zs.true_indices().filter(|&q| q < n).for_each(|q| zs.negate(q));
+        self.xs = xs;
+        self.zs = zs;
+    }
+
+    fn padded(&self, n: usize) -> Cow<'_, Self> {
+        if self.len() >= n {
+            Cow::Borrowed(self)
+        } else {
+            let mut p = self.clone();
+            p.resize(n);
+            Cow::Owned(p)
+        }
+    }
+
+    pub fn commutes(&self, other: &Self) -> bool {
+        let n = self.len().max(other.len());
+        let (a, b) = (self.padded(n), other.padded(n));
+        !anticommutes(&a.xs, &a.zs, &b.xs, &b.zs)
+    }
+
+    /// Formats the non-identity factors as "-X3*Z7".
+    pub fn to_sparse_string(&self) -> String {
+        let factors: Vec<_> = (0..self.len()).filter(|&q| self.get(q) != Pauli::I)
+                                             .map(|q| format!("{:?}{}", self.get(q), q))
+                                             .collect();
+        let factors = if factors.is_empty() { "I".to_owned() } else { factors.join("*") };
+        format!("{}{}", PHASES[self.phase as usize], factors)
+    }
+}
+
+const PHASES: [&str; 4] = ["+", "+i", "-", "-i"];
+
+impl MulAssign<&PauliString> for PauliString {
+    fn mul_assign(&mut self, rhs: &PauliString) {
+        if self.len() < rhs.len() {
+            self.resize(rhs.len());
+        }
+        let rhs = rhs.padded(self.len());
+        let phase = pauli_phase(&self.xs, &self.zs, &rhs.xs, &rhs.zs);
+        self.phase = ((self.phase as u32 + rhs.phase as u32 + phase) & 3) as u8;
+        self.xs.xor_all(&rhs.xs);
+        self.zs.xor_all(&rhs.zs);
+    }
+}
+
+impl Mul for &PauliString {
+    type Output = PauliString;
+
+    fn mul(self, rhs: &PauliString) -> PauliString {
+        let mut p = self.clone();
+        p *= rhs;
+        p
+    }
+}
+
+impl Display for PauliString {
+    /// Formats as "+XIZY".
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.write_str(PHASES[self.phase as usize])?;
+        for q in 0..self.len() {
+            write!(f, "{:?}", self.get(q))?;
+        }
+        Ok(())
+    }
+}
+
+impl Debug for PauliString {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "PauliString(\"{}\")", self)
+    }
+}
+
+impl FromStr for PauliString {
+    type Err = ParsePauliError;
+
+    /// Parses the dense form "+XIZY" ('_' is also I) or the sparse form "-X3*Z7".
+    /// The sign may be "+", "-", "+i", "-i", "i" or omitted.
+    fn from_str(s: &str) -> Result<Self, ParsePauliError> {
+        let s = s.trim();
+        let (mut phase, s) = match s.strip_prefix('-') {
+            Some(rest) => (2, rest),
+            None => (0, s.strip_prefix('+').unwrap_or(s)),
+        };
+        let s = match s.strip_prefix('i') {
+            Some(rest) => {
+                phase += 1;
+                rest
+            },
+            None => s,
+        };
+        let pauli = |c| match c {
+            'I' | '_' => Ok(Pauli::I),
+            'X' => Ok(Pauli::X),
+            'Y' => Ok(Pauli::Y),
+            'Z' => Ok(Pauli::Z),
+            c => Err(ParsePauliError::InvalidChar(c)),
+        };
+        let mut p = if s.chars().any(|c| c.is_ascii_digit()) {
+            let mut factors = vec![];
+            for factor in s.split('*') {
+                let factor = factor.trim();
+                let mut chars = factor.chars();
+                let p = pauli(chars.next().ok_or_else(|| ParsePauliError::InvalidIndex(factor.to_owned()))?)?;
+                let q: usize = chars.as_str().parse().map_err(|_| ParsePauliError::InvalidIndex(factor.to_owned()))?;
+                factors.push((q, p));
+            }
+            let n = factors.iter().map(|&(q, _)| q + 1).max().unwrap_or(0);
+            let mut p = Self::identity(n);
+            let mut seen = BitArray::zeros(n);
+            for (q, pauli) in factors {
+                if seen.get_bool(q) {
+                    return Err(ParsePauliError::DuplicateQubit(q));
+                }
+                seen.negate(q);
+                p.set(q, pauli);
+            }
+            p
+        } else {
+            Self::from_paulis(&s.chars().map(pauli).collect::<Result<Vec<_>, _>>()?)
+        };
+        p.phase = phase;
+        Ok(p)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Pauli, PauliString, ParsePauliError};
+
+    fn p(s: &str) -> PauliString {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parse_and_format() {
+        assert_eq!(p("+XIZY").to_string(), "+XIZY");
+        assert_eq!(p("XIZY"), p("+XIZY"));
+        assert_eq!(p("-iX_Z").to_string(), "-iXIZ");
+        assert_eq!(p("-X3*Z7").to_string(), "-IIIXIIIZ");
+        assert_eq!(p("-X3*Z7").to_sparse_string(), "-X3*Z7");
+        assert_eq!(p("+III").to_sparse_string(), "+I");
+        assert_eq!(p("Y0").get(0), Pauli::Y);
+        assert_eq!("XA".parse::<PauliString>(), Err(ParsePauliError::InvalidChar('A')));
+        assert_eq!("X1*Zq".parse::<PauliString>(), Err(ParsePauliError::InvalidIndex("Zq".to_owned())));
+        assert_eq!("X1*Z1".parse::<PauliString>(), Err(ParsePauliError::DuplicateQubit(1)));
+    }
+
+    #[test]
+    fn multiply() {
+        assert_eq!(&p("X") * &p("Z"), p("-iY"));
+        assert_eq!(&p("Z") * &p("X"), p("iY"));
+        assert_eq!(&p("Y") * &p("Y"), p("I"));
+        assert_eq!(&p("XX") * &p("ZZ"), p("-YY"));
+        assert_eq!(&p("-X0") * &p("Z2"), p("-XIZ"));
+        let mut a = p("iXYZ");
+        a *= &p("iXYZ");
+        assert_eq!(a, p("-III"));
+    }
+
+    #[test]
+    fn commutation_and_weight() {
+        assert!(p("XX").commutes(&p("ZZ")));
+        assert!(!p("XI").commutes(&p("ZZ")));
+        assert!(!p("Y5").commutes(&p("X5")));
+        assert!(p("Y5").commutes(&p("X4")));
+        assert_eq!(p("XIZY").weight(), 3);
+        assert_eq!(p("-X3*Z70").weight(), 2);
+    }
+}
@@ -0,0 +1,494 @@
+use std::error::Error;
+use std::f64::consts::PI;
+use std::fmt::{self, Display, Formatter};
+use std::str::FromStr;
+
+use crate::{Basis, Condition, Gate1, Gate2, Instruction};
+
+/// Errors of reading an OpenQASM 2.0 circuit.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum QasmError {
+    /// A statement which is not valid OpenQASM 2.0, or names an unknown gate or register.
+    Syntax { line: usize, message: String },
+    /// A gate which is not a Clifford, such as t or rz with an angle not a multiple of pi/2.
+    NonClifford { line: usize, gate: String },
+    /// Valid OpenQASM which this crate does not read, such as gate definitions.
+    Unsupported { line: usize, message: String },
+}
+
+impl Display for QasmError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            QasmError::Syntax { line, message } => write!(f, "line {}: {}", line, message),
+            QasmError::NonClifford { line, gate } => write!(f, "line {}: {} is not a Clifford gate", line, gate),
+            QasmError::Unsupported { line, message } => write!(f, "line {}: {}", line, message),
+        }
+    }
+}
+
+impl Error for QasmError {}
+
+/// OpenQASM 2.0 circuit of Clifford gates, as instructions for the simulators of this crate.
+///
+/// Quantum registers are laid out on the qubits, and classical registers on the slots,
+/// in the order they are declared. Rotations by multiples of pi/2 are read as Cliffords,
+/// up to a global phase.
+#[derive(Clone, Debug, PartialEq)]
+pub struct QasmCircuit {
+    pub n_qubits: u32,
+    pub n_slots: u32,
+    pub instructions: Vec<Instruction>,
+    /// Names and sizes of the quantum registers.
+    pub qregs: Vec<(String, u32)>,
+    /// Names and sizes of the classical registers.
+    pub cregs: Vec<(String, u32)>,
+}
+
+fn syntax(line: usize, message: String) -> QasmError {
+    QasmError::Syntax { line, message }
+}
+
+/// Splits the source into statements ending with ';', each with the line it starts on.
+fn statements(s: &str) -> Vec<(usize, String)> {
+    let mut stmts = vec![];
+    let mut current = String::new();
+    let mut start = 0;
+    for (i, line) in s.lines().enumerate() {
+        let line = line.split("//").next().unwrap();
+        for c in line.chars() {
+            if current.trim().is_empty() {
+                start = i + 1;
+            }
+            if c == ';' {
+                stmts.push((start, current.trim().to_owned()));
+                current.clear();
+            } else {
+                current.push(c);
+            }
+        }
+        current.push(' ');
+    }
+    if !current.trim().is_empty() {
+        stmts.push((start, current.trim().to_owned()));
+    }
+    stmts
+}
+
+/// Evaluates an angle such as "-3*pi/2".
+fn angle(line: usize, s: &str) -> Result<f64, QasmError> {
+    struct Parser<'a> {
+        s: &'a [u8],
+        pos: usize,
+    }
+    impl Parser<'_> {
+        fn peek(&mut self) -> Option<u8> {
+            while self.s.get(self.pos) == Some(&b' ') {
+                self.pos += 1;
+            }
+            self.s.get(self.pos).cloned()
+        }
+        fn expr(&mut self) -> Option<f64> {
+            let mut v = self.term()?;
+            while let Some(op @ (b'+' | b'-')) = self.peek() {
+                self.pos += 1;
+                let t = self.term()?;
+                v = if op == b'+' { v + t } else { v - t };
+            }
+            Some(v)
+        }
+        fn term(&mut self) -> Option<f64> {
+            let mut v = self.factor()?;
+            while let Some(op @ (b'*' | b'/')) = self.peek() {
+                self.pos += 1;
+                let f = self.factor()?;
+                v = if op == b'*' { v * f } else { v / f };
+            }
+            Some(v)
+        }
+        fn factor(&mut self) -> Option<f64> {
+            match self.peek()? {
+                b'-' => {
+                    self.pos += 1;
+                    Some(-self.factor()?)
+                },
+                b'(' => {
+                    self.pos += 1;
+                    let v = self.expr()?;
+                    if self.peek()? != b')' {
+                        return None;
+                    }
+                    self.pos += 1;
+                    Some(v)
+                },
+                _ if self.s[self.pos..].starts_with(b"pi") => {
+                    self.pos += 2;
+                    Some(PI)
+                },
+                _ => {
+                    let rest = &self.s[self.pos..];
+                    let mut len = rest.iter().take_while(|c| c.is_ascii_digit() || **c == b'.').count();
+                    // An exponent, which may be signed as in 1e-3.
+                    if len > 0 && matches!(rest.get(len), Some(b'e' | b'E')) {
+                        let sign = matches!(rest.get(len + 1), Some(b'+' | b'-')) as usize;
+                        len += 1 + sign + rest[len + 1 + sign..].iter().take_while(|c| c.is_ascii_digit()).count();
+                    }
+                    let v = std::str::from_utf8(&rest[..len]).ok()?.parse().ok()?;
+                    self.pos += len;
+                    Some(v)
+                },
+            }
+        }
+    }
+    let mut p = Parser { s: s.as_bytes(), pos: 0 };
+    match p.expr() {
+        Some(v) if p.peek().is_none() && v.is_finite() => Ok(v),
+        _ => Err(syntax(line, format!("invalid angle {:?}", s))),
+    }
+}
+
+/// Single-qubit rotation axes of the Clifford rotations by multiples of pi/2.
+#[derive(Clone, Copy)]
+enum Axis {
+    X,
+    Y,
+    Z,
+}
+
+impl Axis {
+    /// Gate of a rotation by k quarter turns, up to a global phase.
+    fn quarter_turns(self, k: u8) -> Gate1 {
+        let gates = match self {
+            Axis::X => [Gate1::I, Gate1::SqrtX, Gate1::X, Gate1::SqrtXdg],
+            Axis::Y => [Gate1::I, Gate1::SqrtY, Gate1::Y, Gate1::SqrtYdg],
+            Axis::Z => [Gate1::I, Gate1::S, Gate1::Z, Gate1::Sdg],
+        };
+        gates[k as usize & 3]
+    }
+}
+
+/// Parses statements into instructions, with the registers declared so far.
+struct Reader {
+    instructions: Vec<Instruction>,
+    qregs: Vec<(String, u32)>,
+    cregs: Vec<(String, u32)>,
+}
+
+fn register(regs: &[(String, u32)], name: &str) -> Option<(u32, u32)> {
+    let mut start = 0;
+    for (n, size) in regs {
+        if n == name {
+            return Some((start, *size));
+        }
+        start += size;
+    }
+    None
+}
+
+impl Reader {
+    /// Resolves "q[3]" to one index and "q" to every index of the register.
+    fn resolve(&self, line: usize, arg: &str, quantum: bool) -> Result<Vec<u32>, QasmError> {
+        let regs = if quantum { &self.qregs } else { &self.cregs };
+        let arg = arg.trim();
+        let (name, index) = match arg.find('[') {
+            Some(i) => {
+                let index = arg[i + 1..].strip_suffix(']')
+                                        .and_then(|k| k.trim().parse::<u32>().ok())
+                                        .ok_or_else(|| syntax(line, format!("invalid index in {:?}", arg)))?;
+                (arg[..i].trim(), Some(index))
+            },
+            None => (arg, None),
+        };
+        let (start, size) = register(regs, name).ok_or_else(|| {
+            syntax(line, format!("unknown {} register {:?}", if quantum { "quantum" } else { "classical" }, name))
+        })?;
+        match index {
+            Some(k) if k >= size => Err(syntax(line, format!("{} is out of the register of size {}", arg, size))),
+            Some(k) => Ok(vec![start + k]),
+            None => Ok((start..start + size).collect()),
+        }
+    }
+
+    /// Broadcasts over registers: single qubits are repeated, registers must have the same size.
+    fn broadcast(&self, line: usize, args: &[&str]) -> Result<Vec<Vec<u32>>, QasmError> {
+        let args = args.iter().map(|a| self.resolve(line, a, true)).collect::<Result<Vec<_>, _>>()?;
+        let n = args.iter().map(|a| a.len()).max().unwrap_or(0);
+        if args.iter().any(|a| a.len() != 1 && a.len() != n) {
+            return Err(syntax(line, "registers of different sizes".to_owned()));
+        }
+        Ok((0..n).map(|i| args.iter().map(|a| if a.len() == 1 { a[0] } else { a[i] }).collect()).collect())
+    }
+
+    /// Reads one statement, pushing its instructions.
+    fn statement(&mut self, line: usize, stmt: &str) -> Result<(), QasmError> {
+        let (word, rest) = match stmt.find(|c: char| c.is_whitespace() || c == '(') {
+            Some(i) => (&stmt[..i], stmt[i..].trim()),
+            None => (stmt, ""),
+        };
+        match word {
+            "OPENQASM" => {
+                if rest != "2.0" {
+                    return Err(QasmError::Unsupported { line, message: format!("OpenQASM version {}", rest) });
+                }
+            },
+            "include" => {
+                if rest != "\"qelib1.inc\"" {
+                    return Err(QasmError::Unsupported { line, message: format!("include of {}", rest) });
+                }
+            },
+            "qreg" | "creg" => {
+                let decl = rest.strip_suffix(']').and_then(|r| {
+                    let i = r.find('[')?;
+                    Some((r[..i].trim().to_owned(), r[i + 1..].trim().parse::<u32>().ok()?))
+                }).ok_or_else(|| syntax(line, format!("invalid declaration {:?}", stmt)))?;
+                if register(&self.qregs, &decl.0).is_some() || register(&self.cregs, &decl.0).is_some() {
+                    return Err(syntax(line, format!("register {:?} is declared twice", decl.0)));
+                }
+                let regs = if word == "qreg" { &mut self.qregs } else { &mut self.cregs };
+                regs.push(decl);
+            },
+            "barrier" => {},
+            "gate" | "opaque" => {
+                return Err(QasmError::Unsupported { line, message: format!("{} definitions are not supported", word) });
+            },
+            "if" => {
+                let inner = rest.strip_prefix('(').ok_or_else(|| syntax(line, "expected '(' after if".to_owned()))?;
+                let close = inner.find(')').ok_or_else(|| syntax(line, "missing ')'".to_owned()))?;
+                let (creg, value) = inner[..close].split_once("==")
+                                                  .ok_or_else(|| syntax(line, "expected if(creg==n)".to_owned()))?;
+                let (start, size) = register(&self.cregs, creg.trim())
+                    .ok_or_else(|| syntax(line, format!("unknown classical register {:?}", creg.trim())))?;
+                let value = value.trim().parse().map_err(|_| syntax(line, format!("invalid value {:?}", value.trim())))?;
+                let slots: Vec<_> = (start..start + size).collect();
+                let first = self.instructions.len();
+                self.statement(line, inner[close + 1..].trim())?;
+                for inst in self.instructions[first..].iter_mut() {
+                    let body = std::mem::replace(inst, Instruction::Init);
+                    *inst = Instruction::Conditional(Condition::Equals(slots.clone(), value), Box::new(body));
+                }
+            },
+            "measure" => {
+                let (q, c) = rest.split_once("->").ok_or_else(|| syntax(line, "expected measure q -> c".to_owned()))?;
+                let qubits = self.resolve(line, q, true)?;
+                let slots = self.resolve(line, c, false)?;
+                if qubits.len() != slots.len() {
+                    return Err(syntax(line, "registers of different sizes".to_owned()));
+                }
+                for (q, s) in qubits.into_iter().zip(slots) {
+                    self.instructions.push(Instruction::Measure(q, s));
+                }
+            },
+            "reset" => {
+                for q in self.resolve(line, rest, true)? {
+                    self.instructions.push(Instruction::Reset(Basis::Z, q));
+                }
+            },
+            _ => self.gate(line, word, rest)?,
+        }
+        Ok(())
+    }
+
+    fn gate(&mut self, line: usize, name: &str, rest: &str) -> Result<(), QasmError> {
+        let (params, rest) = match rest.strip_prefix('(') {
+            Some(r) => {
+                let close = r.rfind(')').ok_or_else(|| syntax(line, "missing ')'".to_owned()))?;
+                let params = r[..close].split(',').map(|p| angle(line, p)).collect::<Result<Vec<_>, _>>()?;
+                (params, r[close + 1..].trim())
+            },
+            None => (vec![], rest),
+        };
+        let args: Vec<_> = rest.split(',').collect();
+        let arity = |n_params: usize, n_args: usize| if params.len() == n_params && args.len() == n_args {
+            Ok(())
+        } else {
+            Err(syntax(line, format!("{} takes {} parameters and {} qubits", name, n_params, n_args)))
+        };
+        let non_clifford = || QasmError::NonClifford { line, gate: name.to_owned() };
+        // Rotations by multiples of pi/2.
+        let quarter_turns = |theta: f64| {
+            let k = theta / (PI / 2.0);
+            if (k - k.round()).abs() > 1e-9 {
+                Err(non_clifford())
+            } else {
+                Ok(k.round().rem_euclid(4.0) as u8)
+            }
+        };
+
+        let gate1 = match name {
+            "id" => Some(vec![Gate1::I]),
+            "x" => Some(vec![Gate1::X]),
+            "y" => Some(vec![Gate1::Y]),
+            "z" => Some(vec![Gate1::Z]),
+            "h" => Some(vec![Gate1::H]),
+            "s" => Some(vec![Gate1::S]),
+            "sdg" => Some(vec![Gate1::Sdg]),
+            "sx" => Some(vec![Gate1::SqrtX]),
+            "sxdg" => Some(vec![Gate1::SqrtXdg]),
+            "rx" | "ry" | "rz" | "u1" | "p" => {
+                let axis = match name {
+                    "rx" => Axis::X,
+                    "ry" => Axis::Y,
+                    _ => Axis::Z,
+                };
+                arity(1, 1)?;
+                Some(vec![axis.quarter_turns(quarter_turns(params[0])?)])
+            },
+            // u3(theta, phi, lambda) = rz(phi) ry(theta) rz(lambda), applied right to left.
+            "u3" | "U" | "u" | "u2" => {
+                let (theta, phi, lambda) = if name == "u2" {
+                    arity(2, 1)?;
+                    (PI / 2.0, params[0], params[1])
+                } else {
+                    arity(3, 1)?;
+                    (params[0], params[1], params[2])
+                };
+                Some(vec![Axis::Z.quarter_turns(quarter_turns(lambda)?),
+                          Axis::Y.quarter_turns(quarter_turns(theta)?),
+                          Axis::Z.quarter_turns(quarter_turns(phi)?)])
+            },
+            _ => None,
+        };
+        if let Some(gates) = gate1 {
+            if params.is_empty() {
+                arity(0, 1)?;
+            }
+            for qs in self.broadcast(line, &args)? {
+                for &gate in gates.iter().filter(|&&g| g != Gate1::I || gates.len() == 1) {
+                    self.instructions.push(Instruction::Gate1(gate, qs[0]));
+                }
+            }
+            return Ok(());
+        }
+        let gate2 = match name {
+            "cx" | "CX" => Gate2::CX,
+            "cy" => Gate2::CY,
+            "cz" => Gate2::CZ,
+            "swap" => Gate2::Swap,
+            "t" | "tdg" | "ccx" | "cswap" | "ch" | "crx" | "cry" | "crz" | "cu1" | "cp" | "cu3" | "csx" | "rxx" | "rzz"
+            | "rccx" | "rc3x" | "c3x" | "c3sqrtx" | "c4x" => return Err(non_clifford()),
+            _ => return Err(syntax(line, format!("unknown gate {:?}", name))),
+        };
+        arity(0, 2)?;
+        for qs in self.broadcast(line, &args)? {
+            if qs[0] == qs[1] {
+                return Err(syntax(line, format!("{} on qubit {} twice", name, qs[0])));
+            }
+            self.instructions.push(Instruction::Gate2(gate2, qs[0], qs[1]));
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for QasmCircuit {
+    type Err = QasmError;
+
+    fn from_str(s: &str) -> Result<Self, QasmError> {
+        let mut reader = Reader { instructions: vec![], qregs: vec![], cregs: vec![] };
+        for (line, stmt) in statements(s) {
+            reader.statement(line, &stmt)?;
+        }
+        Ok(QasmCircuit {
+            n_qubits: reader.qregs.iter().map(|(_, n)| n).sum(),
+            n_slots: reader.cregs.iter().map(|(_, n)| n).sum(),
+            instructions: reader.instructions,
+            qregs: reader.qregs,
+            cregs: reader.cregs,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{BitArray, Condition, Gate1, Gate2, GottesmanKnillSimulator, Instruction, QasmCircuit, QasmError};
+    use lay::Layer;
+
+    #[test]
+    fn parse() {
+        let c: QasmCircuit = r#"
+            OPENQASM 2.0;
+            include "qelib1.inc";
+            qreg q[3]; qreg anc[1];
+            creg c[2];
+            creg d[1];
+            h q;
+            cx q[0], anc;  // comment
+            rz(-pi/2) q[1];
+            u3(pi, 0, pi/2) q[2];
+            barrier q;
+            measure anc[0] -> c[0];
+        "#.parse().unwrap();
+        assert_eq!(c.n_qubits, 4);
+        assert_eq!(c.n_slots, 3);
+        assert_eq!(c.cregs, vec![("c".to_owned(), 2), ("d".to_owned(), 1)]);
+        assert_eq!(c.instructions, vec![
+            Instruction::Gate1(Gate1::H, 0),
+            Instruction::Gate1(Gate1::H, 1),
+            Instruction::Gate1(Gate1::H, 2),
+            Instruction::Gate2(Gate2::CX, 0, 3),
+            Instruction::Gate1(Gate1::Sdg, 1),
+            Instruction::Gate1(Gate1::S, 2),
+            Instruction::Gate1(Gate1::Y, 2),
+            Instruction::Measure(3, 0),
+        ]);
+    }
+
+    #[test]
+    fn conditions() {
+        let c: QasmCircuit = "
+            qreg q[3];
+            creg c[2];
+            creg r[1];
+            x q[1];
+            measure q[0] -> c[0];
+            measure q[1] -> c[1];
+            if(c==2) x q[2];
+            if (c == 1) x q[2];
+            measure q[2] -> r[0];
+            reset q;
+            measure q[1] -> c[1];
+        ".parse().unwrap();
+        assert_eq!(c.instructions[3],
+                   Instruction::Conditional(Condition::Equals(vec![0, 1], 2), Box::new(Instruction::Gate1(Gate1::X, 2))));
+        let mut sim = GottesmanKnillSimulator::from_seed_with_slots(c.n_qubits, c.n_slots, 0);
+        let mut buf = BitArray::zeros(3);
+        sim.run(&c.instructions).unwrap();
+        sim.receive(&mut buf).unwrap();
+        assert_eq!(buf.true_indices().collect::<Vec<_>>(), vec![2]);
+    }
+
+    #[test]
+    fn errors() {
+        let err = |s: &str| s.parse::<QasmCircuit>().unwrap_err();
+        assert_eq!(err("qreg q[1];\n\nt q[0];"), QasmError::NonClifford { line: 3, gate: "t".to_owned() });
+        assert_eq!(err("qreg q[1];\nrz(pi/2) q[0];\nrz(0.3) q[0];"), QasmError::NonClifford { line: 3, gate: "rz".to_owned() });
+        assert_eq!(err("qreg q[2];\nh q[0];\ncx q[0],\n   q[1]; ccx q[0], q[1], q[1];").to_string(),
+                   "line 4: ccx is not a Clifford gate");
+        assert!(matches!(err("qreg q[1];\nh r[0];"), QasmError::Syntax { line: 2, .. }));
+        assert!(matches!(err("qreg q[1];\nh q[1];"), QasmError::Syntax { line: 2, .. }));
+        assert!(matches!(err("qreg q[2];\nqreg r[3];\ncx q, r;"), QasmError::Syntax { line: 3, .. }));
+        assert!(matches!(err("qreg q[2];\ncreg c[1];\nmeasure q -> c;"), QasmError::Syntax { line: 3, .. }));
+        assert!(matches!(err("qreg q[1];\nfoo q[0];"), QasmError::Syntax { line: 2, .. }));
+        assert!(matches!(err("qreg q[1];\nrx(pi/) q[0];"), QasmError::Syntax { line: 2, .. }));
+        assert!(matches!(err("gate g a { h a; }"), QasmError::Unsupported { line: 1, .. }));
+        assert!(matches!(err("OPENQASM 3.0;"), QasmError::Unsupported { line: 1, .. }));
+    }
+
+    #[test]
+    fn angles() {
+        use std::f64::consts::PI;
+        let angle = |s| super::angle(1, s);
+        assert_eq!(angle("-3*pi/2"), Ok(-1.5 * PI));
+        assert_eq!(angle("1e-3"), Ok(0.001));
+        assert_eq!(angle("1.5E+2 - 2e2"), Ok(-50.0));
+        assert_eq!(angle("2.5e1*pi"), Ok(25.0 * PI));
+        assert!(angle("1e").is_err());
+        assert!(angle("1e-").is_err());
+        assert!(angle("e3").is_err());
+        assert!(angle("pi/0").is_err());
+        assert!(angle("0/0").is_err());
+        let err = |s: &str| s.parse::<QasmCircuit>().unwrap_err();
+        assert!(matches!(err("qreg q[1];\nrz(pi/0) q[0];"), QasmError::Syntax { line: 2, .. }));
+        assert!(matches!(err("qreg q[1];\nrz(0/0) q[0];"), QasmError::Syntax { line: 2, .. }));
+        let c: QasmCircuit = "qreg q[1];\nrz(1.5707963267948966e0) q[0];\nrx(0.5e+1*pi) q[0];".parse().unwrap();
+        assert_eq!(c.instructions, vec![Instruction::Gate1(Gate1::S, 0), Instruction::Gate1(Gate1::X, 0)]);
+    }
+}
@@ -0,0 +1,432 @@
+use std::convert::TryInto;
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+
+use rand_core::{RngCore, SeedableRng};
+use rand_xorshift::XorShiftRng;
+
+use crate::{BitArray, GottesmanKnillSimulator, Layout, PauliString};
+use crate::tableau::Tableau;
+
+const MAGIC: &[u8; 4] = b"GKST";
+const VERSION: u16 = 1;
+const COLUMN_MAJOR: u8 = 1;
+const HAS_RNG: u8 = 2;
+
+/// Error of loading a serialized simulator state.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum StateError {
+    /// The bytes do not start with the header of a simulator state.
+    NotAState,
+    /// The state was written in another version of the format.
+    Version(u16),
+    /// The bytes end early or go on after the state.
+    Length,
+    /// A value is out of range, or the rows are not the tableau of a stabilizer group.
+    Invalid(String),
+    /// No RNG state was saved, or it is not a state of this RNG.
+    Rng,
+}
+
+impl Display for StateError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            StateError::NotAState => f.write_str("not a simulator state"),
+            StateError::Version(v) => write!(f, "unsupported state version {}, expected {}", v, VERSION),
+            StateError::Length => f.write_str("state has the wrong length"),
+            StateError::Invalid(message) => write!(f, "invalid state: {}", message),
+            StateError::Rng => f.write_str("no loadable RNG state"),
+        }
+    }
+}
+
+impl Error for StateError {}
+
+/// Error of `measure_forced`: the outcome `bit` of measuring `qubit` has probability zero.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ImpossibleOutcome {
+    pub qubit: u32,
+    pub bit: bool,
+}
+
+impl Display for ImpossibleOutcome {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "outcome {} of qubit {} has probability zero", self.bit as u8, self.qubit)
+    }
+}
+
+impl Error for ImpossibleOutcome {}
+
+/// Simulator state without the RNG, taken by `snapshot` and put back by `restore`.
+#[derive(Clone, Debug)]
+pub struct Snapshot {
+    tableau: Tableau,
+    measured: BitArray,
+    record: BitArray,
+    detectors: BitArray,
+    observables: BitArray,
+    readout_errors: Vec<f64>,
+    reset_errors: Vec<f64>,
+}
+
+/// RNG whose state can be saved in a checkpoint.
+pub trait RngState: Sized {
+    fn save_state(&self) -> Vec<u8>;
+    /// Returns None if `bytes` is not a state of this RNG.
+    fn load_state(bytes: &[u8]) -> Option<Self>;
+}
+
+/// Inverts y = x ^ (x >> s).
+fn unshift_right(y: u32, s: u32) -> u32 {
+    (0..32 / s).fold(y, |x, _| y ^ (x >> s))
+}
+
+/// Inverts y = x ^ (x << s).
+fn unshift_left(y: u32, s: u32) -> u32 {
+    (0..32 / s).fold(y, |x, _| y ^ (x << s))
+}
+
+impl RngState for XorShiftRng {
+    /// Writes (x, y, z, w) as the 16-byte seed which recreates them.
+    ///
+    /// The state is private, but xorshift128 is a bijection: four outputs of a copy are the state
+    /// four steps ahead, and the steps are undone.
+    fn save_state(&self) -> Vec<u8> {
+        let mut rng = self.clone();
+        let mut s = [0u32; 4];
+        s.iter_mut().for_each(|s| *s = rng.next_u32());
+        for _ in 0..4 {
+            // (x, y, z, w) -> (y, z, w, w ^ (w >> 19) ^ t ^ (t >> 8)) with t = x ^ (x << 11).
+            let [y, z, w, next] = s;
+            let t = unshift_right(next ^ w ^ (w >> 19), 8);
+            s = [unshift_left(t, 11), y, z, w];
+        }
+        s.iter().flat_map(|s| s.to_le_bytes()).collect()
+    }
+
+    fn load_state(bytes: &[u8]) -> Option<Self> {
+        let seed: [u8; 16] = bytes.try_into().ok()?;
+        // The zero seed is replaced by a preset one, and is never a state.
+        if seed == [0; 16] {
+            return None;
+        }
+        Some(XorShiftRng::from_seed(seed))
+    }
+}
+
+fn put_u32(out: &mut Vec<u8>, v: usize) {
+    out.extend_from_slice(&(v as u32).to_le_bytes());
+}
+
+/// Packs the bits least significant first, without the length.
+fn put_bits(out: &mut Vec<u8>, bits: &BitArray) {
+    let bytes = bits.blocks().iter().flat_map(|b| b.to_le_bytes());
+    out.extend(bytes.take((bits.len() + 7) / 8));
+}
+
+fn put_bit_vec(out: &mut Vec<u8>, bits: &BitArray) {
+    put_u32(out, bits.len());
+    put_bits(out, bits);
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], StateError> {
+        if self.bytes.len() < n {
+            return Err(StateError::Length);
+        }
+        let (head, rest) = self.bytes.split_at(n);
+        self.bytes = rest;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, StateError> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<usize, StateError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()) as usize)
+    }
+
+    fn f64(&mut self) -> Result<f64, StateError> {
+        Ok(f64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn bits(&mut self, len: usize) -> Result<BitArray, StateError> {
+        let n_bytes = (len + 7) / 8;
+        let bytes = self.take(n_bytes)?;
+        let mut bits = BitArray::zeros(len);
+        for (block, chunk) in bits.blocks_mut().iter_mut().zip(bytes.chunks(8)) {
+            let mut buf = [0; 8];
+            buf[..chunk.len()].copy_from_slice(chunk);
+            *block = u64::from_le_bytes(buf);
+        }
+        // Bits past the end must stay clear for comparisons.
+        if (len..n_bytes * 8).any(|i| (bytes[i / 8] >> (i % 8)) & 1 == 1) {
+            return Err(StateError::Invalid("padding bits are set".to_owned()));
+        }
+        Ok(bits)
+    }
+
+    fn bit_vec(&mut self) -> Result<BitArray, StateError> {
+        let len = self.u32()?;
+        self.bits(len)
+    }
+
+    fn probabilities(&mut self, n: usize) -> Result<Vec<f64>, StateError> {
+        let ps = (0..n).map(|_| self.f64()).collect::<Result<Vec<_>, _>>()?;
+        match ps.iter().find(|p| !(0.0..=1.0).contains(*p)) {
+            Some(p) => Err(StateError::Invalid(format!("error probability {} is out of range", p))),
+            None => Ok(ps),
+        }
+    }
+}
+
+/// Checks that the stabilizers commute and the i-th destabilizer anticommutes only with the
+/// i-th stabilizer, so the stabilizers are independent and generate a stabilizer group.
+fn check_rows(rows: &[PauliString]) -> Result<(), StateError> {
+    let n = rows.len() / 2;
+    for i in 0..2 * n {
+        for j in i + 1..2 * n {
+            if rows[i].commutes(&rows[j]) == (j == i + n) {
+                let kind = |r| if r < n { ("destabilizer", r) } else { ("stabilizer", r - n) };
+                let ((k1, r1), (k2, r2)) = (kind(i), kind(j));
+                let verb = if j == i + n { "commute" } else { "anticommute" };
+                return Err(StateError::Invalid(format!("{} {} and {} {} {}", k1, r1, k2, r2, verb)));
+            }
+        }
+    }
+    Ok(())
+}
+
+impl<Rng> GottesmanKnillSimulator<Rng> {
+    fn write_state(&self, rng: Option<Vec<u8>>) -> Vec<u8> {
+        let n = self.tableau.n_qubits();
+        let mut out = MAGIC.to_vec();
+        out.extend_from_slice(&VERSION.to_le_bytes());
+        let column_major = if self.tableau.layout() == Layout::ColumnMajor { COLUMN_MAJOR } else { 0 };
+        out.push(column_major | if rng.is_some() { HAS_RNG } else { 0 });
+        put_u32(&mut out, n);
+        put_u32(&mut out, self.measured.len());
+        let mut signs = BitArray::zeros(2 * n);
+        for row in 0..2 * n {
+            let p = self.tableau.pauli_row(row);
+            put_bits(&mut out, p.xs());
+            put_bits(&mut out, p.zs());
+            signs.set_bool(row, p.is_negative());
+        }
+        put_bits(&mut out, &signs);
+        put_bits(&mut out, &self.measured);
+        put_bit_vec(&mut out, &self.record);
+        put_bit_vec(&mut out, &self.detectors);
+        put_bit_vec(&mut out, &self.observables);
+        for p in self.readout_errors.iter().chain(&self.reset_errors) {
+            out.extend_from_slice(&p.to_le_bytes());
+        }
+        if let Some(rng) = rng {
+            put_u32(&mut out, rng.len());
+            out.extend_from_slice(&rng);
+        }
+        out
+    }
+
+    /// Reads a state and the saved RNG state, if any, checking the tableau.
+    fn read_state(bytes: &[u8], rng: impl FnOnce(Option<&[u8]>) -> Result<Rng, StateError>)
+        -> Result<Self, StateError> {
+        let mut r = Reader { bytes };
+        if r.take(4).map_err(|_| StateError::NotAState)? != MAGIC {
+            return Err(StateError::NotAState);
+        }
+        match r.u16()? {
+            VERSION => {},
+            v => return Err(StateError::Version(v)),
+        }
+        let flags = r.u8()?;
+        if flags & !(COLUMN_MAJOR | HAS_RNG) != 0 {
+            return Err(StateError::Invalid(format!("unknown flags {:#x}", flags)));
+        }
+        let layout = if flags & COLUMN_MAJOR != 0 { Layout::ColumnMajor } else { Layout::RowMajor };
+        let n = r.u32()?;
+        let n_slots = r.u32()?;
+        // Rows are read before anything is allocated for n qubits, so a bad n fails on the length.
+        let rows = (0..2 * n).map(|_| Ok(PauliString::from_xz(r.bits(n)?, r.bits(n)?, false)))
+                                 .collect::<Result<Vec<_>, _>>()?;
+        let signs = r.bits(2 * n)?;
+        check_rows(&rows)?;
+        let measured = r.bits(n_slots)?;
+        let record = r.bit_vec()?;
+        let detectors = r.bit_vec()?;
+        let observables = r.bit_vec()?;
+        let readout_errors = r.probabilities(n)?;
+        let reset_errors = r.probabilities(n)?;
+        let saved_rng = if flags & HAS_RNG != 0 {
+            let len = r.u32()?;
+            Some(r.take(len)?)
+        } else {
+            None
+        };
+        if !r.bytes.is_empty() {
+            return Err(StateError::Length);
+        }
+        let rng = rng(saved_rng)?;
+        let mut tableau = Tableau::new(n, layout);
+        for (row, p) in rows.iter().enumerate() {
+            tableau.set_row(row, p, signs.get_bool(row));
+        }
+        Ok(Self { tableau, measured, record, detectors, observables, readout_errors, reset_errors, rng })
+    }
+
+    /// Copies the state, whatever the RNG, to go back to it with `restore`.
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            tableau: self.tableau.clone(),
+            measured: self.measured.clone(),
+            record: self.record.clone(),
+            detectors: self.detectors.clone(),
+            observables: self.observables.clone(),
+            readout_errors: self.readout_errors.clone(),
+            reset_errors: self.reset_errors.clone(),
+        }
+    }
+
+    /// Puts back the state of a snapshot. The RNG goes on where it is.
+    pub fn restore(&mut self, snapshot: &Snapshot) {
+        self.tableau.clone_from(&snapshot.tableau);
+        self.measured.clone_from(&snapshot.measured);
+        self.record.clone_from(&snapshot.record);
+        self.detectors.clone_from(&snapshot.detectors);
+        self.observables.clone_from(&snapshot.observables);
+        self.readout_errors.clone_from(&snapshot.readout_errors);
+        self.reset_errors.clone_from(&snapshot.reset_errors);
+    }
+
+    /// Serializes the tableau, the measurement record and the error settings, without the RNG.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.write_state(None)
+    }
+
+    /// Loads a state written by `to_bytes` or `checkpoint`, continuing with `rng`.
+    /// A saved RNG state is ignored.
+    pub fn from_bytes(bytes: &[u8], rng: Rng) -> Result<Self, StateError> {
+        Self::read_state(bytes, |_| Ok(rng))
+    }
+}
+
+impl<Rng: RngState> GottesmanKnillSimulator<Rng> {
+    /// Serializes the whole simulator, including the RNG state, so that `resume` continues
+    /// with the same random outcomes.
+    pub fn checkpoint(&self) -> Vec<u8> {
+        self.write_state(Some(self.rng.save_state()))
+    }
+
+    /// Loads a state written by `checkpoint`, with its RNG state.
+    pub fn resume(bytes: &[u8]) -> Result<Self, StateError> {
+        Self::read_state(bytes, |rng| rng.and_then(Rng::load_state).ok_or(StateError::Rng))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand_core::{RngCore, SeedableRng};
+    use crate::{DefaultRng, Gate1, Gate2, GottesmanKnillSimulator, Instruction, Layout};
+    use crate::state::{RngState, StateError};
+
+    fn prepare(layout: Layout) -> GottesmanKnillSimulator<DefaultRng> {
+        let mut sim = GottesmanKnillSimulator::from_seed_with_layout(5, 3, layout);
+        sim.set_qubit_readout_error(2, 0.25);
+        sim.run(&[
+            Instruction::Gate1(Gate1::H, 0),
+            Instruction::Gate2(Gate2::CX, 0, 1),
+            Instruction::Gate1(Gate1::S, 1),
+            Instruction::Gate2(Gate2::CZ, 1, 3),
+            Instruction::Gate1(Gate1::H, 4),
+            Instruction::Measure(4, 4),
+            Instruction::Detector(vec![], vec![1]),
+        ]).unwrap();
+        sim
+    }
+
+    #[test]
+    fn rng_state() {
+        let mut rng = DefaultRng::seed_from_u64(42);
+        rng.next_u64();
+        let mut loaded = DefaultRng::load_state(&rng.save_state()).unwrap();
+        assert_eq!((0..10).map(|_| rng.next_u32()).collect::<Vec<_>>(),
+                   (0..10).map(|_| loaded.next_u32()).collect::<Vec<_>>());
+        assert!(DefaultRng::load_state(&[0; 16]).is_none());
+        assert!(DefaultRng::load_state(&[1; 15]).is_none());
+    }
+
+    #[test]
+    fn roundtrip() {
+        for &layout in &[Layout::RowMajor, Layout::ColumnMajor] {
+            let sim = prepare(layout);
+            let bytes = sim.checkpoint();
+            let mut a = sim;
+            let mut b = GottesmanKnillSimulator::<DefaultRng>::resume(&bytes).unwrap();
+            assert_eq!(b.layout(), layout);
+            assert_eq!(b.to_bytes(), a.to_bytes());
+            assert_eq!(b.checkpoint(), bytes);
+            // Both continue with the same random outcomes.
+            let rest: Vec<_> = (0..4).map(|q| Instruction::Measure(q, q)).collect();
+            a.run(&rest).unwrap();
+            b.run(&rest).unwrap();
+            assert_eq!(a.checkpoint(), b.checkpoint());
+
+            let c = GottesmanKnillSimulator::from_bytes(&bytes, DefaultRng::seed_from_u64(0)).unwrap();
+            assert_eq!(c.stabilizers(), prepare(layout).stabilizers());
+        }
+    }
+
+    #[test]
+    fn snapshot() {
+        let mut sim = prepare(Layout::ColumnMajor);
+        let snapshot = sim.snapshot();
+        let bytes = sim.to_bytes();
+        let branches: Vec<_> = (0..4).map(|q| {
+            let p = sim.measure_forced(q, true).unwrap_or(0.0);
+            let stabilizers = sim.stabilizers();
+            sim.restore(&snapshot);
+            assert_eq!(sim.to_bytes(), bytes);
+            (p, stabilizers)
+        }).collect();
+        assert_eq!(branches.iter().map(|b| b.0).collect::<Vec<_>>(), vec![0.5, 0.5, 0.0, 0.0]);
+        assert_ne!(branches[0].1, sim.stabilizers());
+        // A clone goes on independently with the same RNG.
+        let mut a = sim.clone();
+        a.run(&[Instruction::Measure(0, 0), Instruction::Measure(1, 1)]).unwrap();
+        sim.run(&[Instruction::Measure(0, 0), Instruction::Measure(1, 1)]).unwrap();
+        assert_eq!(a.to_bytes(), sim.to_bytes());
+    }
+
+    #[test]
+    fn errors() {
+        let sim = prepare(Layout::RowMajor);
+        let bytes = sim.to_bytes();
+        let load = |bytes: &[u8]| GottesmanKnillSimulator::from_bytes(bytes, DefaultRng::seed_from_u64(0)).err();
+        assert_eq!(load(b"GK"), Some(StateError::NotAState));
+        assert_eq!(load(b"hello, world"), Some(StateError::NotAState));
+        let mut v2 = bytes.clone();
+        v2[4] = 2;
+        assert_eq!(load(&v2), Some(StateError::Version(2)));
+        assert_eq!(load(&bytes[..bytes.len() - 1]), Some(StateError::Length));
+        assert_eq!(load(&[&bytes[..], &[0]].concat()), Some(StateError::Length));
+        assert_eq!(GottesmanKnillSimulator::<DefaultRng>::resume(&bytes).err(), Some(StateError::Rng));
+
+        // Sets X on qubit 2 of stabilizer 1, which then anticommutes with stabilizer 2, Z2.
+        let mut bad = bytes.clone();
+        bad[15 + 6 * 2] ^= 1 << 2;
+        assert_eq!(load(&bad), Some(StateError::Invalid("stabilizer 1 and stabilizer 2 anticommute".to_owned())));
+        // A huge number of qubits fails on the length without allocating.
+        let mut huge = bytes;
+        huge[7..11].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert_eq!(load(&huge), Some(StateError::Length));
+    }
+}
@@ -0,0 +1,725 @@
+use std::convert::TryFrom;
+use std::error::Error;
+use std::fmt::{self, Display, Formatter, Write as _};
+use std::iter::Peekable;
+use std::ops::Range;
+use std::slice;
+use std::str::FromStr;
+
+use crate::{Basis, Condition, Gate1, Gate2, Instruction, Noise1, Noise2, Pauli, PauliString};
+
+/// Errors of reading or writing a Stim circuit.
+#[derive(Clone, Debug, PartialEq)]
+pub enum StimError {
+    /// A line which is not valid Stim.
+    Syntax { line: usize, message: String },
+    /// A valid Stim instruction, or use of one, which this crate does not simulate.
+    Unsupported { line: usize, name: String },
+    /// An instruction which has no Stim counterpart.
+    Export(Instruction),
+}
+
+impl Display for StimError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            StimError::Syntax { line, message } => write!(f, "line {}: {}", line, message),
+            StimError::Unsupported { line, name } => write!(f, "line {}: unsupported Stim instruction {}", line, name),
+            StimError::Export(inst) => write!(f, "{:?} cannot be written as Stim", inst),
+        }
+    }
+}
+
+impl Error for StimError {}
+
+/// Circuit in Stim's text format, as instructions for the simulators of this crate.
+///
+/// REPEAT blocks are unrolled into `instructions` and listed in `repeats`, so that they are
+/// written back as blocks. Stim has no slots, so the i-th measurement is written to slot i,
+/// and slots are dropped when writing.
+/// Detector coordinates are absolute, with SHIFT_COORDS applied.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StimCircuit {
+    pub n_qubits: u32,
+    pub instructions: Vec<Instruction>,
+    /// Coordinates of the qubits given by QUBIT_COORDS, in order.
+    pub qubit_coords: Vec<(u32, Vec<f64>)>,
+    /// REPEAT blocks, outer blocks before the blocks they contain, one for each time a block is reached.
+    pub repeats: Vec<RepeatBlock>,
+}
+
+/// A REPEAT block of a Stim circuit, unrolled into `instructions[start..start + len * count]`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RepeatBlock {
+    pub start: usize,
+    /// Number of instructions of one iteration.
+    pub len: usize,
+    pub count: u64,
+    /// Sum of the SHIFT_COORDS of one iteration.
+    pub shift: Vec<f64>,
+}
+
+const GATES1: [(&str, Gate1); 15] = [
+    ("I", Gate1::I),
+    ("X", Gate1::X),
+    ("Y", Gate1::Y),
+    ("Z", Gate1::Z),
+    ("H", Gate1::H),
+    ("S", Gate1::S),
+    ("S_DAG", Gate1::Sdg),
+    ("SQRT_X", Gate1::SqrtX),
+    ("SQRT_X_DAG", Gate1::SqrtXdg),
+    ("SQRT_Y", Gate1::SqrtY),
+    ("SQRT_Y_DAG", Gate1::SqrtYdg),
+    ("H_XY", Gate1::HXY),
+    ("H_YZ", Gate1::HYZ),
+    ("C_XYZ", Gate1::CXYZ),
+    ("C_ZYX", Gate1::CZYX),
+];
+
+const GATES2: [(&str, Gate2); 6] = [
+    ("CX", Gate2::CX),
+    ("CY", Gate2::CY),
+    ("CZ", Gate2::CZ),
+    ("SWAP", Gate2::Swap),
+    ("ISWAP", Gate2::ISwap),
+    ("ISWAP_DAG", Gate2::ISwapdg),
+];
+
+/// Other names Stim accepts for the same instructions.
+const ALIASES: [(&str, &str); 10] = [
+    ("H_XZ", "H"),
+    ("SQRT_Z", "S"),
+    ("SQRT_Z_DAG", "S_DAG"),
+    ("CNOT", "CX"),
+    ("ZCX", "CX"),
+    ("ZCY", "CY"),
+    ("ZCZ", "CZ"),
+    ("MZ", "M"),
+    ("MRZ", "MR"),
+    ("RZ", "R"),
+];
+
+#[derive(Debug)]
+enum Target {
+    Qubit(u32),
+    /// rec[-k]
+    Rec(u32),
+    /// A qubit with an inverted result, "!q".
+    Inverted,
+    /// A Pauli product of MPP.
+    Product(PauliString),
+}
+
+#[derive(Debug)]
+enum Node {
+    Op { line: usize, name: String, args: Vec<f64>, targets: Vec<Target> },
+    Repeat { line: usize, count: u64, body: Vec<Node> },
+}
+
+fn syntax(line: usize, message: String) -> StimError {
+    StimError::Syntax { line, message }
+}
+
+/// Parses lines up to the closing brace of a REPEAT block, or to the end at the top level.
+fn parse_block<'a, I>(lines: &mut I, opened: Option<usize>) -> Result<Vec<Node>, StimError>
+        where I: Iterator<Item = (usize, &'a str)> {
+    let mut nodes = vec![];
+    while let Some((line, text)) = lines.next() {
+        let text = text.split('#').next().unwrap().trim();
+        if text.is_empty() {
+            continue;
+        }
+        if text == "}" {
+            return match opened {
+                Some(_) => Ok(nodes),
+                None => Err(syntax(line, "unmatched '}'".to_owned())),
+            };
+        }
+        if let Some(rest) = text.strip_prefix("REPEAT ").or_else(|| text.strip_prefix("repeat ")) {
+            let count = rest.trim().strip_suffix('{')
+                            .and_then(|c| c.trim().parse().ok())
+                            .ok_or_else(|| syntax(line, format!("invalid REPEAT block {:?}", text)))?;
+            let body = parse_block(lines, Some(line))?;
+            nodes.push(Node::Repeat { line, count, body });
+            continue;
+        }
+        nodes.push(parse_op(line, text)?);
+    }
+    match opened {
+        Some(line) => Err(syntax(line, "REPEAT block is not closed".to_owned())),
+        None => Ok(nodes),
+    }
+}
+
+fn parse_op(line: usize, text: &str) -> Result<Node, StimError> {
+    let name_end = text.find(|c: char| c == '(' || c.is_whitespace()).unwrap_or(text.len());
+    let name = text[..name_end].to_uppercase();
+    let name = ALIASES.iter().find(|(alias, _)| *alias == name).map_or(name.clone(), |(_, n)| (*n).to_owned());
+    let mut rest = &text[name_end..];
+    let mut args = vec![];
+    if let Some(r) = rest.strip_prefix('(') {
+        let close = r.find(')').ok_or_else(|| syntax(line, "missing ')'".to_owned()))?;
+        for arg in r[..close].split(',').map(str::trim).filter(|a| !a.is_empty()) {
+            args.push(arg.parse().map_err(|_| syntax(line, format!("invalid argument {:?}", arg)))?);
+        }
+        rest = &r[close + 1..];
+    }
+    // Products of MPP may be written with spaces around '*'.
+    let rest = rest.split('*').map(str::trim).collect::<Vec<_>>().join("*");
+    let mut targets = vec![];
+    for t in rest.split_whitespace() {
+        let invalid = || syntax(line, format!("invalid target {:?}", t));
+        let target = if let Some(k) = t.strip_prefix("rec[-").and_then(|k| k.strip_suffix(']')) {
+            Target::Rec(k.parse().ok().filter(|&k| k > 0).ok_or_else(invalid)?)
+        } else if t.starts_with(|c: char| c.is_ascii_digit()) {
+            Target::Qubit(t.parse().map_err(|_| invalid())?)
+        } else if let Some(q) = t.strip_prefix('!').filter(|q| q.starts_with(|c: char| c.is_ascii_digit())) {
+            q.parse::<u32>().map_err(|_| invalid())?;
+            Target::Inverted
+        } else if t.starts_with("sweep[") {
+            return Err(StimError::Unsupported { line, name: format!("{} with sweep bits", name) });
+        } else {
+            let (negated, p) = match t.strip_prefix('!') {
+                Some(p) => (true, p),
+                None => (false, t),
+            };
+            if !p.starts_with(['X', 'Y', 'Z']) {
+                return Err(invalid());
+            }
+            let mut p: PauliString = p.parse().map_err(|_| invalid())?;
+            if negated {
+                p.negate();
+            }
+            Target::Product(p)
+        };
+        targets.push(target);
+    }
+    Ok(Node::Op { line, name, args, targets })
+}
+
+/// Bound on the instructions and blocks that REPEAT blocks may unroll to.
+const MAX_UNROLLED: usize = 1 << 24;
+
+/// State of unrolling the parsed blocks into instructions.
+struct Lowering {
+    instructions: Vec<Instruction>,
+    qubit_coords: Vec<(u32, Vec<f64>)>,
+    repeats: Vec<RepeatBlock>,
+    n_measurements: u32,
+    shift: Vec<f64>,
+}
+
+impl Lowering {
+    fn lower(&mut self, nodes: &[Node]) -> Result<(), StimError> {
+        for node in nodes {
+            match node {
+                Node::Op { line, name, args, targets } => self.lower_op(*line, name, args, targets)?,
+                Node::Repeat { count: 0, .. } => {},
+                Node::Repeat { line, count, body } => {
+                    let index = self.repeats.len();
+                    let start = self.instructions.len();
+                    self.repeats.push(RepeatBlock { start, len: 0, count: *count, shift: vec![] });
+                    let before = self.shift.clone();
+                    self.lower(body)?;
+                    self.repeats[index].len = self.instructions.len() - start;
+                    self.repeats[index].shift = unshifted(&self.shift, &before);
+                    // Each iteration costs at least one step, even if it only shifts coordinates.
+                    let size = (self.instructions.len() - start + self.repeats.len() - index).max(1) as u64;
+                    let unrolled = (*count - 1).checked_mul(size).and_then(|n| n.checked_add(self.size() as u64));
+                    if unrolled.map_or(true, |n| n > MAX_UNROLLED as u64) {
+                        return Err(syntax(*line, format!("REPEAT block unrolls to more than {} instructions", MAX_UNROLLED)));
+                    }
+                    for _ in 1..*count {
+                        self.lower(body)?;
+                    }
+                },
+            }
+        }
+        Ok(())
+    }
+
+    fn size(&self) -> usize {
+        self.instructions.len() + self.repeats.len()
+    }
+
+    fn measure(&mut self, inst: impl FnOnce(u32) -> Instruction) {
+        self.instructions.push(inst(self.n_measurements));
+        self.n_measurements += 1;
+    }
+
+    fn shifted(&self, coords: &[f64]) -> Vec<f64> {
+        coords.iter().enumerate().map(|(i, c)| c + self.shift.get(i).unwrap_or(&0.0)).collect()
+    }
+
+    fn lower_op(&mut self, line: usize, name: &str, args: &[f64], targets: &[Target]) -> Result<(), StimError> {
+        let unsupported = |what: &str| StimError::Unsupported { line, name: what.to_owned() };
+        let qubits = || targets.iter().map(|t| match t {
+            Target::Qubit(q) => Ok(*q),
+            Target::Inverted => Err(unsupported(&format!("{} with inverted targets", name))),
+            t => Err(syntax(line, format!("{} cannot target {:?}", name, t))),
+        }).collect::<Result<Vec<_>, _>>();
+        let lookbacks = || targets.iter().map(|t| match t {
+            Target::Rec(k) if *k <= self.n_measurements => Ok(*k),
+            Target::Rec(k) => Err(syntax(line, format!("rec[-{}] is out of the {} measurements so far", k, self.n_measurements))),
+            t => Err(syntax(line, format!("{} cannot target {:?}", name, t))),
+        }).collect::<Result<Vec<_>, _>>();
+        let n_args = |n: usize| if args.len() == n {
+            Ok(())
+        } else {
+            Err(syntax(line, format!("{} takes {} arguments", name, n)))
+        };
+        let basis = |name: &str| match name.chars().last() {
+            Some('X') => Basis::X,
+            Some('Y') => Basis::Y,
+            _ => Basis::Z,
+        };
+
+        if let Some(&(_, gate)) = GATES1.iter().find(|(n, _)| *n == name) {
+            n_args(0)?;
+            for q in qubits()? {
+                self.instructions.push(Instruction::Gate1(gate, q));
+            }
+        } else if let Some(&(_, gate)) = GATES2.iter().find(|(n, _)| *n == name) {
+            n_args(0)?;
+            if targets.len() % 2 == 1 {
+                return Err(syntax(line, format!("{} needs pairs of targets", name)));
+            }
+            for pair in targets.chunks(2) {
+                let inst = match (&pair[0], &pair[1], gate) {
+                    (Target::Qubit(a), Target::Qubit(b), _) if a == b => {
+                        return Err(syntax(line, format!("{} on qubit {} twice", name, a)));
+                    },
+                    (Target::Qubit(a), Target::Qubit(b), _) => Instruction::Gate2(gate, *a, *b),
+                    // Classically controlled Paulis.
+                    (Target::Rec(_), Target::Qubit(q), Gate2::CX | Gate2::CY | Gate2::CZ)
+                    | (Target::Qubit(q), Target::Rec(_), Gate2::CZ) => {
+                        let k = match (&pair[0], &pair[1]) {
+                            (Target::Rec(k), _) | (_, Target::Rec(k)) => *k,
+                            _ => unreachable!(),
+                        };
+                        if k > self.n_measurements {
+                            return Err(syntax(line, format!("rec[-{}] is out of the {} measurements so far", k, self.n_measurements)));
+                        }
+                        let p = match gate {
+                            Gate2::CX => Gate1::X,
+                            Gate2::CY => Gate1::Y,
+                            _ => Gate1::Z,
+                        };
+                        Instruction::Conditional(Condition::Record(vec![k]), Box::new(Instruction::Gate1(p, *q)))
+                    },
+                    (a, b, _) => return Err(syntax(line, format!("{} cannot target {:?} and {:?}", name, a, b))),
+                };
+                self.instructions.push(inst);
+            }
+        } else {
+            match name {
+                "M" | "MX" | "MY" | "MR" | "MRX" | "MRY" => {
+                    if !args.is_empty() {
+                        return Err(unsupported(&format!("{} with a flip probability", name)));
+                    }
+                    let basis = basis(name);
+                    for q in qubits()? {
+                        if name.starts_with("MR") {
+                            self.measure(|s| Instruction::MeasureReset(basis, q, s));
+                        } else if basis == Basis::Z {
+                            self.measure(|s| Instruction::Measure(q, s));
+                        } else {
+                            self.measure(|s| Instruction::MeasureIn(basis, q, s));
+                        }
+                    }
+                },
+                "R" | "RX" | "RY" => {
+                    n_args(0)?;
+                    let basis = basis(name);
+                    for q in qubits()? {
+                        self.instructions.push(Instruction::Reset(basis, q));
+                    }
+                },
+                "MPP" => {
+                    if !args.is_empty() {
+                        return Err(unsupported("MPP with a flip probability"));
+                    }
+                    for t in targets {
+                        match t {
+                            Target::Product(p) => self.measure(|s| Instruction::Mpp(p.clone(), s)),
+                            t => return Err(syntax(line, format!("MPP cannot target {:?}", t))),
+                        }
+                    }
+                },
+                "X_ERROR" | "Y_ERROR" | "Z_ERROR" | "DEPOLARIZE1" | "PAULI_CHANNEL_1" => {
+                    let ch = match name {
+                        "PAULI_CHANNEL_1" => {
+                            n_args(3)?;
+                            Noise1::PauliChannel(args[0], args[1], args[2])
+                        },
+                        _ => {
+                            n_args(1)?;
+                            match name {
+                                "X_ERROR" => Noise1::BitFlip(args[0]),
+                                "Y_ERROR" => Noise1::PauliChannel(0.0, args[0], 0.0),
+                                "Z_ERROR" => Noise1::PhaseFlip(args[0]),
+                                _ => Noise1::Depolarize(args[0]),
+                            }
+                        },
+                    };
+                    if !ch.is_valid() {
+                        return Err(syntax(line, format!("invalid probabilities for {}: {:?}", name, args)));
+                    }
+                    for q in qubits()? {
+                        self.instructions.push(Instruction::Noise1(ch, q));
+                    }
+                },
+                "DEPOLARIZE2" => {
+                    n_args(1)?;
+                    if !Noise2::Depolarize(args[0]).is_valid() {
+                        return Err(syntax(line, format!("invalid probability for DEPOLARIZE2: {}", args[0])));
+                    }
+                    let qubits = qubits()?;
+                    if qubits.len() % 2 == 1 {
+                        return Err(syntax(line, "DEPOLARIZE2 needs pairs of targets".to_owned()));
+                    }
+                    for pair in qubits.chunks(2) {
+                        self.instructions.push(Instruction::Noise2(Noise2::Depolarize(args[0]), pair[0], pair[1]));
+                    }
+                },
+                "DETECTOR" => {
+                    let lookbacks = lookbacks()?;
+                    self.instructions.push(Instruction::Detector(self.shifted(args), lookbacks));
+                },
+                "OBSERVABLE_INCLUDE" => {
+                    n_args(1)?;
+                    if args[0] < 0.0 || args[0].fract() != 0.0 {
+                        return Err(syntax(line, format!("invalid observable index {}", args[0])));
+                    }
+                    let lookbacks = lookbacks()?;
+                    self.instructions.push(Instruction::ObservableInclude(args[0] as u32, lookbacks));
+                },
+                "QUBIT_COORDS" => {
+                    let coords = self.shifted(args);
+                    for q in qubits()? {
+                        self.qubit_coords.push((q, coords.clone()));
+                    }
+                },
+                "SHIFT_COORDS" => add_shift(&mut self.shift, args),
+                "TICK" => {},
+                _ => return Err(unsupported(name)),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for StimCircuit {
+    type Err = StimError;
+
+    fn from_str(s: &str) -> Result<Self, StimError> {
+        let nodes = parse_block(&mut s.lines().enumerate().map(|(i, l)| (i + 1, l)), None)?;
+        let mut lowering = Lowering {
+            instructions: vec![],
+            qubit_coords: vec![],
+            repeats: vec![],
+            n_measurements: 0,
+            shift: vec![],
+        };
+        lowering.lower(&nodes)?;
+        let mut n_qubits = lowering.qubit_coords.iter().map(|&(q, _)| q + 1).max().unwrap_or(0);
+        for inst in lowering.instructions.iter() {
+            n_qubits = n_qubits.max(max_qubit(inst).map_or(0, |q| q + 1));
+        }
+        Ok(StimCircuit {
+            n_qubits,
+            instructions: lowering.instructions,
+            qubit_coords: lowering.qubit_coords,
+            repeats: lowering.repeats,
+        })
+    }
+}
+
+fn max_qubit(inst: &Instruction) -> Option<u32> {
+    match inst {
+        Instruction::Gate1(_, q) | Instruction::Measure(q, _) | Instruction::MeasureIn(_, q, _) | Instruction::Reset(_, q)
+        | Instruction::MeasureReset(_, q, _) | Instruction::Noise1(_, q) => Some(*q),
+        Instruction::Gate2(_, a, b) | Instruction::Noise2(_, a, b) => Some(*a.max(b)),
+        Instruction::Mpp(p, _) => p.len().checked_sub(1).map(|q| q as u32),
+        Instruction::Clifford(_, targets) => targets.iter().cloned().max(),
+        Instruction::Conditional(_, inst) => max_qubit(inst),
+        _ => None,
+    }
+}
+
+fn coords(args: &[f64]) -> String {
+    if args.is_empty() {
+        String::new()
+    } else {
+        format!("({})", args.iter().map(|a| a.to_string()).collect::<Vec<_>>().join(", "))
+    }
+}
+
+fn recs(lookbacks: &[u32]) -> String {
+    lookbacks.iter().map(|k| format!(" rec[-{}]", k)).collect()
+}
+
+fn add_shift(shift: &mut Vec<f64>, delta: &[f64]) {
+    if shift.len() < delta.len() {
+        shift.resize(delta.len(), 0.0);
+    }
+    shift.iter_mut().zip(delta.iter()).for_each(|(s, d)| *s += d);
+}
+
+/// Subtracts `shift` from the leading coordinates, undoing SHIFT_COORDS.
+fn unshifted(coords: &[f64], shift: &[f64]) -> Vec<f64> {
+    coords.iter().enumerate().map(|(i, c)| c - shift.get(i).unwrap_or(&0.0)).collect()
+}
+
+impl StimCircuit {
+    /// Number of slots the instructions measure into, one per measurement of the Stim circuit.
+    pub fn n_slots(&self) -> u32 {
+        self.instructions.iter().filter_map(|inst| inst.max_slot()).map(|s| s + 1).max().unwrap_or(0)
+    }
+
+    /// Writes the circuit in Stim's text format, one instruction per line.
+    ///
+    /// The blocks of `repeats` are written as REPEAT blocks, with the detector coordinates
+    /// relative to SHIFT_COORDS, as long as their iterations are still alike; otherwise they
+    /// are written unrolled. TICKs and the places of QUBIT_COORDS are not kept.
+    pub fn to_stim(&self) -> Result<String, StimError> {
+        let mut out = String::new();
+        for (q, c) in self.qubit_coords.iter() {
+            writeln!(out, "QUBIT_COORDS{} {}", coords(c), q).unwrap();
+        }
+        let mut repeats = self.repeats.iter().peekable();
+        self.write_range(&mut out, 0..self.instructions.len(), &mut repeats, &mut vec![])?;
+        Ok(out)
+    }
+
+    /// Writes `instructions[range]` with the coordinates shifted by `shift`.
+    fn write_range(&self, out: &mut String, range: Range<usize>, repeats: &mut Peekable<slice::Iter<RepeatBlock>>,
+                   shift: &mut Vec<f64>) -> Result<(), StimError> {
+        let mut i = range.start;
+        loop {
+            while repeats.next_if(|r| r.start < i).is_some() {}
+            if let Some(r) = repeats.next_if(|r| r.start == i && i < range.end) {
+                if self.is_rolled(r, range.end, shift)? {
+                    writeln!(out, "REPEAT {} {{", r.count).unwrap();
+                    let before = shift.clone();
+                    self.write_range(out, i..i + r.len, repeats, shift)?;
+                    let mut after = before.clone();
+                    add_shift(&mut after, &r.shift);
+                    let rest = unshifted(&after, shift);
+                    if rest.iter().any(|&d| d != 0.0) {
+                        writeln!(out, "SHIFT_COORDS{}", coords(&rest)).unwrap();
+                    }
+                    writeln!(out, "}}").unwrap();
+                    *shift = before;
+                    for _ in 0..r.count {
+                        add_shift(shift, &r.shift);
+                    }
+                    i += r.len * r.count as usize;
+                }
+                continue;
+            }
+            if i >= range.end {
+                return Ok(());
+            }
+            let line = self.line(i, shift)?;
+            if !line.is_empty() {
+                writeln!(out, "{}", line).unwrap();
+            }
+            i += 1;
+        }
+    }
+
+    /// Whether the iterations of `r` fit before `end` and are written the same.
+    fn is_rolled(&self, r: &RepeatBlock, end: usize, shift: &[f64]) -> Result<bool, StimError> {
+        let fits = usize::try_from(r.count).ok().and_then(|count| r.len.checked_mul(count))
+                                            .and_then(|len| r.start.checked_add(len))
+                                            .map_or(false, |e| e <= end);
+        if !fits {
+            return Ok(false);
+        }
+        let first = (r.start..r.start + r.len).map(|i| self.line(i, shift)).collect::<Result<Vec<_>, _>>()?;
+        let mut shift = shift.to_vec();
+        for k in 1..r.count as usize {
+            add_shift(&mut shift, &r.shift);
+            for (j, line) in first.iter().enumerate() {
+                if self.line(r.start + k * r.len + j, &shift)? != *line {
+                    return Ok(false);
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    /// The lines of `instructions[i]`, empty if it needs none.
+    fn line(&self, i: usize, shift: &[f64]) -> Result<String, StimError> {
+        let inst = &self.instructions[i];
+        let basis = |b: &Basis| match b {
+            Basis::X => "X",
+            Basis::Y => "Y",
+            Basis::Z => "",
+        };
+        Ok(match inst {
+            // The state starts reset, so only a leading Init has a Stim counterpart.
+            Instruction::Init if i == 0 => String::new(),
+            Instruction::Gate1(gate, q) => format!("{} {}", GATES1.iter().find(|(_, g)| g == gate).unwrap().0, q),
+            Instruction::Gate2(gate, a, b) => format!("{} {} {}", GATES2.iter().find(|(_, g)| g == gate).unwrap().0, a, b),
+            Instruction::Measure(q, _) => format!("M {}", q),
+            Instruction::MeasureIn(b, q, _) => format!("M{} {}", basis(b), q),
+            Instruction::Reset(b, q) => format!("R{} {}", basis(b), q),
+            Instruction::MeasureReset(b, q, _) => format!("MR{} {}", basis(b), q),
+            Instruction::Mpp(p, _) if p.is_hermitian() && p.weight() > 0 => {
+                let sparse = p.to_sparse_string();
+                format!("MPP {}{}", if p.is_negative() { "!" } else { "" }, &sparse[1..])
+            },
+            Instruction::Noise1(ch, q) => match ch {
+                Noise1::BitFlip(p) => format!("X_ERROR({}) {}", p, q),
+                Noise1::PhaseFlip(p) => format!("Z_ERROR({}) {}", p, q),
+                Noise1::Depolarize(p) => format!("DEPOLARIZE1({}) {}", p, q),
+                Noise1::PauliChannel(px, py, pz) => format!("PAULI_CHANNEL_1({}, {}, {}) {}", px, py, pz, q),
+            },
+            Instruction::Noise2(Noise2::Depolarize(p), a, b) => format!("DEPOLARIZE2({}) {} {}", p, a, b),
+            Instruction::Conditional(Condition::Record(lookbacks), target) => {
+                let (name, q) = match **target {
+                    Instruction::Gate1(gate, q) => match gate.as_pauli() {
+                        Some(Pauli::X) => ("CX", q),
+                        Some(Pauli::Y) => ("CY", q),
+                        Some(Pauli::Z) => ("CZ", q),
+                        _ => return Err(StimError::Export(inst.clone())),
+                    },
+                    _ => return Err(StimError::Export(inst.clone())),
+                };
+                // A Pauli conditioned on a parity is the product of the Paulis conditioned on each bit.
+                lookbacks.iter().map(|k| format!("{} rec[-{}] {}", name, k, q)).collect::<Vec<_>>().join("\n")
+            },
+            Instruction::Detector(c, lookbacks) => format!("DETECTOR{}{}", coords(&unshifted(c, shift)), recs(lookbacks)),
+            Instruction::ObservableInclude(k, lookbacks) => format!("OBSERVABLE_INCLUDE({}){}", k, recs(lookbacks)),
+            _ => return Err(StimError::Export(inst.clone())),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Basis, Condition, Gate1, GottesmanKnillSimulator, Instruction, Noise1, PauliFrameSimulator, RepeatBlock, StimCircuit,
+                StimError};
+
+    const REPETITION_CODE: &str = "
+        # Distance-3 repetition code, data on even qubits.
+        QUBIT_COORDS(0) 0
+        QUBIT_COORDS(1) 1
+        QUBIT_COORDS(2) 2
+        QUBIT_COORDS(3) 3
+        QUBIT_COORDS(4) 4
+        REPEAT 3 {
+            X_ERROR(0.01) 0 2 4
+            CNOT 0 1 2 3
+            CX 2 1 4 3
+            DEPOLARIZE2(0.01) 0 1
+            MR 1 3
+            DETECTOR(1, 0) rec[-2]
+            DETECTOR(3, 0) rec[-1]
+            SHIFT_COORDS(0, 1)
+            TICK
+        }
+        M 0 2 4
+        DETECTOR(1, 0) rec[-3] rec[-2] rec[-5]
+        OBSERVABLE_INCLUDE(0) rec[-1]
+    ";
+
+    #[test]
+    fn parse() {
+        let c: StimCircuit = REPETITION_CODE.parse().unwrap();
+        assert_eq!(c.n_qubits, 5);
+        assert_eq!(c.n_slots(), 9);
+        assert_eq!(c.qubit_coords[3], (3, vec![3.0]));
+        assert_eq!(c.instructions.len(), 3 * 12 + 5);
+        assert_eq!(c.instructions[0], Instruction::Noise1(Noise1::BitFlip(0.01), 0));
+        assert_eq!(c.instructions[9], Instruction::MeasureReset(Basis::Z, 3, 1));
+        assert_eq!(c.instructions[23], Instruction::Detector(vec![3.0, 1.0], vec![1]));
+        assert_eq!(c.instructions[39], Instruction::Detector(vec![1.0, 3.0], vec![3, 2, 5]));
+
+        let reference = PauliFrameSimulator::check_detectors(c.n_qubits, &c.instructions).unwrap();
+        assert_eq!(reference.detectors.len(), 7);
+        let mut sim = GottesmanKnillSimulator::from_seed_with_slots(c.n_qubits, c.n_slots(), 0);
+        sim.run(&c.instructions).unwrap();
+        assert_eq!(sim.measurement_record().len(), 9);
+        let dem = PauliFrameSimulator::detector_error_model(c.n_qubits, &c.instructions).unwrap();
+        // An X error on qubit 4 in the last round.
+        assert!(dem.to_string().contains("\nerror(0.01) D5 L0\n"));
+    }
+
+    #[test]
+    fn feedback_and_mpp() {
+        let c: StimCircuit = "H 0\nM 0\nCX rec[-1] 1\nCZ 2 rec[-1]\nMPP !Z0*Z1 X2 * X0\nMY 3".parse().unwrap();
+        assert_eq!(c.instructions[2],
+                   Instruction::Conditional(Condition::Record(vec![1]), Box::new(Instruction::Gate1(Gate1::X, 1))));
+        assert_eq!(c.instructions[3],
+                   Instruction::Conditional(Condition::Record(vec![1]), Box::new(Instruction::Gate1(Gate1::Z, 2))));
+        assert_eq!(c.instructions[4], Instruction::Mpp("-ZZ".parse().unwrap(), 1));
+        assert_eq!(c.instructions[5], Instruction::Mpp("XIX".parse().unwrap(), 2));
+        assert_eq!(c.instructions[6], Instruction::MeasureIn(Basis::Y, 3, 3));
+        let mut sim = GottesmanKnillSimulator::from_seed_with_slots(c.n_qubits, c.n_slots(), 0);
+        for _ in 0..10 {
+            sim.run(&[Instruction::Init]).unwrap();
+            sim.run(&c.instructions).unwrap();
+            // Z0 Z1 is +1 after the correction, so its inverted outcome is 1.
+            assert!(sim.measurement_record().get_bool(1));
+        }
+    }
+
+    #[test]
+    fn errors() {
+        let err = |s: &str| s.parse::<StimCircuit>().unwrap_err();
+        assert_eq!(err("H 0\nHERALDED_ERASE(0.1) 0"), StimError::Unsupported { line: 2, name: "HERALDED_ERASE".to_owned() });
+        assert_eq!(err("M(0.01) 0"), StimError::Unsupported { line: 1, name: "M with a flip probability".to_owned() });
+        assert_eq!(err("M !0"), StimError::Unsupported { line: 1, name: "M with inverted targets".to_owned() });
+        assert!(matches!(err("CX 0"), StimError::Syntax { line: 1, .. }));
+        assert!(matches!(err("H 0\n\nCX 1 1"), StimError::Syntax { line: 3, .. }));
+        assert!(matches!(err("M 0\nDETECTOR rec[-2]"), StimError::Syntax { line: 2, .. }));
+        assert!(matches!(err("X_ERROR 0"), StimError::Syntax { line: 1, .. }));
+        assert_eq!(err("H 0\nX_ERROR(1.5) 0").to_string(), "line 2: invalid probabilities for X_ERROR: [1.5]");
+        assert!(matches!(err("PAULI_CHANNEL_1(0.5, 0.4, 0.3) 0"), StimError::Syntax { line: 1, .. }));
+        assert!(matches!(err("DEPOLARIZE1(-0.1) 0"), StimError::Syntax { line: 1, .. }));
+        assert!(matches!(err("DEPOLARIZE2(2) 0 1"), StimError::Syntax { line: 1, .. }));
+        assert!(matches!(err("H 0\nREPEAT 2 {\nH 0"), StimError::Syntax { line: 2, .. }));
+        assert!(matches!(err("H 0\n}"), StimError::Syntax { line: 2, .. }));
+        assert!(matches!(err("H 0\nREPEAT 18446744073709551615 {\nH 0\n}"), StimError::Syntax { line: 2, .. }));
+        assert!(matches!(err("REPEAT 100000 {\nREPEAT 1000 {\nH 0\n}\n}"), StimError::Syntax { line: 1, .. }));
+        assert!(matches!(err("REPEAT 1000000000 {\nREPEAT 2 {\n}\n}"), StimError::Syntax { line: 1, .. }));
+        assert!(matches!(err("H q"), StimError::Syntax { line: 1, .. }));
+        assert_eq!(err("CX 0 1\nM 0\nCX rec[-1] 1\nSWAP rec[-1] 1").to_string(),
+                   "line 4: SWAP cannot target Rec(1) and Qubit(1)");
+    }
+
+    #[test]
+    fn write() {
+        let c: StimCircuit = REPETITION_CODE.parse().unwrap();
+        let text = c.to_stim().unwrap();
+        assert!(text.starts_with("QUBIT_COORDS(0) 0\n"));
+        assert!(text.contains("\nREPEAT 3 {\nX_ERROR(0.01) 0\n"));
+        assert!(text.contains("\nDETECTOR(3, 0) rec[-1]\nSHIFT_COORDS(0, 1)\n}\nM 0\n"));
+        assert!(text.ends_with("\nDETECTOR(1, 0) rec[-3] rec[-2] rec[-5]\nOBSERVABLE_INCLUDE(0) rec[-1]\n"));
+        assert_eq!(text.parse::<StimCircuit>().unwrap(), c);
+
+        // Blocks whose iterations were edited apart are unrolled.
+        let mut edited = c.clone();
+        edited.instructions[12] = Instruction::Noise1(Noise1::BitFlip(0.02), 0);
+        let text = edited.to_stim().unwrap();
+        assert!(!text.contains("REPEAT"));
+        assert!(text.contains("\nDETECTOR(3, 2) rec[-1]\n"));
+        assert_eq!(text.parse::<StimCircuit>().unwrap().instructions, edited.instructions);
+
+        let nested = "REPEAT 2 {\nH 0\nREPEAT 3 {\nM 0\nDETECTOR(1) rec[-1]\nSHIFT_COORDS(1)\n}\nSHIFT_COORDS(0, 1)\n}\n\
+                      DETECTOR(0) rec[-1]\n";
+        let c: StimCircuit = nested.parse().unwrap();
+        assert_eq!(c.repeats[0], RepeatBlock { start: 0, len: 7, count: 2, shift: vec![3.0, 1.0] });
+        assert_eq!(c.to_stim().unwrap(), nested);
+
+        let c: StimCircuit = "H 0\nM 0\nCY rec[-1] 1\nMPP !X0*Y1\nRX 0\nMRY 1\nPAULI_CHANNEL_1(0.1, 0.2, 0.3) 0".parse().unwrap();
+        let text = c.to_stim().unwrap();
+        assert_eq!(text, "H 0\nM 0\nCY rec[-1] 1\nMPP !X0*Y1\nRX 0\nMRY 1\nPAULI_CHANNEL_1(0.1, 0.2, 0.3) 0\n");
+
+        let c = StimCircuit { n_qubits: 2, instructions: vec![Instruction::XorSlot(0, 1)], qubit_coords: vec![], repeats: vec![] };
+        assert_eq!(c.to_stim(), Err(StimError::Export(Instruction::XorSlot(0, 1))));
+    }
+}
@@ -0,0 +1,805 @@
+use rand_core::RngCore;
+
+use crate::{BitArray, CliffordTableau, Pauli, PauliString};
+use crate::clifford::anticommutes;
+
+/// Memory layout of the stabilizer tableau.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Layout {
+    /// One `BitArray` per tableau row. Measurements touch whole rows, so they are cheapest here.
+    RowMajor,
+    /// One `BitArray` per qubit spanning every row. Gates become a few word-wide operations.
+    ColumnMajor,
+}
+
+impl Default for Layout {
+    fn default() -> Self {
+        Layout::RowMajor
+    }
+}
+
+/// Aaronson-Gottesman tableau. Rows 0..n are destabilizers and rows n..2n are stabilizers.
+#[derive(Debug)]
+pub(crate) struct Tableau {
+    n: usize,
+    layout: Layout,
+    xs: Vec<BitArray>,
+    zs: Vec<BitArray>,
+    sgns: BitArray,
+}
+
+impl Clone for Tableau {
+    fn clone(&self) -> Self {
+        Tableau { n: self.n, layout: self.layout, xs: self.xs.clone(), zs: self.zs.clone(), sgns: self.sgns.clone() }
+    }
+
+    /// Reuses the allocated rows, as restoring a snapshot of the same size does.
+    fn clone_from(&mut self, source: &Self) {
+        self.n = source.n;
+        self.layout = source.layout;
+        self.xs.clone_from(&source.xs);
+        self.zs.clone_from(&source.zs);
+        self.sgns.clone_from(&source.sgns);
+    }
+}
+
+/// g-function of Aaronson and Gottesman: the power of i (mod 4) picked up
+/// when the single-qubit Pauli (x1, z1) is multiplied by (x2, z2) from the right.
+#[inline]
+fn g(x1: bool, z1: bool, x2: bool, z2: bool) -> u32 {
+    match (x1, z1) {
+        (false, false) => 0,
+        (true, true) => (z2 as u32 + 3 * x2 as u32) & 3,
+        (true, false) => if z2 { if x2 { 1 } else { 3 } } else { 0 },
+        (false, true) => if x2 { if z2 { 3 } else { 1 } } else { 0 },
+    }
+}
+
+/// Returns the power of i (mod 4) picked up when the Pauli string (x1, z1)
+/// is multiplied by (x2, z2) from the right. This is the sum of the g-function
+/// over every qubit.
+pub(crate) fn pauli_phase(x1: &BitArray, z1: &BitArray, x2: &BitArray, z2: &BitArray) -> u32 {
+    let mut plus = 0;
+    let mut minus = 0;
+    for (((&x1, &z1), &x2), &z2) in x1.blocks().iter()
+                                      .zip(z1.blocks().iter())
+                                      .zip(x2.blocks().iter())
+                                      .zip(z2.blocks().iter()) {
+        let y1 = x1 & z1;
+        let x1 = x1 & !z1;
+        let z1 = z1 & !y1;
+        // Y * Z = iX, Y * X = -iZ, X * Y = iZ, X * Z = -iY, Z * X = iY, Z * Y = -iX
+        plus += ((y1 & z2 & !x2) | (x1 & z2 & x2) | (z1 & x2 & !z2)).count_ones();
+        minus += ((y1 & x2 & !z2) | (x1 & z2 & !x2) | (z1 & x2 & z2)).count_ones();
+    }
+    plus.wrapping_sub(minus) & 3
+}
+
+/// Borrows two distinct elements of a slice mutably.
+#[inline]
+pub(crate) fn pair_mut<T>(v: &mut [T], a: usize, b: usize) -> (&mut T, &mut T) {
+    assert_ne!(a, b);
+    if a < b {
+        let (l, r) = v.split_at_mut(b);
+        (&mut l[a], &mut r[0])
+    } else {
+        let (l, r) = v.split_at_mut(a);
+        (&mut r[0], &mut l[b])
+    }
+}
+
+impl Tableau {
+    fn shape(n: usize, layout: Layout) -> (usize, usize) {
+        match layout {
+            Layout::RowMajor => (2 * n, n),
+            Layout::ColumnMajor => (n, 2 * n),
+        }
+    }
+
+    pub fn new(n: usize, layout: Layout) -> Self {
+        let (n_arrays, len) = Self::shape(n, layout);
+        let mut tableau = Self {
+            n,
+            layout,
+            xs: (0..n_arrays).map(|_| BitArray::zeros(len)).collect(),
+            zs: (0..n_arrays).map(|_| BitArray::zeros(len)).collect(),
+            sgns: BitArray::zeros(2 * n),
+        };
+        tableau.reset();
+        tableau
+    }
+
+    pub fn reset(&mut self) {
+        self.xs.iter_mut().for_each(|a| a.reset());
+        self.zs.iter_mut().for_each(|a| a.reset());
+        self.sgns.reset();
+        for i in 0..self.n {
+            self.set_x(i, i, true);
+            self.set_z(i + self.n, i, true);
+        }
+    }
+
+    #[inline]
+    pub fn n_qubits(&self) -> usize {
+        self.n
+    }
+
+    #[inline]
+    pub fn layout(&self) -> Layout {
+        self.layout
+    }
+
+    pub fn set_layout(&mut self, layout: Layout) {
+        if layout == self.layout {
+            return;
+        }
+        let (n_arrays, len) = Self::shape(self.n, layout);
+        let mut xs: Vec<_> = (0..n_arrays).map(|_| BitArray::zeros(len)).collect();
+        let mut zs: Vec<_> = (0..n_arrays).map(|_| BitArray::zeros(len)).collect();
+        for (i, (x, z)) in self.xs.iter().zip(self.zs.iter()).enumerate() {
+            x.true_indices().for_each(|j| xs[j].negate(i));
+            z.true_indices().for_each(|j| zs[j].negate(i));
+        }
+        self.xs = xs;
+        self.zs = zs;
+        self.layout = layout;
+    }
+
+    #[inline]
+    pub fn get_x(&self, row: usize, q: usize) -> bool {
+        match self.layout {
+            Layout::RowMajor => self.xs[row].get_bool(q),
+            Layout::ColumnMajor => self.xs[q].get_bool(row),
+        }
+    }
+
+
+    #[inline]
+    pub fn get_z(&self, row: usize, q: usize) -> bool {
+        match self.layout {
+            Layout::RowMajor => self.zs[row].get_bool(q),
+            Layout::ColumnMajor => self.zs[q].get_bool(row),
+        }
+    }
+
+    /// Returns a copy of a row as a Pauli string.
+    pub fn pauli_row(&self, row: usize) -> PauliString {
+        let mut x = BitArray::zeros(self.n);
+        let mut z = BitArray::zeros(self.n);
+        for q in 0..self.n {
+            x.set_bool(q, self.get_x(row, q));
+            z.set_bool(q, self.get_z(row, q));
+        }
+        PauliString::from_xz(x, z, self.sgns.get_bool(row))
+    }
+
+    #[inline]
+    pub fn set_x(&mut self, row: usize, q: usize, val: bool) {
+        match self.layout {
+            Layout::RowMajor => self.xs[row].set_bool(q, val),
+            Layout::ColumnMajor => self.xs[q].set_bool(row, val),
+        }
+    }
+
+    #[inline]
+    pub fn set_z(&mut self, row: usize, q: usize, val: bool) {
+        match self.layout {
+            Layout::RowMajor => self.zs[row].set_bool(q, val),
+            Layout::ColumnMajor => self.zs[q].set_bool(row, val),
+        }
+    }
+
+    #[inline]
+    pub fn x(&mut self, q: usize) {
+        match self.layout {
+            Layout::RowMajor => {
+                for (i, _) in self.zs.iter().enumerate()
+                                            .filter(|(_, zs)| zs.get_bool(q)) {
+                    self.sgns.negate(i);
+                }
+            },
+            Layout::ColumnMajor => self.sgns.xor_all(&self.zs[q]),
+        }
+    }
+
+    #[inline]
+    pub fn y(&mut self, q: usize) {
+        match self.layout {
+            Layout::RowMajor => {
+                for (i, _) in self.xs.iter().zip(self.zs.iter())
+                                   .enumerate()
+                                   .filter(|(_, (xs, zs))| xs.get_bool(q) != zs.get_bool(q)) {
+                    self.sgns.negate(i);
+                }
+            },
+            Layout::ColumnMajor => {
+                self.sgns.xor_all(&self.xs[q]);
+                self.sgns.xor_all(&self.zs[q]);
+            },
+        }
+    }
+
+    #[inline]
+    pub fn z(&mut self, q: usize) {
+        match self.layout {
+            Layout::RowMajor => {
+                for (i, _) in self.xs.iter().enumerate()
+                                            .filter(|(_, xs)| xs.get_bool(q)) {
+                    self.sgns.negate(i);
+                }
+            },
+            Layout::ColumnMajor => self.sgns.xor_all(&self.xs[q]),
+        }
+    }
+
+    #[inline]
+    pub fn h(&mut self, q: usize) {
+        match self.layout {
+            Layout::RowMajor => {
+                for (i, (xs, zs)) in self.xs.iter_mut().zip(self.zs.iter_mut()).enumerate() {
+                    let x = xs.get_bool(q);
+                    let z = zs.get_bool(q);
+                    if x && z {
+                        self.sgns.negate(i);
+                    } else if x || z {
+                        xs.negate(q);
+                        zs.negate(q);
+                    }
+                }
+            },
+            Layout::ColumnMajor => {
+                let (xs, zs) = (&mut self.xs[q], &mut self.zs[q]);
+                for ((r, &x), &z) in self.sgns.blocks_mut().iter_mut()
+                                              .zip(xs.blocks().iter())
+                                              .zip(zs.blocks().iter()) {
+                    *r ^= x & z;
+                }
+                std::mem::swap(xs, zs);
+            },
+        }
+    }
+
+    #[inline]
+    pub fn s(&mut self, q: usize) {
+        match self.layout {
+            Layout::RowMajor => {
+                for (i, (xs, zs)) in self.xs.iter().zip(self.zs.iter_mut())
+                                                   .enumerate() {
+                    if xs.get_bool(q) {
+                        if zs.get_bool(q) {
+                            self.sgns.negate(i);
+                        }
+                        zs.negate(q);
+                    }
+                }
+            },
+            Layout::ColumnMajor => {
+                let (xs, zs) = (&self.xs[q], &mut self.zs[q]);
+                for ((r, &x), z) in self.sgns.blocks_mut().iter_mut()
+                                             .zip(xs.blocks().iter())
+                                             .zip(zs.blocks_mut().iter_mut()) {
+                    *r ^= x & *z;
+                    *z ^= x;
+                }
+            },
+        }
+    }
+
+    #[inline]
+    pub fn sdg(&mut self, q: usize) {
+        match self.layout {
+            Layout::RowMajor => {
+                for (i, (xs, zs)) in self.xs.iter().zip(self.zs.iter_mut())
+                                                   .enumerate() {
+                    if xs.get_bool(q) {
+                        if !zs.get_bool(q) {
+                            self.sgns.negate(i);
+                        }
+                        zs.negate(q);
+                    }
+                }
+            },
+            Layout::ColumnMajor => {
+                let (xs, zs) = (&self.xs[q], &mut self.zs[q]);
+                for ((r, &x), z) in self.sgns.blocks_mut().iter_mut()
+                                             .zip(xs.blocks().iter())
+                                             .zip(zs.blocks_mut().iter_mut()) {
+                    *r ^= x & !*z;
+                    *z ^= x;
+                }
+            },
+        }
+    }
+
+    #[inline]
+    pub fn cx(&mut self, c: usize, t: usize) {
+        match self.layout {
+            Layout::RowMajor => {
+                for (i, (xs, zs)) in self.xs.iter_mut()
+                                         .zip(self.zs.iter_mut())
+                                         .enumerate() {
+                    let xc = xs.get_bool(c);
+                    let zt = zs.get_bool(t);
+                    if xc && zt && xs.get_bool(t) == zs.get_bool(c) {
+                        self.sgns.negate(i);
+                    }
+                    if xc {
+                        xs.negate(t);
+                    }
+                    if zt {
+                        zs.negate(c);
+                    }
+                }
+            },
+            Layout::ColumnMajor => {
+                let (xc, xt) = pair_mut(&mut self.xs, c, t);
+                let (zc, zt) = pair_mut(&mut self.zs, c, t);
+                for ((((r, xc), xt), zc), zt) in self.sgns.blocks_mut().iter_mut()
+                                                          .zip(xc.blocks().iter())
+                                                          .zip(xt.blocks_mut().iter_mut())
+                                                          .zip(zc.blocks_mut().iter_mut())
+                                                          .zip(zt.blocks().iter()) {
+                    *r ^= xc & zt & !(*xt ^ *zc);
+                    *xt ^= xc;
+                    *zc ^= zt;
+                }
+            },
+        }
+    }
+
+    /// Applies a single-qubit Clifford given by the images of X, Y and Z, each as (x, z, negated).
+    pub fn clifford1(&mut self, images: &[(bool, bool, bool); 3], q: usize) {
+        match self.layout {
+            Layout::RowMajor => {
+                for (i, (xs, zs)) in self.xs.iter_mut().zip(self.zs.iter_mut()).enumerate() {
+                    let image = match (xs.get_bool(q), zs.get_bool(q)) {
+                        (false, false) => continue,
+                        (true, false) => images[0],
+                        (true, true) => images[1],
+                        (false, true) => images[2],
+                    };
+                    xs.set_bool(q, image.0);
+                    zs.set_bool(q, image.1);
+                    if image.2 {
+                        self.sgns.negate(i);
+                    }
+                }
+            },
+            Layout::ColumnMajor => {
+                let sel = |m: u64, b: bool| if b { m } else { 0 };
+                let (xs, zs) = (&mut self.xs[q], &mut self.zs[q]);
+                for ((r, x), z) in self.sgns.blocks_mut().iter_mut()
+                                           .zip(xs.blocks_mut().iter_mut())
+                                           .zip(zs.blocks_mut().iter_mut()) {
+                    let masks = [*x & !*z, *x & *z, !*x & *z];
+                    let (mut nx, mut nz, mut flip) = (0, 0, 0);
+                    for (&m, image) in masks.iter().zip(images.iter()) {
+                        nx |= sel(m, image.0);
+                        nz |= sel(m, image.1);
+                        flip |= sel(m, image.2);
+                    }
+                    *x = nx;
+                    *z = nz;
+                    *r ^= flip;
+                }
+            },
+        }
+    }
+
+    #[inline]
+    pub fn cz(&mut self, a: usize, b: usize) {
+        match self.layout {
+            Layout::RowMajor => {
+                for (i, (xs, zs)) in self.xs.iter_mut()
+                                         .zip(self.zs.iter_mut())
+                                         .enumerate() {
+                    let xa = xs.get_bool(a);
+                    let xb = xs.get_bool(b);
+                    if xa && xb && zs.get_bool(a) != zs.get_bool(b) {
+                        self.sgns.negate(i);
+                    }
+                    if xb {
+                        zs.negate(a);
+                    }
+                    if xa {
+                        zs.negate(b);
+                    }
+                }
+            },
+            Layout::ColumnMajor => {
+                let xa = &self.xs[a];
+                let xb = &self.xs[b];
+                let (za, zb) = pair_mut(&mut self.zs, a, b);
+                for ((((r, xa), xb), za), zb) in self.sgns.blocks_mut().iter_mut()
+                                                          .zip(xa.blocks().iter())
+                                                          .zip(xb.blocks().iter())
+                                                          .zip(za.blocks_mut().iter_mut())
+                                                          .zip(zb.blocks_mut().iter_mut()) {
+                    *r ^= xa & xb & (*za ^ *zb);
+                    *za ^= xb;
+                    *zb ^= xa;
+                }
+            },
+        }
+    }
+
+    #[inline]
+    pub fn swap(&mut self, a: usize, b: usize) {
+        match self.layout {
+            Layout::RowMajor => {
+                for arr in self.xs.iter_mut().chain(self.zs.iter_mut()) {
+                    let (va, vb) = (arr.get_bool(a), arr.get_bool(b));
+                    arr.set_bool(a, vb);
+                    arr.set_bool(b, va);
+                }
+            },
+            Layout::ColumnMajor => {
+                self.xs.swap(a, b);
+                self.zs.swap(a, b);
+            },
+        }
+    }
+
+    /// Applies a k-qubit Clifford to `targets` by conjugating the restriction of every row.
+    pub fn clifford(&mut self, c: &CliffordTableau, targets: &[usize]) {
+        let k = targets.len();
+        let mut x = BitArray::zeros(k);
+        let mut z = BitArray::zeros(k);
+        for row in 0..2 * self.n {
+            for (j, &q) in targets.iter().enumerate() {
+                x.set_bool(j, self.get_x(row, q));
+                z.set_bool(j, self.get_z(row, q));
+            }
+            if x.true_indices().next().is_none() && z.true_indices().next().is_none() {
+                continue;
+            }
+            let (x, z, negated) = c.conjugate(&x, &z);
+            for (j, &q) in targets.iter().enumerate() {
+                self.set_x(row, q, x.get_bool(j));
+                self.set_z(row, q, z.get_bool(j));
+            }
+            if negated {
+                self.sgns.negate(row);
+            }
+        }
+    }
+
+    /// Multiplies row `src` into row `dest` (the rowsum of Aaronson and Gottesman).
+    pub fn mult_to(&mut self, dest: usize, src: usize) {
+        assert_ne!(dest, src);
+        let mut phase = 2 * (self.sgns.get_bool(src) as u32 + self.sgns.get_bool(dest) as u32);
+        match self.layout {
+            Layout::RowMajor => {
+                phase += pauli_phase(&self.xs[src], &self.zs[src], &self.xs[dest], &self.zs[dest]);
+                let (into, from) = pair_mut(&mut self.xs, dest, src);
+                into.xor_all(from);
+                let (into, from) = pair_mut(&mut self.zs, dest, src);
+                into.xor_all(from);
+            },
+            Layout::ColumnMajor => {
+                for (xs, zs) in self.xs.iter_mut().zip(self.zs.iter_mut()) {
+                    let (x1, z1) = (xs.get_bool(src), zs.get_bool(src));
+                    phase += g(x1, z1, xs.get_bool(dest), zs.get_bool(dest));
+                    if x1 {
+                        xs.negate(dest);
+                    }
+                    if z1 {
+                        zs.negate(dest);
+                    }
+                }
+            },
+        }
+        self.sgns.set_bool(dest, phase & 3 == 2);
+    }
+
+    /// Multiplies row `src` into every row in `mask` at once. Only for the column-major layout.
+    fn mult_to_masked(&mut self, mask: &BitArray, src: usize) {
+        debug_assert_eq!(self.layout, Layout::ColumnMajor);
+        // Per-row phase counter mod 4, as two bit planes.
+        let mut lo = BitArray::zeros(2 * self.n);
+        let mut hi = BitArray::zeros(2 * self.n);
+        for (xs, zs) in self.xs.iter_mut().zip(self.zs.iter_mut()) {
+            let (x1, z1) = (xs.get_bool(src), zs.get_bool(src));
+            if !x1 && !z1 {
+                continue;
+            }
+            for ((((lo, hi), &m), x), z) in lo.blocks_mut().iter_mut()
+                                              .zip(hi.blocks_mut().iter_mut())
+                                              .zip(mask.blocks().iter())
+                                              .zip(xs.blocks_mut().iter_mut())
+                                              .zip(zs.blocks_mut().iter_mut()) {
+                let (plus, minus) = match (x1, z1) {
+                    (true, false) => (*z & *x, *z & !*x),
+                    (true, true) => (*z & !*x, *x & !*z),
+                    _ => (*x & !*z, *x & *z),
+                };
+                let (plus, minus) = (plus & m, minus & m);
+                *hi ^= *lo & plus;
+                *lo ^= plus;
+                *lo ^= minus;
+                *hi ^= *lo & minus;
+                if x1 {
+                    *x ^= m;
+                }
+                if z1 {
+                    *z ^= m;
+                }
+            }
+        }
+        let r1 = if self.sgns.get_bool(src) { !0 } else { 0 };
+        for (((r, &lo), &hi), &m) in self.sgns.blocks_mut().iter_mut()
+                                          .zip(lo.blocks().iter())
+                                          .zip(hi.blocks().iter())
+                                          .zip(mask.blocks().iter()) {
+            let updated = !lo & (*r ^ r1 ^ hi);
+            *r = (*r & !m) | (updated & m);
+        }
+    }
+
+    pub fn copy_row(&mut self, dest: usize, src: usize) {
+        assert_ne!(dest, src);
+        match self.layout {
+            Layout::RowMajor => {
+                let (into, from) = pair_mut(&mut self.xs, dest, src);
+                into.copy_from(from);
+                let (into, from) = pair_mut(&mut self.zs, dest, src);
+                into.copy_from(from);
+            },
+            Layout::ColumnMajor => {
+                for (xs, zs) in self.xs.iter_mut().zip(self.zs.iter_mut()) {
+                    xs.set_bool(dest, xs.get_bool(src));
+                    zs.set_bool(dest, zs.get_bool(src));
+                }
+            },
+        }
+        self.sgns.set_bool(dest, self.sgns.get_bool(src));
+    }
+
+    /// Overwrites a row with the single-qubit Z on `q` and the given sign.
+    pub fn set_row_z(&mut self, row: usize, q: usize, sign: bool) {
+        match self.layout {
+            Layout::RowMajor => {
+                self.xs[row].reset();
+                self.zs[row].reset();
+                self.zs[row].negate(q);
+            },
+            Layout::ColumnMajor => {
+                for (xs, zs) in self.xs.iter_mut().zip(self.zs.iter_mut()) {
+                    xs.set_bool(row, false);
+                    zs.set_bool(row, false);
+                }
+                self.zs[q].negate(row);
+            },
+        }
+        self.sgns.set_bool(row, sign);
+    }
+
+    /// Overwrites a row with the Pauli string `p`, ignoring its phase, and the given sign.
+    pub fn set_row(&mut self, row: usize, p: &PauliString, sign: bool) {
+        match self.layout {
+            Layout::RowMajor => {
+                self.xs[row].copy_from(p.xs());
+                self.zs[row].copy_from(p.zs());
+            },
+            Layout::ColumnMajor => {
+                for q in 0..self.n {
+                    self.xs[q].set_bool(row, p.xs().get_bool(q));
+                    self.zs[q].set_bool(row, p.zs().get_bool(q));
+                }
+            },
+        }
+        self.sgns.set_bool(row, sign);
+    }
+
+    /// Returns the stabilizer row that anticommutes with Z_q, if any.
+    fn anticommuting_stabilizer(&self, q: usize) -> Option<usize> {
+        match self.layout {
+            Layout::RowMajor => (self.n..2 * self.n).find(|&i| self.xs[i].get_bool(q)),
+            Layout::ColumnMajor => self.xs[q].true_indices().find(|&i| i >= self.n),
+        }
+    }
+
+    /// Rowsums stabilizer row `p` into every other row in `mask`, then moves it to the paired
+    /// destabilizer. Row `p` is left to be overwritten with the measured observable.
+    fn collapse(&mut self, mut mask: BitArray, p: usize) {
+        mask.set_bool(p, false);
+        match self.layout {
+            Layout::RowMajor => mask.true_indices().for_each(|i| self.mult_to(i, p)),
+            Layout::ColumnMajor => self.mult_to_masked(&mask, p),
+        }
+        self.copy_row(p - self.n, p);
+    }
+
+    pub fn measure<Rng: RngCore>(&mut self, q: usize, rng: &mut Rng) -> bool {
+        match self.anticommuting_stabilizer(q) {
+            Some(p) => {
+                let is_one = (rng.next_u32() & 1) != 0;
+                self.collapse_z(q, p, is_one);
+                is_one
+            },
+            None => self.deterministic_z(q),
+        }
+    }
+
+    /// Measures Z_q with the outcome `bit` and returns its probability, 0.5 or 1.
+    /// Returns None, leaving the state as it is, if the outcome has probability zero.
+    pub fn measure_forced(&mut self, q: usize, bit: bool) -> Option<f64> {
+        match self.anticommuting_stabilizer(q) {
+            Some(p) => {
+                self.collapse_z(q, p, bit);
+                Some(0.5)
+            },
+            None if self.deterministic_z(q) == bit => Some(1.0),
+            None => None,
+        }
+    }
+
+    /// Projects onto the outcome `is_one` of Z_q, where stabilizer `p` anticommutes with it.
+    fn collapse_z(&mut self, q: usize, p: usize, is_one: bool) {
+        let mask = match self.layout {
+            Layout::RowMajor => {
+                let mut mask = BitArray::zeros(2 * self.n);
+                (0..2 * self.n).filter(|&i| self.xs[i].get_bool(q)).for_each(|i| mask.negate(i));
+                mask
+            },
+            Layout::ColumnMajor => self.xs[q].clone(),
+        };
+        self.collapse(mask, p);
+        self.set_row_z(p, q, is_one);
+    }
+
+    /// Returns the outcome of Z_q, which must be in the stabilizer group.
+    fn deterministic_z(&self, q: usize) -> bool {
+        let n = self.n;
+        // Z_q is in the stabilizer group. It is the product of the stabilizers
+        // whose paired destabilizers anticommute with it.
+        let mut xs = BitArray::zeros(n);
+        let mut zs = BitArray::zeros(n);
+        let mut phase = 0;
+        for i in (0..n).filter(|&i| self.get_x(i, q)) {
+            match self.layout {
+                Layout::RowMajor => {
+                    phase += pauli_phase(&self.xs[i + n], &self.zs[i + n], &xs, &zs);
+                    xs.xor_all(&self.xs[i + n]);
+                    zs.xor_all(&self.zs[i + n]);
+                },
+                Layout::ColumnMajor => {
+                    for j in 0..n {
+                        let (x1, z1) = (self.xs[j].get_bool(i + n), self.zs[j].get_bool(i + n));
+                        phase += g(x1, z1, xs.get_bool(j), zs.get_bool(j));
+                        if x1 {
+                            xs.negate(j);
+                        }
+                        if z1 {
+                            zs.negate(j);
+                        }
+                    }
+                },
+            }
+            phase += 2 * self.sgns.get_bool(i + n) as u32;
+        }
+        phase & 3 == 2
+    }
+
+    /// Measures the observable `p`, collapsing the state like `measure`.
+    pub fn measure_pauli<Rng: RngCore>(&mut self, p: &PauliString, rng: &mut Rng) -> bool {
+        let (p, anti) = self.anticommuting_rows(p);
+        match anti.true_indices().find(|&i| i >= self.n) {
+            Some(row) => {
+                self.collapse(anti, row);
+                let is_one = (rng.next_u32() & 1) != 0;
+                self.set_row(row, &p, is_one ^ p.is_negative());
+                is_one
+            },
+            None => self.product_sign(&p, &anti),
+        }
+    }
+
+    /// Resizes `p` to the number of qubits and returns it with the set of rows anticommuting with it.
+    fn anticommuting_rows(&self, p: &PauliString) -> (PauliString, BitArray) {
+        assert!(p.is_hermitian(), "observable {} is not Hermitian", p);
+        let n = self.n;
+        let mut p = p.clone();
+        if p.len() > n {
+            assert!((n..p.len()).all(|q| p.get(q) == Pauli::I), "observable {} acts on missing qubits", p);
+        }
+        p.resize(n);
+        let mut anti = BitArray::zeros(2 * n);
+        match self.layout {
+            Layout::RowMajor => {
+                for i in 0..2 * n {
+                    anti.set_bool(i, anticommutes(&self.xs[i], &self.zs[i], p.xs(), p.zs()));
+                }
+            },
+            Layout::ColumnMajor => {
+                p.xs().true_indices().for_each(|q| anti.xor_all(&self.zs[q]));
+                p.zs().true_indices().for_each(|q| anti.xor_all(&self.xs[q]));
+            },
+        }
+        (p, anti)
+    }
+
+    /// For `p` in the stabilizer group up to sign, returns whether it is minus a stabilizer.
+    /// It is the product of the stabilizers whose destabilizers anticommute with it.
+    fn product_sign(&self, p: &PauliString, anti: &BitArray) -> bool {
+        let mut product = PauliString::identity(self.n);
+        for i in anti.true_indices() {
+            product *= &self.pauli_row(i + self.n);
+        }
+        debug_assert!(product.xs() == p.xs() && product.zs() == p.zs());
+        product.phase() != p.phase()
+    }
+
+    /// Returns the eigenvalue of `p` as a measurement outcome (true for -1) if `p` is in the
+    /// stabilizer group up to sign, and None if measuring it would be random.
+    pub fn peek(&self, p: &PauliString) -> Option<bool> {
+        let (p, anti) = self.anticommuting_rows(p);
+        if anti.true_indices().any(|i| i >= self.n) {
+            return None;
+        }
+        Some(self.product_sign(&p, &anti))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tableau::{Layout, Tableau};
+    use rand::{Rng, SeedableRng};
+    use rand_xorshift::XorShiftRng;
+
+    fn assert_same(a: &Tableau, b: &Tableau) {
+        let (mut a, mut b) = (a.clone(), b.clone());
+        a.set_layout(Layout::RowMajor);
+        b.set_layout(Layout::RowMajor);
+        assert_eq!(format!("{:?}", a), format!("{:?}", b));
+    }
+
+    #[test]
+    fn layouts_agree() {
+        let n = 70;
+        let mut rng = XorShiftRng::seed_from_u64(0);
+        let mut rows = Tableau::new(n, Layout::RowMajor);
+        let mut cols = Tableau::new(n, Layout::ColumnMajor);
+        let mut rng_rows = XorShiftRng::seed_from_u64(1);
+        let mut rng_cols = XorShiftRng::seed_from_u64(1);
+        for _ in 0..2000 {
+            let a = rng.gen_range(0..n);
+            let b = (a + rng.gen_range(1..n)) % n;
+            match rng.gen_range(0..9) {
+                0 => { rows.x(a); cols.x(a); },
+                1 => { rows.y(a); cols.y(a); },
+                2 => { rows.z(a); cols.z(a); },
+                3 => { rows.h(a); cols.h(a); },
+                4 => { rows.s(a); cols.s(a); },
+                5 => { rows.sdg(a); cols.sdg(a); },
+                6 | 7 => { rows.cx(a, b); cols.cx(a, b); },
+                _ => {
+                    let m1 = rows.measure(a, &mut rng_rows);
+                    let m2 = cols.measure(a, &mut rng_cols);
+                    assert_eq!(m1, m2);
+                },
+            }
+        }
+        assert_same(&rows, &cols);
+    }
+
+    #[test]
+    fn transpose_roundtrip() {
+        let n = 40;
+        let mut rng = XorShiftRng::seed_from_u64(2);
+        let mut t = Tableau::new(n, Layout::RowMajor);
+        for _ in 0..300 {
+            let a = rng.gen_range(0..n);
+            let b = (a + rng.gen_range(1..n)) % n;
+            t.h(a);
+            t.s(b);
+            t.cx(a, b);
+        }
+        let mut u = t.clone();
+        u.set_layout(Layout::ColumnMajor);
+        assert_eq!(u.layout(), Layout::ColumnMajor);
+        assert_same(&t, &u);
+        u.set_layout(Layout::RowMajor);
+        assert_same(&t, &u);
+    }
+}